@@ -0,0 +1,218 @@
+//! Making the strip show what the browser shows.
+//!
+//! Everything up to the output is sRGB, like `input type=color`. LEDs are
+//! linear in PWM duty and every batch has its own idea of white, so each
+//! device gets a [`Calibration`]: gamma per channel, a white point and
+//! optionally a measured curve. It's compiled into a [`Curve`] once, which is
+//! a table lookup per channel per frame.
+
+use alloc::vec::Vec;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    output::Intensity,
+    strip::{Srgb8, Wrap},
+};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Calibration {
+    /// Per channel, red green blue. `1` sends the sRGB values as they are.
+    pub gamma: [f32; 3],
+    /// Scales each channel at full, to pull the strip's white to the
+    /// screen's. `0..=1`.
+    pub white: [f32; 3],
+    /// Measured light output per channel at evenly spaced inputs from `0` to
+    /// `255`, `0..=1`. Replaces `gamma` where given.
+    #[serde(default)]
+    pub lut: Option<[Vec<f32>; 3]>,
+}
+
+impl Default for Calibration {
+    /// Plain sRGB decoding, white left alone
+    fn default() -> Self {
+        Self {
+            gamma: [2.2; 3],
+            white: [1.; 3],
+            lut: None,
+        }
+    }
+}
+
+impl Calibration {
+    /// Positive gammas, whites in range and LUTs with at least two points
+    pub fn is_valid(&self) -> bool {
+        let gamma = self.gamma.iter().all(|g| g.is_finite() && *g > 0.);
+        let white = self.white.iter().all(|w| (0. ..=1.).contains(w));
+        let lut = self
+            .lut
+            .iter()
+            .flatten()
+            .all(|points| points.len() >= 2 && points.iter().all(|p| (0. ..=1.).contains(p)));
+        gamma && white && lut
+    }
+
+    pub fn curve(&self) -> Curve {
+        let mut table = [[0; 256]; 3];
+        for (channel, row) in table.iter_mut().enumerate() {
+            let lut = self.lut.as_ref().map(|lut| &lut[channel][..]);
+            for (value, out) in row.iter_mut().enumerate() {
+                let x = value as f32 / 255.;
+                let linear = match lut {
+                    Some(points) if points.len() >= 2 => interpolate(points, x),
+                    _ => libm::powf(x, self.gamma[channel]),
+                };
+                let level = (linear * self.white[channel]).clamp(0., 1.);
+                *out = libm::roundf(level * u16::MAX as f32) as u16;
+            }
+        }
+        Curve { table }
+    }
+}
+
+/// Piecewise linear through `points`, spaced evenly over `0..=1`
+fn interpolate(points: &[f32], x: f32) -> f32 {
+    let pos = x.clamp(0., 1.) * (points.len() - 1) as f32;
+    let i = (pos as usize).min(points.len() - 2);
+    let t = pos - i as f32;
+    points[i] + (points[i + 1] - points[i]) * t
+}
+
+/// A [`Calibration`] ready to apply
+#[derive(Clone, Debug, PartialEq)]
+pub struct Curve {
+    table: [[u16; 256]; 3],
+}
+
+impl Default for Curve {
+    fn default() -> Self {
+        Calibration::default().curve()
+    }
+}
+
+impl Curve {
+    /// `color` through the calibration, at `level` (a fraction of full)
+    pub fn intensity(&self, color: Srgb8, level: f32) -> Intensity {
+        self.intensity_fine(color, [0; 3], level)
+    }
+
+    /// [`intensity`](Self::intensity) in between table entries, `fine` is
+    /// per channel in 1/256 of a step as in [`Led`](crate::strip::Led)
+    pub fn intensity_fine(&self, color: Srgb8, fine: [i8; 3], level: f32) -> Intensity {
+        // 16.16 fixed point, the C3 has no FPU
+        let level = (level.clamp(0., 1.) * 65_536.) as u32;
+        let channel = |c: usize, v: u8| {
+            let row = &self.table[c];
+            let here = row[v as usize] as i32;
+            let next = match fine[c] {
+                f if f > 0 => v.checked_add(1),
+                f if f < 0 => v.checked_sub(1),
+                _ => None,
+            };
+            let value = match next {
+                Some(next) => {
+                    here + (row[next as usize] as i32 - here) * (fine[c] as i32).abs() / 256
+                }
+                None => here,
+            };
+            ((value as u32 * level) >> 16) as u16
+        };
+        Intensity {
+            red: channel(0, color.red),
+            green: channel(1, color.green),
+            blue: channel(2, color.blue),
+        }
+    }
+}
+
+/// Body of `POST /calibration/patch`. Shows `color` on the whole strip,
+/// calibrated, to hold against the same color on screen. `None` goes back
+/// to the state.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TestPatch {
+    pub color: Option<Wrap>,
+}
+
+/// What the calibration mode cycles through
+pub const TEST_PATCHES: [(u8, u8, u8); 8] = [
+    (255, 255, 255),
+    (128, 128, 128),
+    (32, 32, 32),
+    (255, 0, 0),
+    (0, 255, 0),
+    (0, 0, 255),
+    (255, 150, 0),
+    (166, 0, 255),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn raw() -> Calibration {
+        Calibration {
+            gamma: [1.; 3],
+            ..Calibration::default()
+        }
+    }
+
+    #[test]
+    fn raw_passes_through() {
+        let curve = raw().curve();
+        let color = Srgb8::new(255, 128, 0);
+        assert_eq!(curve.intensity(color, 1.), Intensity::from_color(color, 1.));
+        assert_eq!(curve.intensity(color, 0.), Intensity::default());
+    }
+
+    #[test]
+    fn gamma_darkens_the_middle() {
+        let curve = Curve::default();
+        let full = curve.intensity(Srgb8::new(255, 255, 255), 1.);
+        assert_eq!(full.red, u16::MAX);
+        // sRGB 128 is about 22% of the light
+        let mid = curve.intensity(Srgb8::new(128, 128, 128), 1.);
+        let frac = mid.green as f32 / u16::MAX as f32;
+        assert!((frac - 0.22).abs() < 0.01, "{frac}");
+        // and the level scales after the curve
+        let half = curve.intensity(Srgb8::new(128, 128, 128), 0.5);
+        assert!((half.green as i32 - mid.green as i32 / 2).abs() <= 1);
+    }
+
+    #[test]
+    fn white_point_and_lut() {
+        let cal = Calibration {
+            white: [1., 0.8, 0.5],
+            lut: Some([vec![0., 1.], vec![0., 0.25, 1.], vec![]]),
+            ..Calibration::default()
+        };
+        // an empty LUT is invalid, but falls back to gamma anyway
+        assert!(!cal.is_valid());
+        let curve = cal.curve();
+        let white = curve.intensity(Srgb8::new(255, 255, 255), 1.);
+        assert_eq!(white.red, u16::MAX);
+        assert_eq!(white.green, (0.8 * u16::MAX as f32).round() as u16);
+        assert_eq!(white.blue, (0.5 * u16::MAX as f32).round() as u16);
+
+        // halfway along the measured points
+        let mid = curve.intensity(Srgb8::new(0, 128, 0), 1.);
+        let frac = mid.green as f32 / u16::MAX as f32;
+        assert!((frac - 0.8 * 0.25).abs() < 0.005, "{frac}");
+
+        assert!(Calibration::default().is_valid());
+        assert!(!Calibration {
+            gamma: [0., 1., 1.],
+            ..Calibration::default()
+        }
+        .is_valid());
+    }
+
+    #[test]
+    fn test_patch_json() {
+        let patch: TestPatch =
+            serde_json::from_str(r#"{"color":{"red":1,"green":2,"blue":3}}"#).unwrap();
+        assert_eq!(patch.color.map(|c| c.0), Some(Srgb8::new(1, 2, 3)));
+        let off: TestPatch = serde_json::from_str(r#"{"color":null}"#).unwrap();
+        assert_eq!(off, TestPatch::default());
+    }
+}
@@ -0,0 +1,294 @@
+//! Monotonic millisecond time for animations.
+//!
+//! Everything that renders (`Segment::color_at`, `Segment::render`) takes a
+//! `u64` millisecond timestamp, which does not wrap for the lifetime of any
+//! installation. A [`Clock`] produces those timestamps, [`Control`] turns them
+//! into "time since start".
+
+use core::cell::Cell;
+
+/// A monotonic time source with millisecond resolution
+pub trait Clock {
+    /// Milliseconds since a clock specific origin. Must never go backwards.
+    fn now_ms(&self) -> u64;
+}
+
+impl<C: Clock + ?Sized> Clock for &C {
+    fn now_ms(&self) -> u64 {
+        (**self).now_ms()
+    }
+}
+
+/// Calendar time, for schedules. Unlike [`Clock`] this can jump, e.g. when
+/// SNTP syncs.
+pub trait WallClock {
+    /// Milliseconds since the unix epoch in UTC, `None` while unknown
+    fn unix_ms(&self) -> Option<u64>;
+}
+
+impl<C: WallClock + ?Sized> WallClock for &C {
+    fn unix_ms(&self) -> Option<u64> {
+        (**self).unix_ms()
+    }
+}
+
+/// A clock that only moves when told to, for tests and simulations
+#[derive(Debug, Default)]
+pub struct ManualClock {
+    now: Cell<u64>,
+}
+
+impl ManualClock {
+    pub fn new(now: u64) -> Self {
+        Self {
+            now: Cell::new(now),
+        }
+    }
+
+    /// Jump to `now`. Going backwards is ignored, clocks are monotonic.
+    pub fn set(&self, now: u64) {
+        self.now.set(now.max(self.now.get()));
+    }
+
+    pub fn advance(&self, by_ms: u64) {
+        self.now.set(self.now.get().saturating_add(by_ms));
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_ms(&self) -> u64 {
+        self.now.get()
+    }
+}
+
+/// Doubles as a fake wall clock, `now` is the unix time then
+impl WallClock for ManualClock {
+    fn unix_ms(&self) -> Option<u64> {
+        Some(self.now.get())
+    }
+}
+
+/// `std::time::Instant` based clock. Also what ESP-IDF's std uses underneath.
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+#[derive(Debug)]
+pub struct StdClock {
+    start: std::time::Instant,
+}
+
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+impl StdClock {
+    pub fn new() -> Self {
+        Self {
+            start: std::time::Instant::now(),
+        }
+    }
+}
+
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+impl Default for StdClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+impl Clock for StdClock {
+    fn now_ms(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+}
+
+/// Browser clock. `Instant` panics on wasm32, so this goes through the wall
+/// clock and clamps it to never step backwards.
+#[cfg(feature = "wasm")]
+#[derive(Debug)]
+pub struct WasmClock {
+    start: chrono::DateTime<chrono::Utc>,
+    last: Cell<u64>,
+}
+
+#[cfg(feature = "wasm")]
+impl WasmClock {
+    pub fn new() -> Self {
+        Self {
+            start: chrono::Utc::now(),
+            last: Cell::new(0),
+        }
+    }
+}
+
+#[cfg(feature = "wasm")]
+impl Default for WasmClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "wasm")]
+impl Clock for WasmClock {
+    fn now_ms(&self) -> u64 {
+        let elapsed = chrono::Utc::now()
+            .signed_duration_since(self.start)
+            .num_milliseconds()
+            .max(0) as u64;
+        let now = elapsed.max(self.last.get());
+        self.last.set(now);
+        now
+    }
+}
+
+#[cfg(feature = "wasm")]
+pub type DefaultClock = WasmClock;
+
+#[cfg(all(feature = "std", not(feature = "wasm"), not(target_arch = "wasm32")))]
+pub type DefaultClock = StdClock;
+
+#[cfg(not(any(feature = "wasm", all(feature = "std", not(target_arch = "wasm32")))))]
+pub type DefaultClock = ManualClock;
+
+/// Keeps track of animation time relative to when it was created
+pub struct Control<C: Clock = DefaultClock> {
+    clock: C,
+    start: u64,
+    now: u64,
+}
+
+impl Control<DefaultClock> {
+    pub fn new() -> Self {
+        Self::with_clock(DefaultClock::default())
+    }
+}
+
+impl Default for Control<DefaultClock> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C: Clock> Control<C> {
+    pub fn with_clock(clock: C) -> Self {
+        let now = clock.now_ms();
+        Self {
+            clock,
+            start: now,
+            now,
+        }
+    }
+
+    /// Read the clock, returns the new `ms_since_start`
+    pub fn tick(&mut self) -> u64 {
+        self.now = self.clock.now_ms();
+        self.ms_since_start()
+    }
+
+    /// Override the current time, in the clock's own time base
+    pub fn set_now(&mut self, now: u64) {
+        self.now = now;
+    }
+
+    pub fn ms_since_start(&self) -> u64 {
+        self.now.saturating_sub(self.start)
+    }
+
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+}
+
+/// Paces a render loop at a fixed frame rate. Frames are due on a fixed grid,
+/// so one slow frame doesn't push back all the ones after it, and frames the
+/// loop fell too far behind for are dropped instead of rendered in a burst.
+/// [`Dither`](crate::dither::Dither) relies on the steady rate.
+#[derive(Clone, Debug)]
+pub struct FrameScheduler {
+    period_ms: u64,
+    next: u64,
+    skipped: u64,
+}
+
+impl FrameScheduler {
+    /// The first frame is due at `now`
+    pub fn new(period_ms: u64, now: u64) -> Self {
+        Self {
+            period_ms: period_ms.max(1),
+            next: now,
+            skipped: 0,
+        }
+    }
+
+    pub fn period_ms(&self) -> u64 {
+        self.period_ms
+    }
+
+    /// When the next frame is due, in the clock's time base
+    pub fn next_frame(&self) -> u64 {
+        self.next
+    }
+
+    /// Call when a frame is out, returns how long to wait for the next one
+    pub fn frame_done(&mut self, now: u64) -> u64 {
+        self.next = self.next.saturating_add(self.period_ms);
+        if now > self.next {
+            // a little late just goes right away, whole periods are gone
+            let missed = (now - self.next) / self.period_ms;
+            self.skipped += missed;
+            self.next += missed * self.period_ms;
+        }
+        self.next.saturating_sub(now)
+    }
+
+    /// Frames dropped because the loop fell behind
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manual_clock_is_monotonic() {
+        let clock = ManualClock::new(100);
+        clock.set(50);
+        assert_eq!(clock.now_ms(), 100);
+        clock.advance(25);
+        assert_eq!(clock.now_ms(), 125);
+        clock.advance(u64::MAX);
+        assert_eq!(clock.now_ms(), u64::MAX);
+    }
+
+    #[test]
+    fn control_counts_from_start() {
+        let clock = ManualClock::new(1_000);
+        let mut control = Control::with_clock(&clock);
+        assert_eq!(control.tick(), 0);
+        clock.advance(u32::MAX as u64 + 10);
+        assert_eq!(control.tick(), u32::MAX as u64 + 10);
+        assert_eq!(control.ms_since_start(), u32::MAX as u64 + 10);
+    }
+
+    #[test]
+    fn frames_stay_on_the_grid() {
+        let clock = ManualClock::new(1_000);
+        let mut frames = FrameScheduler::new(10, clock.now_ms());
+        // rendering took 3ms, wait out the rest
+        clock.advance(3);
+        assert_eq!(frames.frame_done(clock.now_ms()), 7);
+        clock.advance(7);
+        // a slow frame goes right on, but doesn't shift the ones after it
+        clock.advance(14);
+        assert_eq!(frames.frame_done(clock.now_ms()), 0);
+        clock.advance(2);
+        assert_eq!(frames.frame_done(clock.now_ms()), 4);
+        assert_eq!(frames.next_frame(), 1_030);
+        assert_eq!(frames.skipped(), 0);
+
+        // falling behind by more than a period drops frames
+        clock.set(1_030 + 35);
+        assert_eq!(frames.frame_done(clock.now_ms()), 0);
+        assert_eq!(frames.next_frame(), 1_060);
+        assert_eq!(frames.skipped(), 2);
+        assert_eq!(frames.frame_done(clock.now_ms()), 5);
+    }
+}
@@ -0,0 +1,406 @@
+//! Stacking several things on one strip.
+//!
+//! A [`Compositor`] draws [`Layer`]s over whatever is already in the LEDs,
+//! usually [`State::render`](crate::strip::State::render): a chaser on top of
+//! a fade, or a notification flash over everything. Each layer covers a range
+//! of LEDs and has an opacity and a [`BlendMode`]. Blending happens in linear
+//! light with each LED's brightness applied, so a dim layer adds as much as
+//! it shows on its own.
+
+use alloc::{boxed::Box, vec::Vec};
+use core::ops::Range;
+
+use effects::effect::Effect;
+use palette::{LinSrgb, Srgb};
+use serde::{Deserialize, Serialize};
+use smart_leds::RGB8;
+
+use crate::strip::{Led, Segment, Srgb8, Wrap, FULL_BRIGHTNESS};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BlendMode {
+    /// The layer replaces what's below
+    #[default]
+    Normal,
+    /// Light adds up, clipping at full
+    Add,
+    /// The layer's color filters what's below, like tinted glass. Its
+    /// brightness doesn't matter, nothing gets brighter.
+    Multiply,
+    /// Brightens like add, but eases into full instead of clipping
+    Screen,
+    /// The brighter of the two, per channel
+    Max,
+    /// The brighter of the two whole LEDs, so colors don't mix
+    Lighten,
+}
+
+pub enum Source {
+    Segment(Segment),
+    /// Any effect, its colors shown at `brightness`
+    Effect {
+        effect: Box<dyn Effect + Send>,
+        brightness: u8,
+    },
+    /// The same on every LED, e.g. a flash
+    Solid(Led),
+}
+
+pub struct Layer {
+    pub source: Source,
+    pub mode: BlendMode,
+    /// `0` leaves what's below alone, `1` is the full blend
+    pub opacity: f32,
+    /// The LEDs the layer covers, counted from the start of the strip.
+    /// Anything past the end is cut off.
+    pub range: Range<usize>,
+    /// `(start, duration_ms)` to fade from `opacity` to nothing. Once faded
+    /// out, the compositor drops the layer.
+    pub fade_out: Option<(u64, u64)>,
+}
+
+impl Layer {
+    /// Over the whole strip at full opacity
+    pub fn new(source: Source, mode: BlendMode) -> Self {
+        Self {
+            source,
+            mode,
+            opacity: 1.,
+            range: 0..usize::MAX,
+            fade_out: None,
+        }
+    }
+
+    /// [`opacity`](Self::opacity) at `at_millis`, with the fade out applied
+    pub fn opacity_at(&self, at_millis: u64) -> f32 {
+        let opacity = self.opacity.clamp(0., 1.);
+        let Some((start, duration_ms)) = self.fade_out else {
+            return opacity;
+        };
+        let end = start.saturating_add(duration_ms);
+        if at_millis >= end {
+            0.
+        } else if at_millis <= start {
+            opacity
+        } else {
+            opacity * (end - at_millis) as f32 / duration_ms as f32
+        }
+    }
+
+    pub fn is_done(&self, at_millis: u64) -> bool {
+        self.fade_out
+            .is_some_and(|(start, duration_ms)| at_millis >= start.saturating_add(duration_ms))
+    }
+}
+
+/// `POST /flash` body: `color` over the whole strip, fading out over `ms`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Flash {
+    pub color: Wrap,
+    pub ms: u32,
+}
+
+impl Flash {
+    /// The flash as a layer, starting at `at_millis`
+    pub fn layer(&self, at_millis: u64) -> Layer {
+        let led = Led::new(self.color.0, FULL_BRIGHTNESS);
+        Layer {
+            fade_out: Some((at_millis, self.ms as u64)),
+            ..Layer::new(Source::Solid(led), BlendMode::Screen)
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Compositor {
+    layers: Vec<Layer>,
+    colors: Vec<Srgb8>,
+    rgb: Vec<RGB8>,
+}
+
+impl Compositor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// On top of all the others
+    pub fn push(&mut self, layer: Layer) {
+        self.layers.push(layer);
+    }
+
+    /// Bottom to top
+    pub fn layers(&self) -> &[Layer] {
+        &self.layers
+    }
+
+    pub fn layers_mut(&mut self) -> &mut Vec<Layer> {
+        &mut self.layers
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+
+    /// Draw the layers bottom to top over `leds`, which hold what's below
+    /// them at `at_millis`. Layers that have faded out are dropped.
+    pub fn render(&mut self, at_millis: u64, leds: &mut [Led]) {
+        self.layers.retain(|layer| !layer.is_done(at_millis));
+
+        for layer in &mut self.layers {
+            let opacity = layer.opacity_at(at_millis);
+            let end = layer.range.end.min(leds.len());
+            let start = layer.range.start.min(end);
+            if opacity <= 0. || start == end {
+                continue;
+            }
+
+            self.colors.clear();
+            self.colors.resize(end - start, Srgb8::default());
+            let brightness = match &mut layer.source {
+                Source::Segment(seg) => {
+                    seg.render(at_millis, &mut self.colors);
+                    seg.brightness()
+                }
+                Source::Effect { effect, brightness } => {
+                    self.rgb.clear();
+                    self.rgb.resize(end - start, RGB8::default());
+                    effect.render(at_millis, &mut self.rgb);
+                    for (color, rgb) in self.colors.iter_mut().zip(&self.rgb) {
+                        *color = Srgb8::new(rgb.r, rgb.g, rgb.b);
+                    }
+                    *brightness
+                }
+                Source::Solid(led) => {
+                    self.colors.fill(led.color);
+                    led.brightness
+                }
+            };
+
+            for (led, color) in leds[start..end].iter_mut().zip(&self.colors) {
+                let top = Led::new(*color, brightness);
+                *led = blend(layer.mode, led, &top, opacity);
+            }
+        }
+    }
+}
+
+type Light = [f32; 3];
+
+/// Linear light the LED puts out, `1` being full color at full brightness
+fn light(led: &Led) -> Light {
+    let lin: LinSrgb = led.color.into_format::<f32>().into_linear();
+    let scale = led.brightness as f32 / FULL_BRIGHTNESS as f32;
+    [lin.red * scale, lin.green * scale, lin.blue * scale]
+}
+
+fn luma(light: &Light) -> f32 {
+    0.2126 * light[0] + 0.7152 * light[1] + 0.0722 * light[2]
+}
+
+fn each(a: Light, b: Light, f: impl Fn(f32, f32) -> f32) -> Light {
+    [f(a[0], b[0]), f(a[1], b[1]), f(a[2], b[2])]
+}
+
+/// `top` over `below` with `mode`, at `opacity` between `0` and `1`
+pub fn blend(mode: BlendMode, below: &Led, top: &Led, opacity: f32) -> Led {
+    let a = light(below);
+    let b = light(top);
+    let dark = b == [0.; 3];
+
+    let (blended, brightness) = match mode {
+        BlendMode::Normal => (b, top.brightness),
+        BlendMode::Add => (each(a, b, |a, b| (a + b).min(1.)), top.brightness),
+        BlendMode::Multiply => {
+            let filter = light(&Led::new(top.color, FULL_BRIGHTNESS));
+            (each(a, filter, |a, f| a * f), below.brightness)
+        }
+        BlendMode::Screen => (each(a, b, |a, b| 1. - (1. - a) * (1. - b)), top.brightness),
+        BlendMode::Max => (each(a, b, f32::max), top.brightness),
+        BlendMode::Lighten if luma(&b) > luma(&a) => (b, top.brightness),
+        BlendMode::Lighten => (a, below.brightness),
+    };
+    // brightening modes need room for both, unless the layer adds nothing
+    let brightness = match mode {
+        BlendMode::Normal | BlendMode::Multiply | BlendMode::Lighten => brightness,
+        _ if dark => below.brightness,
+        _ => brightness.max(below.brightness),
+    };
+
+    let opacity = opacity.clamp(0., 1.);
+    let light = each(a, blended, |a, b| a + (b - a) * opacity);
+    let brightness =
+        below.brightness as f32 + (brightness as f32 - below.brightness as f32) * opacity;
+    let brightness = libm::roundf(brightness) as u8;
+    if brightness == 0 {
+        return Led::default();
+    }
+
+    let scale = brightness as f32 / FULL_BRIGHTNESS as f32;
+    let [r, g, b] = light.map(|c| (c / scale).clamp(0., 1.));
+    let color: Srgb = Srgb::from_linear(LinSrgb::new(r, g, b));
+    Led::new(color.into_format(), brightness)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::strip::{SegmentBuilder, State};
+    use alloc::vec;
+    use effects::effect::Chaser;
+    use uuid::Uuid;
+
+    fn led(r: u8, g: u8, b: u8, brightness: u8) -> Led {
+        Led::new(Srgb8::new(r, g, b), brightness)
+    }
+
+    #[test]
+    fn layers_cover_their_range() {
+        let base = led(0, 0, 255, 20);
+        let mut leds = [base; 6];
+        let mut compositor = Compositor::new();
+        compositor.push(Layer {
+            range: 2..4,
+            ..Layer::new(Source::Solid(led(255, 0, 0, 50)), BlendMode::Normal)
+        });
+        compositor.push(Layer {
+            range: 5..100,
+            ..Layer::new(Source::Solid(led(0, 255, 0, 50)), BlendMode::Normal)
+        });
+        compositor.render(0, &mut leds);
+        assert_eq!(leds[..2], [base; 2]);
+        assert_eq!(leds[2..4], [led(255, 0, 0, 50); 2]);
+        assert_eq!(leds[4], base);
+        assert_eq!(leds[5], led(0, 255, 0, 50));
+    }
+
+    #[test]
+    fn blend_modes() {
+        let red = led(255, 0, 0, 40);
+        let green = led(0, 255, 0, 40);
+        let blend = |mode, below: Led, top: Led| blend(mode, &below, &top, 1.);
+
+        assert_eq!(blend(BlendMode::Add, red, green), led(255, 255, 0, 40));
+        assert_eq!(blend(BlendMode::Max, red, green), led(255, 255, 0, 40));
+        assert_eq!(blend(BlendMode::Screen, red, green), led(255, 255, 0, 40));
+        // green is the brighter hue
+        assert_eq!(blend(BlendMode::Lighten, red, green), green);
+        assert_eq!(blend(BlendMode::Lighten, green, red), green);
+        // a red filter over white, at whatever brightness
+        let white = led(255, 255, 255, 40);
+        assert_eq!(blend(BlendMode::Multiply, white, led(255, 0, 0, 1)), red);
+        assert_eq!(blend(BlendMode::Multiply, green, red), led(0, 0, 0, 40));
+
+        // screen eases in where add clips
+        let grey = led(200, 200, 200, 100);
+        let added = blend(BlendMode::Add, grey, grey).color;
+        let screened = blend(BlendMode::Screen, grey, grey).color;
+        assert_eq!(added, Srgb8::new(255, 255, 255));
+        assert!(screened.red > 200 && screened.red < 255, "{screened:?}");
+
+        // nothing at zero opacity
+        assert_eq!(super::blend(BlendMode::Normal, &red, &green, 0.), red);
+    }
+
+    #[test]
+    fn brightness_is_part_of_the_light() {
+        // a dim layer adds as much as it shows on its own
+        let dim = led(255, 0, 0, 10);
+        assert_eq!(blend(BlendMode::Add, &led(0, 0, 0, 10), &dim, 1.), dim);
+        // and keeps its light when what's below is brighter
+        let over = blend(BlendMode::Add, &led(0, 0, 0, 50), &dim, 1.);
+        assert_eq!(over.brightness, 50);
+        assert!(over.color.red > 100 && over.color.red < 150, "{over:?}");
+        let both = blend(BlendMode::Add, &led(0, 0, 255, 50), &dim, 1.);
+        assert_eq!(both.brightness, 50);
+        assert!(both.color.red < 200 && both.color.blue == 255, "{both:?}");
+
+        // dark parts of a layer leave what's below exactly as it was
+        let below = led(12, 34, 56, 7);
+        for mode in [BlendMode::Add, BlendMode::Screen, BlendMode::Max] {
+            assert_eq!(blend(mode, &below, &led(0, 0, 0, 100), 1.), below);
+        }
+    }
+
+    #[test]
+    fn flashes_fade_out_and_go_away() {
+        let flash: Flash = serde_json::from_str(
+            r#"{"color": {"red": 255, "green": 255, "blue": 255}, "ms": 1000}"#,
+        )
+        .unwrap();
+        let mut compositor = Compositor::new();
+        compositor.push(flash.layer(1000));
+        let frame = |compositor: &mut Compositor, at| {
+            let mut leds = [led(0, 0, 0, 10); 3];
+            compositor.render(at, &mut leds);
+            leds[0]
+        };
+
+        assert_eq!(frame(&mut compositor, 500), led(255, 255, 255, 100));
+        assert_eq!(frame(&mut compositor, 1000), led(255, 255, 255, 100));
+        let half = frame(&mut compositor, 1500);
+        assert_eq!(half.brightness, 55);
+        assert!(half.color.red > 200, "{half:?}");
+        assert!(!compositor.is_empty());
+        assert_eq!(frame(&mut compositor, 2000), led(0, 0, 0, 10));
+        assert!(compositor.is_empty());
+    }
+
+    #[test]
+    fn flashes_cover_leds_past_the_segments() {
+        let seg = SegmentBuilder::new(Uuid::from_u128(1))
+            .length(2)
+            .build()
+            .unwrap();
+        let state = State::new([seg].into_iter());
+        // the whole strip, not just what the segments cover
+        let mut leds = vec![led(1, 2, 3, 4); 8];
+        state.render(0, &mut leds);
+        let flash = Flash {
+            color: Wrap(Srgb8::new(255, 255, 255)),
+            ms: 100,
+        };
+        let mut compositor = Compositor::new();
+        compositor.push(flash.layer(0));
+        compositor.render(0, &mut leds);
+        assert!(
+            leds.iter().all(|l| *l == led(255, 255, 255, 100)),
+            "{leds:?}"
+        );
+    }
+
+    #[test]
+    fn effects_over_segments() {
+        let color = Srgb8::new(0, 0, 255);
+        let seg = SegmentBuilder::new(Uuid::from_u128(1))
+            .length(8)
+            .colors(color, color)
+            .brightness(30)
+            .build()
+            .unwrap();
+        let state = State::new([seg.clone()].into_iter());
+
+        let mut compositor = Compositor::new();
+        compositor.push(Layer {
+            range: 4..8,
+            ..Layer::new(Source::Segment(seg), BlendMode::Normal)
+        });
+        compositor.push(Layer::new(
+            Source::Effect {
+                effect: Box::new(Chaser { step_ms: 100 }),
+                brightness: 30,
+            },
+            BlendMode::Add,
+        ));
+
+        let mut leds = vec![Led::default(); 10];
+        state.render(250, &mut leds);
+        compositor.render(250, &mut leds);
+        // the chaser is on LED 2, the rest shows through
+        let base = led(0, 0, 255, 30);
+        assert_eq!(leds[..2], [base; 2]);
+        assert_ne!(leds[2], base);
+        assert_eq!(leds[2].color.blue, 255);
+        assert_eq!(leds[3..8], [base; 5]);
+        assert_eq!(leds[8..], [Led::default(); 2]);
+    }
+}
@@ -0,0 +1,190 @@
+//! Temporal dithering of what the strip puts out.
+//!
+//! Slow fades move less than one 8 bit step per frame. Mix segments keep
+//! the rest in [`Led::fine`], the calibration curve turns that into an
+//! [`Intensity`] in between steps, and [`hdr_pixel`] gets as close to it as
+//! global current and 8 bit PWM allow. [`Dither`] carries what each pixel
+//! missed by, measured in light after the curve, into the next frame, so
+//! over a few frames every LED averages out to what was asked for. It needs
+//! frames at a steady rate, see [`FrameScheduler`](crate::clock::FrameScheduler).
+
+use alloc::vec::Vec;
+
+use crate::{
+    calibration::Curve,
+    output::{hdr_pixel, led_intensity, ApaPixel, Intensity, MAX_GLOBAL},
+    strip::Led,
+};
+
+/// Light is counted in units of `1 / (FULL * STEPS)`, fine enough for both
+/// an [`Intensity`] step and a PWM step at any global current
+const FULL: i64 = u16::MAX as i64;
+const STEPS: i64 = 255 * MAX_GLOBAL as i64;
+/// Half a PWM step at full global current, the most rounding can be off
+const MAX_ERROR: i64 = FULL * MAX_GLOBAL as i64 / 2;
+
+/// Per LED output error, carried from frame to frame
+#[derive(Clone, Debug, Default)]
+pub struct Dither {
+    error: Vec<[i64; 3]>,
+}
+
+impl Dither {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Forget the carried error
+    pub fn reset(&mut self) {
+        self.error.clear();
+    }
+
+    /// [`hdr_pixel`] for LED `idx`, plus whatever it missed by last frame
+    pub fn pixel(&mut self, idx: usize, target: Intensity) -> ApaPixel {
+        if self.error.len() <= idx {
+            self.error.resize(idx + 1, [0; 3]);
+        }
+        let error = &mut self.error[idx];
+
+        let mut wanted = [0; 3];
+        let mut rounded = [0; 3];
+        for (c, &t) in target.channels().iter().enumerate() {
+            wanted[c] = t as i64 * STEPS + error[c];
+            rounded[c] = ((wanted[c] + STEPS / 2).div_euclid(STEPS)).clamp(0, FULL) as u16;
+        }
+        let px = hdr_pixel(Intensity {
+            red: rounded[0],
+            green: rounded[1],
+            blue: rounded[2],
+        });
+
+        let pwm = [px.color.red, px.color.green, px.color.blue];
+        for c in 0..3 {
+            let out = pwm[c] as i64 * px.global as i64 * FULL;
+            // clamped away at the ends, don't let it pile up there
+            error[c] = (wanted[c] - out).clamp(-MAX_ERROR, MAX_ERROR);
+        }
+        px
+    }
+
+    /// [`apa_pixel`](crate::output::apa_pixel) for a whole frame, dithered
+    pub fn frame(&mut self, leds: &[Led], master: f32, curve: &Curve, out: &mut Vec<ApaPixel>) {
+        out.clear();
+        for (idx, led) in leds.iter().enumerate() {
+            let px = self.pixel(idx, led_intensity(led, master, curve));
+            out.push(px);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::{apa_pixel, intensity};
+    use crate::strip::{Period, SegmentBuilder, Srgb8, State, FULL_BRIGHTNESS};
+    use alloc::vec;
+    use uuid::Uuid;
+
+    fn red(t: u16) -> Intensity {
+        Intensity {
+            red: t,
+            ..Intensity::default()
+        }
+    }
+
+    fn average(frames: usize, mut px: impl FnMut() -> ApaPixel) -> f32 {
+        (0..frames).map(|_| intensity(&px())[0]).sum::<f32>() / frames as f32
+    }
+
+    #[test]
+    fn averages_to_the_exact_light() {
+        // so close to full that only full global current reaches it, and
+        // between two of its PWM steps
+        let t = (254.4 / 255. * u16::MAX as f32) as u16;
+        let wanted = t as f32 / u16::MAX as f32;
+        let plain = intensity(&hdr_pixel(red(t)))[0];
+        let mut dither = Dither::new();
+        let dithered = average(100, || dither.pixel(0, red(t)));
+        assert!((plain - wanted).abs() > 1e-3, "{plain}");
+        assert!((dithered - wanted).abs() < 1e-4, "{dithered}");
+    }
+
+    #[test]
+    fn only_flickers_by_one_step() {
+        let t = (254.5 / 255. * u16::MAX as f32) as u16;
+        let mut dither = Dither::new();
+        for _ in 0..20 {
+            let px = dither.pixel(3, red(t));
+            assert_eq!(px.global, MAX_GLOBAL);
+            assert!(px.color.red == 254 || px.color.red == 255, "{px:?}");
+        }
+
+        // exact ones don't flicker at all
+        let mut dither = Dither::new();
+        let full = hdr_pixel(red(u16::MAX));
+        assert!((0..20).all(|_| dither.pixel(0, red(u16::MAX)) == full));
+        assert!((0..20).all(|_| dither.pixel(1, red(0)) == ApaPixel::default()));
+    }
+
+    #[test]
+    fn slow_dim_fades_track_the_unrounded_color() {
+        // between two close, dim reds, a few seconds per 8 bit step
+        let seg = SegmentBuilder::new(Uuid::from_u128(1))
+            .length(1)
+            .colors(Srgb8::new(10, 0, 0), Srgb8::new(14, 0, 0))
+            .brightness(FULL_BRIGHTNESS)
+            .period(Period::Millis { ms: 200_000 })
+            .build()
+            .unwrap();
+        let state = State::new([seg.clone()].into_iter());
+        let curve = Curve::default();
+
+        let mut dither = Dither::new();
+        let (mut leds, mut pixels) = (vec![Led::default(); 1], Vec::new());
+        let (mut worst_dithered, mut worst_plain) = (0f32, 0f32);
+        let mut at = 40_000;
+        for _ in 0..50 {
+            let (mut ideal, mut dithered, mut plain) = (0., 0., 0.);
+            // 20 frames at 100 fps
+            for _ in 0..20 {
+                state.render(at, &mut leds);
+                dither.frame(&leds, 1., &curve, &mut pixels);
+                let exact = seg.mix_exact(seg.phase_at(at)).red;
+                ideal += libm::powf(exact, 2.2);
+                dithered += intensity(&pixels[0])[0];
+                let rounded = Led::new(leds[0].color, leds[0].brightness);
+                plain += intensity(&apa_pixel(&rounded, 1., &curve))[0];
+                at += 10;
+            }
+            worst_dithered = worst_dithered.max((dithered - ideal).abs() / 20.);
+            worst_plain = worst_plain.max((plain - ideal).abs() / 20.);
+        }
+        // in units of one 8 bit step there
+        let step = libm::powf(13. / 255., 2.2) - libm::powf(12. / 255., 2.2);
+        assert!(
+            worst_dithered < step / 10.,
+            "{} steps",
+            worst_dithered / step
+        );
+        assert!(worst_plain > step / 4., "{} steps", worst_plain / step);
+    }
+
+    #[test]
+    fn slow_bright_fades_average_out() {
+        // the top of a fade, where PWM steps are coarsest
+        let mut dither = Dither::new();
+        let (mut worst_dithered, mut worst_plain) = (0f32, 0f32);
+        for step in 0..50u32 {
+            let t = (u16::MAX as u32 * 97 / 100 + step * 7) as u16;
+            let wanted = t as f32 / u16::MAX as f32;
+            let dithered = average(20, || dither.pixel(0, red(t)));
+            let plain = intensity(&hdr_pixel(red(t)))[0];
+            worst_dithered = worst_dithered.max((dithered - wanted).abs());
+            worst_plain = worst_plain.max((plain - wanted).abs());
+        }
+        assert!(
+            worst_dithered < worst_plain / 4.,
+            "{worst_dithered} {worst_plain}"
+        );
+    }
+}
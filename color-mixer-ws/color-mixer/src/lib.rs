@@ -1,3 +1,20 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+pub mod calibration;
+pub mod clock;
+pub mod compose;
+pub mod dither;
+pub mod mesh;
+pub mod output;
+pub mod patch;
+pub mod power;
+pub mod preset;
+pub mod schedule;
+pub mod schema;
 pub mod strip;
+pub mod timesync;
+pub mod transition;
 
 pub trait Container: Clone {}
@@ -0,0 +1,548 @@
+//! Keeping several boards in one room on the same animation time.
+//!
+//! Every [`Node`] multicasts a [`Message::Beacon`] with its shared time once
+//! per `beacon_interval_ms`. The eligible node with the lowest id is the
+//! leader, everyone else follows the leader's beacons. Beacons are one-way, so
+//! the offset sample with the least delay is the largest `leader - local`
+//! seen recently; followers slew towards that instead of jumping.
+//!
+//! A node only becomes eligible once it follows a leader (or nobody spoke up
+//! during `listen_ms`), so a new node with a low id first adopts the room's
+//! time and then takes over without a visible step.
+//!
+//! Optionally the segment [`State`] is replicated: every edit gets a
+//! [`Revision`], beacons advertise the newest one a node has, and whoever has
+//! a newer state than a peer advertises sends it out.
+//!
+//! Nothing in here touches the network, a transport feeds received messages
+//! to [`Node::handle`] and sends whatever [`Node::poll`] returns.
+
+use alloc::{collections::BTreeMap, collections::VecDeque, vec::Vec};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{strip::State, timesync::Slew};
+
+pub type NodeId = u64;
+
+/// Orders state edits across the whole mesh, ties broken by origin
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub struct Revision {
+    pub counter: u64,
+    pub origin: NodeId,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "t", rename_all = "snake_case")]
+pub enum Message {
+    Beacon {
+        from: NodeId,
+        /// the sender's shared time when sending
+        time: u64,
+        eligible: bool,
+        rev: Option<Revision>,
+    },
+    State {
+        from: NodeId,
+        rev: Revision,
+        state: State,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct MeshConfig {
+    pub beacon_interval_ms: u64,
+    /// peers not heard from for this long are gone
+    pub peer_timeout_ms: u64,
+    /// how long a fresh node waits for a leader before it may lead itself
+    pub listen_ms: u64,
+    /// number of leader beacons the offset filter looks at
+    pub window: usize,
+    pub max_slew: f64,
+    pub step_threshold_ms: f64,
+    pub replicate_state: bool,
+}
+
+impl Default for MeshConfig {
+    fn default() -> Self {
+        Self {
+            beacon_interval_ms: 1_000,
+            peer_timeout_ms: 3_500,
+            listen_ms: 3_000,
+            window: 8,
+            max_slew: 0.05,
+            step_threshold_ms: 1_000.,
+            replicate_state: true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Peer {
+    last_seen: u64,
+    eligible: bool,
+    rev: Option<Revision>,
+}
+
+pub struct Node {
+    id: NodeId,
+    config: MeshConfig,
+    started: u64,
+    peers: BTreeMap<NodeId, Peer>,
+    /// leader the samples below came from
+    following: Option<NodeId>,
+    /// `(local receive time, leader time - local receive time)`
+    samples: VecDeque<(u64, i64)>,
+    /// offset towards the leader, held while leading or between leaders
+    target: Option<f64>,
+    /// has followed a leader at some point
+    synced: bool,
+    slew: Slew,
+    last_beacon: Option<u64>,
+    state: Option<(Revision, State)>,
+    state_update: Option<State>,
+    outbox: Vec<Message>,
+}
+
+impl Node {
+    pub fn new(id: NodeId, config: MeshConfig, local: u64) -> Self {
+        Self {
+            id,
+            samples: VecDeque::with_capacity(config.window),
+            slew: Slew::new(config.max_slew, config.step_threshold_ms),
+            config,
+            started: local,
+            peers: BTreeMap::new(),
+            following: None,
+            target: None,
+            synced: false,
+            last_beacon: None,
+            state: None,
+            state_update: None,
+            outbox: Vec::new(),
+        }
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    /// Whether this node may be elected
+    pub fn is_eligible(&self, local: u64) -> bool {
+        self.synced || local.saturating_sub(self.started) >= self.config.listen_ms
+    }
+
+    /// The eligible node with the lowest id, if any
+    pub fn leader(&self, local: u64) -> Option<NodeId> {
+        let me = self.is_eligible(local).then_some(self.id);
+        let peers = self
+            .live_peers(local)
+            .filter(|(_, peer)| peer.eligible)
+            .map(|(id, _)| *id);
+        me.into_iter().chain(peers).min()
+    }
+
+    pub fn is_leader(&self, local: u64) -> bool {
+        self.leader(local) == Some(self.id)
+    }
+
+    /// Ids of the peers heard from recently
+    pub fn peers(&self, local: u64) -> impl Iterator<Item = NodeId> + '_ {
+        self.live_peers(local).map(|(id, _)| *id)
+    }
+
+    /// The mesh wide animation time corresponding to `local`
+    ///
+    /// Only steps (possibly backwards) when first syncing to a leader, after
+    /// that it slews.
+    pub fn now(&mut self, local: u64) -> u64 {
+        if self.leader(local) != self.following {
+            // samples of the previous leader say nothing about anyone else,
+            // the target they led to is kept until there are new ones
+            self.samples.clear();
+            self.following = None;
+        }
+        if self.target.is_none() && self.is_leader(local) {
+            // leading without ever having followed: our own time is the time
+            self.target = Some(0.);
+        }
+        self.slew.apply(local, self.target)
+    }
+
+    pub fn handle(&mut self, msg: Message, local: u64) {
+        match msg {
+            Message::Beacon {
+                from,
+                time,
+                eligible,
+                rev,
+            } => {
+                if from == self.id {
+                    return;
+                }
+                self.peers.insert(
+                    from,
+                    Peer {
+                        last_seen: local,
+                        eligible,
+                        rev,
+                    },
+                );
+
+                if eligible && self.leader(local) == Some(from) {
+                    if self.following != Some(from) {
+                        self.samples.clear();
+                        self.following = Some(from);
+                    }
+                    if self.samples.len() == self.config.window {
+                        self.samples.pop_front();
+                    }
+                    self.samples.push_back((local, time as i64 - local as i64));
+                    self.synced = true;
+
+                    // the least delayed beacon gives the largest offset
+                    let best = self.samples.iter().map(|(_, offset)| *offset).max();
+                    self.target = best.map(|offset| offset as f64);
+                }
+
+                if self.config.replicate_state {
+                    if let Some((ours, state)) = &self.state {
+                        if rev.is_none_or(|theirs| theirs < *ours) {
+                            self.queue_state(*ours, state.clone());
+                        }
+                    }
+                }
+            }
+            Message::State { from, rev, state } => {
+                if from == self.id || !self.config.replicate_state {
+                    return;
+                }
+                if self.state.as_ref().is_none_or(|(ours, _)| rev > *ours) {
+                    self.state = Some((rev, state.clone()));
+                    self.state_update = Some(state);
+                }
+            }
+        }
+    }
+
+    /// Messages to send now: a beacon when one is due plus anything queued
+    pub fn poll(&mut self, local: u64) -> Vec<Message> {
+        let due = self
+            .last_beacon
+            .is_none_or(|last| local.saturating_sub(last) >= self.config.beacon_interval_ms);
+        if due {
+            self.last_beacon = Some(local);
+            let time = self.now(local);
+            self.outbox.push(Message::Beacon {
+                from: self.id,
+                time,
+                eligible: self.is_eligible(local),
+                rev: self.state.as_ref().map(|(rev, _)| *rev),
+            });
+        }
+        self.peers
+            .retain(|_, peer| local.saturating_sub(peer.last_seen) < self.config.peer_timeout_ms);
+        core::mem::take(&mut self.outbox)
+    }
+
+    /// A local edit, to be sent to the other nodes
+    pub fn set_state(&mut self, state: State) {
+        let newest_seen = self
+            .peers
+            .values()
+            .filter_map(|peer| peer.rev)
+            .chain(self.state.as_ref().map(|(rev, _)| *rev))
+            .map(|rev| rev.counter)
+            .max()
+            .unwrap_or(0);
+        let rev = Revision {
+            counter: newest_seen + 1,
+            origin: self.id,
+        };
+        if self.config.replicate_state {
+            self.queue_state(rev, state.clone());
+        }
+        self.state = Some((rev, state));
+    }
+
+    /// A state received from another node since the last call, to be applied
+    pub fn take_state_update(&mut self) -> Option<State> {
+        self.state_update.take()
+    }
+
+    pub fn revision(&self) -> Option<Revision> {
+        self.state.as_ref().map(|(rev, _)| *rev)
+    }
+
+    fn queue_state(&mut self, rev: Revision, state: State) {
+        let queued = self
+            .outbox
+            .iter()
+            .any(|msg| matches!(msg, Message::State { rev: r, .. } if *r == rev));
+        if !queued {
+            self.outbox.push(Message::State {
+                from: self.id,
+                rev,
+                state,
+            });
+        }
+    }
+
+    fn live_peers(&self, local: u64) -> impl Iterator<Item = (&NodeId, &Peer)> + '_ {
+        let timeout = self.config.peer_timeout_ms;
+        self.peers
+            .iter()
+            .filter(move |(_, peer)| local.saturating_sub(peer.last_seen) < timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::strip::{Segment, Srgb8};
+    use alloc::vec;
+
+    /// xorshift, so the network behaves the same on every run
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+    }
+
+    /// A board with its own crystal: `local = boot + global * (1 + drift)`
+    struct SimNode {
+        node: Node,
+        boot: u64,
+        drift: f64,
+        online: bool,
+        joined: u64,
+        last_global: u64,
+        last_now: u64,
+    }
+
+    fn local(boot: u64, drift: f64, global: u64) -> u64 {
+        boot + (global as f64 * (1. + drift)) as u64
+    }
+
+    impl SimNode {
+        fn local(&self, global: u64) -> u64 {
+            local(self.boot, self.drift, global)
+        }
+    }
+
+    /// Several nodes on one multicast group, with 1..=8ms of latency
+    struct Sim {
+        nodes: Vec<SimNode>,
+        in_flight: Vec<(u64, usize, Message)>,
+        rng: Rng,
+        global: u64,
+    }
+
+    const STEP: u64 = 10;
+
+    impl Sim {
+        fn new() -> Self {
+            Self {
+                nodes: vec![],
+                in_flight: vec![],
+                rng: Rng(0x1ed5),
+                global: 0,
+            }
+        }
+
+        fn add(&mut self, id: NodeId, boot: u64, drift: f64) -> usize {
+            let start = local(boot, drift, self.global);
+            self.nodes.push(SimNode {
+                node: Node::new(id, MeshConfig::default(), start),
+                boot,
+                drift,
+                online: true,
+                joined: self.global,
+                last_global: self.global,
+                last_now: 0,
+            });
+            self.nodes.len() - 1
+        }
+
+        fn run(&mut self, ms: u64) {
+            let end = self.global + ms;
+            while self.global < end {
+                self.global += STEP;
+                let global = self.global;
+
+                let (due, pending): (Vec<_>, Vec<_>) = self
+                    .in_flight
+                    .drain(..)
+                    .partition(|(at, _, _)| *at <= global);
+                self.in_flight = pending;
+                for (_, to, msg) in due {
+                    let sim = &mut self.nodes[to];
+                    if sim.online {
+                        let local = sim.local(global);
+                        sim.node.handle(msg, local);
+                    }
+                }
+
+                for from in 0..self.nodes.len() {
+                    if !self.nodes[from].online {
+                        continue;
+                    }
+                    let local = self.nodes[from].local(global);
+                    let sim = &mut self.nodes[from];
+                    let now = sim.node.now(local);
+                    // a newcomer steps onto the room's time once, after that
+                    // nobody may run backwards or jump
+                    if global - sim.joined > 5_000 {
+                        let id = sim.node.id();
+                        let elapsed = global - sim.last_global;
+                        assert!(now >= sim.last_now, "{id} went backwards");
+                        let step = now - sim.last_now;
+                        assert!(step <= elapsed + elapsed / 10 + 1, "{id} jumped {step}");
+                    }
+                    sim.last_global = global;
+                    sim.last_now = now;
+
+                    for msg in sim.node.poll(local) {
+                        for to in 0..self.nodes.len() {
+                            if to != from {
+                                let at = global + 1 + self.rng.below(8);
+                                self.in_flight.push((at, to, msg.clone()));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        fn spread(&mut self) -> u64 {
+            let global = self.global;
+            let nows: Vec<u64> = self
+                .nodes
+                .iter_mut()
+                .filter(|sim| sim.online)
+                .map(|sim| {
+                    let local = sim.local(global);
+                    sim.node.now(local)
+                })
+                .collect();
+            nows.iter().max().unwrap() - nows.iter().min().unwrap()
+        }
+
+        fn leaders(&self) -> Vec<Option<NodeId>> {
+            self.nodes
+                .iter()
+                .filter(|sim| sim.online)
+                .map(|sim| sim.node.leader(sim.local(self.global)))
+                .collect()
+        }
+    }
+
+    fn state(color: u8) -> State {
+        let seg = Segment::new_with_uuid(
+            uuid::Uuid::from_u128(color as u128),
+            3,
+            false,
+            Srgb8::new(color, 0, 0),
+            Srgb8::new(0, 0, color),
+            0,
+            100,
+            10,
+        );
+        State::new([seg].into_iter())
+    }
+
+    #[test]
+    fn elects_lowest_id_and_converges() {
+        let mut sim = Sim::new();
+        sim.add(30, 5_000, 100e-6);
+        sim.add(10, 123_000, -50e-6);
+        sim.add(20, 0, 0.);
+        sim.run(30_000);
+
+        assert_eq!(sim.leaders(), vec![Some(10); 3]);
+        assert!(sim.spread() <= 10, "spread {}", sim.spread());
+    }
+
+    #[test]
+    fn leader_failover_keeps_time() {
+        let mut sim = Sim::new();
+        let first = sim.add(1, 70_000, 0.);
+        sim.add(2, 0, 80e-6);
+        sim.add(3, 9_000, -80e-6);
+        sim.run(20_000);
+        assert!(sim.spread() <= 10);
+
+        sim.nodes[first].online = false;
+        sim.run(20_000);
+
+        assert_eq!(sim.leaders(), vec![Some(2); 2]);
+        assert!(sim.spread() <= 10, "spread {}", sim.spread());
+    }
+
+    #[test]
+    fn late_low_id_joins_without_stepping_the_room() {
+        let mut sim = Sim::new();
+        sim.add(5, 40_000, 0.);
+        sim.add(6, 0, 0.);
+        sim.run(10_000);
+
+        // run() asserts nobody jumps while the newcomer takes over
+        sim.add(1, 999_000, 0.);
+        sim.run(20_000);
+
+        assert_eq!(sim.leaders(), vec![Some(1); 3]);
+        assert!(sim.spread() <= 10, "spread {}", sim.spread());
+    }
+
+    #[test]
+    fn lone_node_leads_after_listening() {
+        let mut node = Node::new(7, MeshConfig::default(), 100);
+        assert_eq!(node.leader(100), None);
+        assert_eq!(node.now(100), 100);
+        assert!(node.is_leader(3_100));
+        assert_eq!(node.now(3_100), 3_100);
+    }
+
+    #[test]
+    fn replicates_newest_state() {
+        let mut sim = Sim::new();
+        let a = sim.add(1, 0, 0.);
+        let b = sim.add(2, 0, 0.);
+        let c = sim.add(3, 0, 0.);
+        sim.run(5_000);
+
+        sim.nodes[b].node.set_state(state(1));
+        sim.run(100);
+        for i in [a, c] {
+            assert_eq!(sim.nodes[i].node.take_state_update(), Some(state(1)));
+        }
+
+        // a stale edit loses against the one everyone already has
+        let stale = Message::State {
+            from: 9,
+            rev: Revision {
+                counter: 0,
+                origin: 9,
+            },
+            state: state(2),
+        };
+        sim.nodes[a].node.handle(stale, 0);
+        assert_eq!(sim.nodes[a].node.take_state_update(), None);
+
+        // a node that missed the edit catches up from the next beacons
+        sim.nodes[c].online = false;
+        sim.nodes[a].node.set_state(state(3));
+        sim.run(100);
+        sim.nodes[c].online = true;
+        sim.run(3_000);
+        assert_eq!(sim.nodes[c].node.take_state_update(), Some(state(3)));
+        assert_eq!(sim.nodes[c].node.revision(), sim.nodes[a].node.revision());
+    }
+}
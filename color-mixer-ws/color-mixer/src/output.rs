@@ -0,0 +1,225 @@
+//! From rendered [`Led`]s to what APA102 / SK9822 strips take.
+//!
+//! Those have a 5 bit global current per pixel on top of 8 bit PWM per
+//! channel. Colors come in as 16 bit [`Intensity`], with segment brightness,
+//! master brightness and whatever else dims the strip already multiplied in,
+//! and [`hdr_pixel`] picks the global current and PWM that come closest. Dim
+//! pixels get a low global current and most of the PWM range, so slow fades
+//! near black step smoothly instead of banding.
+
+use crate::{
+    calibration::Curve,
+    strip::{Led, Srgb8, FULL_BRIGHTNESS},
+};
+
+/// Highest value of the 5 bit global brightness
+pub const MAX_GLOBAL: u8 = 31;
+
+const MAX_PWM: u64 = 255;
+const FULL: u64 = u16::MAX as u64;
+
+/// One pixel as the strip takes it
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct ApaPixel {
+    pub color: Srgb8,
+    /// `0..=MAX_GLOBAL`
+    pub global: u8,
+}
+
+/// Light output per channel, linear in PWM duty times global current. `0` is
+/// off, `u16::MAX` is full PWM at full global brightness.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Intensity {
+    pub red: u16,
+    pub green: u16,
+    pub blue: u16,
+}
+
+impl Intensity {
+    /// `color` at `level`, a fraction of full, without going through 8 bit
+    pub fn from_color(color: Srgb8, level: f32) -> Self {
+        let level = level.clamp(0., 1.);
+        let channel = |c: u8| libm::roundf(c as f32 / 255. * level * FULL as f32) as u16;
+        Self {
+            red: channel(color.red),
+            green: channel(color.green),
+            blue: channel(color.blue),
+        }
+    }
+
+    pub fn channels(&self) -> [u16; 3] {
+        [self.red, self.green, self.blue]
+    }
+}
+
+/// The global brightness and PWM that come closest to `target`.
+///
+/// Tries the lowest global brightness that reaches the brightest channel and
+/// the two above it. The lowest has the finest PWM steps, but rounding
+/// sometimes lands closer on one just above, never much further up. All
+/// integer math, the C3 has no FPU.
+pub fn hdr_pixel(target: Intensity) -> ApaPixel {
+    let channels = target.channels();
+    let brightest = channels.iter().copied().max().unwrap_or(0) as u64;
+    if brightest == 0 {
+        return ApaPixel::default();
+    }
+    let max_global = MAX_GLOBAL as u64;
+    let lowest = (brightest * max_global).div_ceil(FULL).max(1);
+
+    let mut best = (u64::MAX, ApaPixel::default());
+    for global in lowest..=(lowest + 2).min(max_global) {
+        // pwm * global / (255 * 31) should be t / FULL, errors are in
+        // units of 1 / (FULL * 255 * 31)
+        let mut error = 0;
+        let mut pwm = [0u8; 3];
+        for (pwm, &t) in pwm.iter_mut().zip(&channels) {
+            let wanted = t as u64 * MAX_PWM * max_global;
+            let step = FULL * global;
+            let p = ((wanted + step / 2) / step).min(MAX_PWM);
+            error += (p * step).abs_diff(wanted);
+            *pwm = p as u8;
+        }
+        if error < best.0 {
+            let color = Srgb8::new(pwm[0], pwm[1], pwm[2]);
+            best = (
+                error,
+                ApaPixel {
+                    color,
+                    global: global as u8,
+                },
+            );
+        }
+        if error == 0 {
+            break;
+        }
+    }
+    best.1
+}
+
+/// What `px` puts out per channel, as a fraction of full
+pub fn intensity(px: &ApaPixel) -> [f32; 3] {
+    let global = px.global.min(MAX_GLOBAL) as f32 / MAX_GLOBAL as f32;
+    let channel = |c: u8| c as f32 / MAX_PWM as f32 * global;
+    [
+        channel(px.color.red),
+        channel(px.color.green),
+        channel(px.color.blue),
+    ]
+}
+
+/// `master` is every factor on top of the LED's own brightness, `0..=1`.
+/// `curve` is the device's calibration.
+pub fn apa_pixel(led: &Led, master: f32, curve: &Curve) -> ApaPixel {
+    hdr_pixel(led_intensity(led, master, curve))
+}
+
+/// What [`apa_pixel`] aims for, before picking global and PWM
+pub fn led_intensity(led: &Led, master: f32, curve: &Curve) -> Intensity {
+    let level = led.brightness as f32 / FULL_BRIGHTNESS as f32 * master;
+    curve.intensity_fine(led.color, led.fine, level)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calibration::Calibration;
+    use alloc::vec::Vec;
+
+    // without gamma, so colors map straight to PWM
+    fn raw() -> Curve {
+        Calibration {
+            gamma: [1.; 3],
+            ..Calibration::default()
+        }
+        .curve()
+    }
+
+    fn led(brightness: u8) -> Led {
+        Led::new(Srgb8::new(255, 128, 0), brightness)
+    }
+
+    fn red(t: u16) -> Intensity {
+        Intensity {
+            red: t,
+            ..Intensity::default()
+        }
+    }
+
+    #[test]
+    fn full_and_off() {
+        let full = Intensity {
+            red: u16::MAX,
+            green: u16::MAX,
+            blue: u16::MAX,
+        };
+        assert_eq!(
+            hdr_pixel(full),
+            ApaPixel {
+                color: Srgb8::new(255, 255, 255),
+                global: MAX_GLOBAL
+            }
+        );
+        assert_eq!(hdr_pixel(Intensity::default()), ApaPixel::default());
+
+        assert_eq!(
+            apa_pixel(&led(100), 1., &raw()),
+            ApaPixel {
+                color: Srgb8::new(255, 128, 0),
+                global: MAX_GLOBAL
+            }
+        );
+        assert_eq!(apa_pixel(&led(100), 0., &raw()).global, 0);
+        assert_eq!(apa_pixel(&led(0), 1., &raw()).global, 0);
+    }
+
+    #[test]
+    fn effective_intensity_matches_the_request() {
+        for t in (0..=u16::MAX).step_by(7) {
+            let px = hdr_pixel(red(t));
+            let wanted = t as f32 / u16::MAX as f32;
+            let out = intensity(&px)[0];
+            // never off by more than half a PWM step at the chosen global
+            let half_step = px.global as f32 / (2. * 255. * 31.);
+            assert!((out - wanted).abs() <= half_step + 1e-6, "{t}: {px:?}");
+            // which is under 2% from half a percent up
+            if wanted > 0.005 {
+                assert!((out - wanted).abs() / wanted < 0.02, "{t}: {px:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn dim_fades_dont_band() {
+        // the bottom 1% in 200 steps
+        let outputs: Vec<f32> = (0..=200)
+            .map(|i| {
+                let t = (i as f32 / 200. * 0.01 * u16::MAX as f32) as u16;
+                intensity(&hdr_pixel(red(t)))[0]
+            })
+            .collect();
+        assert!(outputs.windows(2).all(|w| w[0] <= w[1]));
+        let mut distinct = outputs.clone();
+        distinct.dedup();
+        // full global with 8 bit color would have 3
+        assert!(distinct.len() > 60, "{}", distinct.len());
+    }
+
+    #[test]
+    fn keeps_the_hue_when_dim() {
+        let px = apa_pixel(&led(1), 1., &raw());
+        let [r, g, b] = intensity(&px);
+        assert!((r / g - 255. / 128.).abs() < 0.05, "{px:?}");
+        assert_eq!(b, 0.);
+    }
+
+    #[test]
+    fn master_scales_segment_brightness() {
+        // 50% segment at 20% master is 10% of full
+        let out = intensity(&apa_pixel(&led(50), 0.2, &raw()))[0];
+        assert!((out - 0.1).abs() < 0.001, "{out}");
+        // and the segments stay in proportion
+        let full_out = intensity(&apa_pixel(&led(100), 0.2, &raw()))[0];
+        assert!((full_out - 2. * out).abs() < 0.001, "{full_out}");
+    }
+}
@@ -0,0 +1,497 @@
+//! Small, operation based edits to a [`State`].
+//!
+//! Clients send a [`Patch`]: the sequence number they last saw plus a list of
+//! [`Op`]s. The server keeps a [`Journal`] which remembers the sequence number
+//! that last touched each segment, so an edit based on an outdated view of a
+//! segment is rejected while edits to other segments go through.
+
+use alloc::{collections::BTreeMap, string::ToString, vec::Vec};
+use core::fmt;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    schedule::Schedule,
+    strip::{Period, Segment, SegmentKind, State, ValidationError, Wrap},
+    transition,
+};
+
+/// One segment property
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "name", content = "value", rename_all = "snake_case")]
+pub enum Field {
+    Length(usize),
+    Bgr(bool),
+    Colors([Wrap; 2]),
+    ChillIdx(usize),
+    ChillFac(u32),
+    Brightness(u8),
+    Kind(SegmentKind),
+    Period(Period),
+}
+
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Op {
+    /// Append a new segment
+    Insert {
+        segment: Segment,
+    },
+    Remove {
+        id: Uuid,
+    },
+    /// Move a segment to position `to`
+    Move {
+        id: Uuid,
+        to: usize,
+    },
+    Set {
+        id: Uuid,
+        field: Field,
+    },
+}
+
+impl Op {
+    /// The segment this op is about
+    pub fn id(&self) -> Uuid {
+        match self {
+            Op::Insert { segment } => segment.uuid(),
+            Op::Remove { id } | Op::Move { id, .. } | Op::Set { id, .. } => *id,
+        }
+    }
+}
+
+/// A batch of ops, all or nothing
+#[derive(PartialEq, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Patch {
+    /// The last sequence number the sender has seen
+    pub base: u64,
+    pub ops: Vec<Op>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PatchError {
+    NotFound(Uuid),
+    /// `Insert` of a segment that's already there
+    Exists(Uuid),
+    /// `Move` past the end
+    BadIndex {
+        to: usize,
+        len: usize,
+    },
+    /// Someone else changed the segment at `seq`, after the patch's `base`
+    Stale {
+        id: Uuid,
+        seq: u64,
+    },
+    Invalid(ValidationError),
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatchError::NotFound(id) => write!(f, "no segment {id}"),
+            PatchError::Exists(id) => write!(f, "segment {id} already exists"),
+            PatchError::BadIndex { to, len } => {
+                write!(f, "can't move to {to}, there are {len} segments")
+            }
+            PatchError::Stale { id, seq } => write!(f, "segment {id} changed at {seq}"),
+            PatchError::Invalid(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for PatchError {}
+
+impl From<ValidationError> for PatchError {
+    fn from(e: ValidationError) -> Self {
+        PatchError::Invalid(e)
+    }
+}
+
+impl State {
+    /// Apply a single op. Segments are validated, order is kept on removal.
+    pub fn apply(&mut self, op: &Op) -> Result<(), PatchError> {
+        let key = op.id().to_string();
+        match op {
+            Op::Insert { segment } => {
+                if self.contains_key(&key) {
+                    return Err(PatchError::Exists(segment.uuid()));
+                }
+                segment.validate()?;
+                self.insert(segment.clone());
+            }
+            Op::Remove { id } => {
+                self.shift_remove(&key).ok_or(PatchError::NotFound(*id))?;
+            }
+            Op::Move { id, to } => {
+                let from = self.get_index_of(&key).ok_or(PatchError::NotFound(*id))?;
+                if *to >= self.len() {
+                    return Err(PatchError::BadIndex {
+                        to: *to,
+                        len: self.len(),
+                    });
+                }
+                self.move_index(from, *to);
+            }
+            Op::Set { id, field } => {
+                let seg = self.get_mut(&key).ok_or(PatchError::NotFound(*id))?;
+                let mut new = seg.clone();
+                match field.clone() {
+                    Field::Length(length) => new.set_length(length),
+                    Field::Bgr(bgr) => new.set_bgr(bgr),
+                    Field::Colors(colors) => *new.colors_mut() = colors,
+                    Field::ChillIdx(idx) => new.set_chill_idx(idx),
+                    Field::ChillFac(fac) => new.set_chill_fac(fac),
+                    Field::Brightness(brightness) => new.set_brightness(brightness),
+                    Field::Kind(kind) => new.set_kind(kind),
+                    Field::Period(period) => new.set_period(period),
+                }
+                new.validate()?;
+                *seg = new;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The ops that turn `old` into `new`
+pub fn diff(old: &State, new: &State) -> Vec<Op> {
+    let mut ops = Vec::new();
+
+    for seg in old.values() {
+        if !new.contains_key(&seg.to_uuid_string()) {
+            ops.push(Op::Remove { id: seg.uuid() });
+        }
+    }
+
+    for seg in new.values() {
+        let Some(before) = old.get(&seg.to_uuid_string()) else {
+            ops.push(Op::Insert {
+                segment: seg.clone(),
+            });
+            continue;
+        };
+        let id = seg.uuid();
+        let mut set = |changed: bool, field: Field| {
+            if changed {
+                ops.push(Op::Set { id, field });
+            }
+        };
+        set(before.length() != seg.length(), Field::Length(seg.length()));
+        set(before.bgr() != seg.bgr(), Field::Bgr(seg.bgr()));
+        set(
+            before.color_1() != seg.color_1() || before.color_2() != seg.color_2(),
+            Field::Colors([Wrap(*seg.color_1()), Wrap(*seg.color_2())]),
+        );
+        set(
+            before.chill_idx() != seg.chill_idx(),
+            Field::ChillIdx(seg.chill_idx()),
+        );
+        set(
+            before.chill_fac() != seg.chill_fac(),
+            Field::ChillFac(seg.chill_fac()),
+        );
+        set(
+            before.brightness() != seg.brightness(),
+            Field::Brightness(seg.brightness()),
+        );
+        set(before.kind() != seg.kind(), Field::Kind(seg.kind()));
+        set(before.period() != seg.period(), Field::Period(seg.period()));
+    }
+
+    // removals and inserts done, now fix up the order
+    let mut order: Vec<Uuid> = old
+        .values()
+        .map(Segment::uuid)
+        .filter(|id| new.contains_key(&id.to_string()))
+        .chain(
+            new.values()
+                .map(Segment::uuid)
+                .filter(|id| !old.contains_key(&id.to_string())),
+        )
+        .collect();
+    for (to, seg) in new.values().enumerate() {
+        let id = seg.uuid();
+        if order[to] != id {
+            let from = order.iter().position(|o| *o == id).unwrap_or(to);
+            let moved = order.remove(from);
+            order.insert(to, moved);
+            ops.push(Op::Move { id, to });
+        }
+    }
+
+    ops
+}
+
+/// The server's [`State`], with a sequence number for every change
+#[derive(Clone, Debug)]
+pub struct Journal {
+    state: State,
+    seq: u64,
+    /// Segments edited since the last [`replace`](Self::replace), removed
+    /// ones are dropped so this doesn't grow forever
+    touched: BTreeMap<Uuid, u64>,
+    /// Patches from before this are stale, whatever they touch
+    replaced: u64,
+}
+
+impl Journal {
+    pub fn new(state: State) -> Self {
+        Self {
+            state,
+            seq: 0,
+            touched: BTreeMap::new(),
+            replaced: 0,
+        }
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    /// Sequence number of the latest change
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Apply all of `patch` or none of it, returns the new sequence number
+    pub fn apply(&mut self, patch: &Patch) -> Result<u64, PatchError> {
+        for op in &patch.ops {
+            let id = op.id();
+            let seq = self
+                .touched
+                .get(&id)
+                .copied()
+                .unwrap_or(0)
+                .max(self.replaced);
+            if seq > patch.base {
+                return Err(PatchError::Stale { id, seq });
+            }
+        }
+
+        let mut state = self.state.clone();
+        for op in &patch.ops {
+            state.apply(op)?;
+        }
+
+        self.state = state;
+        self.seq += 1;
+        for op in &patch.ops {
+            match op {
+                Op::Remove { id } => self.touched.remove(id),
+                op => self.touched.insert(op.id(), self.seq),
+            };
+        }
+        Ok(self.seq)
+    }
+
+    /// Swap in a whole new state, everything in it or before it counts as
+    /// changed
+    pub fn replace(&mut self, state: State) -> u64 {
+        self.seq += 1;
+        self.replaced = self.seq;
+        self.touched.clear();
+        self.state = state;
+        self.seq
+    }
+
+    /// Change the master brightness, segments and their edit history stay as
+    /// they are
+    pub fn set_master_brightness(&mut self, brightness: u8) -> u64 {
+        self.state.set_master_brightness(brightness);
+        self.seq += 1;
+        self.seq
+    }
+
+    /// Swap the schedule, segments and their edit history stay as they are
+    pub fn set_schedule(&mut self, schedule: Schedule) -> u64 {
+        self.state.set_schedule(schedule);
+        self.seq += 1;
+        self.seq
+    }
+
+    /// Same for how changes come in
+    pub fn set_transition(&mut self, transition: transition::Settings) -> u64 {
+        self.state.set_transition(transition);
+        self.seq += 1;
+        self.seq
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::strip::{SegmentBuilder, Srgb8};
+    use alloc::vec;
+
+    fn seg(n: u128) -> Segment {
+        SegmentBuilder::new(Uuid::from_u128(n))
+            .length(n as usize + 1)
+            .build()
+            .unwrap()
+    }
+
+    fn state(ns: &[u128]) -> State {
+        State::new(ns.iter().map(|n| seg(*n)))
+    }
+
+    #[test]
+    fn diff_then_apply_gets_there() {
+        let old = state(&[1, 2, 3, 4]);
+        let mut new = state(&[4, 2, 5, 1]);
+        new[1].set_brightness(99);
+        new[1].colors_mut()[0] = Wrap(Srgb8::new(1, 2, 3));
+        new[3].set_period(Period::Millis { ms: 500 });
+
+        let ops = diff(&old, &new);
+        let mut applied = old.clone();
+        for op in &ops {
+            applied.apply(op).unwrap();
+        }
+        assert_eq!(applied, new);
+        assert!(ops.contains(&Op::Remove {
+            id: Uuid::from_u128(3)
+        }));
+        assert!(ops.contains(&Op::Set {
+            id: Uuid::from_u128(2),
+            field: Field::Brightness(99)
+        }));
+
+        assert!(diff(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn ops_are_small_json() {
+        let op = Op::Set {
+            id: Uuid::from_u128(1),
+            field: Field::ChillIdx(3),
+        };
+        let json = serde_json::to_string(&op).unwrap();
+        assert_eq!(
+            json,
+            r#"{"op":"set","id":"00000000-0000-0000-0000-000000000001","field":{"name":"chill_idx","value":3}}"#
+        );
+        assert_eq!(serde_json::from_str::<Op>(&json).unwrap(), op);
+    }
+
+    #[test]
+    fn invalid_ops_change_nothing() {
+        let mut journal = Journal::new(state(&[1, 2]));
+        let before = journal.state().clone();
+        let patch = Patch {
+            base: 0,
+            ops: vec![
+                Op::Set {
+                    id: Uuid::from_u128(1),
+                    field: Field::Brightness(1),
+                },
+                Op::Set {
+                    id: Uuid::from_u128(2),
+                    field: Field::ChillFac(0),
+                },
+            ],
+        };
+        assert_eq!(
+            journal.apply(&patch),
+            Err(PatchError::Invalid(ValidationError::ZeroChillFac))
+        );
+        assert_eq!(journal.state(), &before);
+        assert_eq!(journal.seq(), 0);
+
+        for op in [
+            Op::Remove {
+                id: Uuid::from_u128(9),
+            },
+            Op::Move {
+                id: Uuid::from_u128(1),
+                to: 2,
+            },
+            Op::Insert { segment: seg(2) },
+        ] {
+            assert!(journal.state.clone().apply(&op).is_err(), "{op:?}");
+        }
+    }
+
+    #[test]
+    fn concurrent_clients_only_conflict_on_the_same_segment() {
+        let mut journal = Journal::new(state(&[1, 2]));
+        let set = |n, brightness| Op::Set {
+            id: Uuid::from_u128(n),
+            field: Field::Brightness(brightness),
+        };
+
+        // both clients loaded at seq 0
+        let a = journal
+            .apply(&Patch {
+                base: 0,
+                ops: vec![set(1, 20)],
+            })
+            .unwrap();
+        assert_eq!(a, 1);
+        let b = journal
+            .apply(&Patch {
+                base: 0,
+                ops: vec![set(2, 30)],
+            })
+            .unwrap();
+        assert_eq!(b, 2);
+        assert_eq!(journal.state()[0].brightness(), 20);
+        assert_eq!(journal.state()[1].brightness(), 30);
+
+        // b has seen a's edit by now, so it can change the same segment
+        assert_eq!(
+            journal.apply(&Patch {
+                base: b,
+                ops: vec![set(1, 40)]
+            }),
+            Ok(3)
+        );
+        assert_eq!(
+            journal.apply(&Patch {
+                base: 0,
+                ops: vec![set(1, 50)]
+            }),
+            Err(PatchError::Stale {
+                id: Uuid::from_u128(1),
+                seq: 3
+            })
+        );
+
+        let seq = journal.replace(state(&[3]));
+        assert!(matches!(
+            journal.apply(&Patch {
+                base: seq - 1,
+                ops: vec![set(3, 1)]
+            }),
+            Err(PatchError::Stale { .. })
+        ));
+    }
+
+    #[test]
+    fn forgets_removed_and_replaced_segments() {
+        let mut journal = Journal::new(state(&[1]));
+        for n in 2..50 {
+            let base = journal.seq();
+            let ops = vec![
+                Op::Insert { segment: seg(n) },
+                Op::Remove {
+                    id: Uuid::from_u128(n - 1),
+                },
+            ];
+            journal.apply(&Patch { base, ops }).unwrap();
+        }
+        assert_eq!(journal.touched.len(), 1);
+
+        journal.replace(state(&[7, 8]));
+        assert!(journal.touched.is_empty());
+        let base = journal.seq();
+        let ops = vec![Op::Remove {
+            id: Uuid::from_u128(7),
+        }];
+        assert_eq!(journal.apply(&Patch { base, ops }), Ok(base + 1));
+        assert!(journal.touched.is_empty());
+    }
+}
@@ -0,0 +1,228 @@
+//! Keeping a frame within what the supply can deliver.
+//!
+//! APA102s draw a little when dark and roughly linearly more with PWM duty
+//! times global brightness per channel. A [`PowerModel`] estimates that for a
+//! frame and [`PowerModel::limit`] dims the whole frame just enough to stay
+//! under the budget, so white-heavy looks don't brown out the board.
+
+use alloc::vec::Vec;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    calibration::Curve,
+    output::{hdr_pixel, intensity, led_intensity, ApaPixel, Intensity},
+    strip::Led,
+};
+
+/// Rounding to PWM steps can land a little above the exact dimming
+const HEADROOM: f32 = 0.99;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PowerModel {
+    /// One color channel at full PWM and full global brightness
+    pub channel_ma: f32,
+    /// Every LED, even when dark
+    pub idle_ma: f32,
+    /// What the supply can give the strip, `0` for no limit
+    pub limit_ma: u32,
+}
+
+impl Default for PowerModel {
+    /// Datasheet APA102s on a 2A USB supply
+    fn default() -> Self {
+        Self {
+            channel_ma: 20.,
+            idle_ma: 1.,
+            limit_ma: 2_000,
+        }
+    }
+}
+
+/// What a frame draws, reported over the API
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PowerEstimate {
+    /// Without the limit
+    pub wanted_ma: f32,
+    /// After dimming
+    pub drawn_ma: f32,
+    /// Brightness factor the limit applied, `1` when within budget
+    pub scale: f32,
+}
+
+impl Default for PowerEstimate {
+    fn default() -> Self {
+        Self {
+            wanted_ma: 0.,
+            drawn_ma: 0.,
+            scale: 1.,
+        }
+    }
+}
+
+impl PowerModel {
+    /// No negative or non-finite currents
+    pub fn is_valid(&self) -> bool {
+        let ok = |ma: f32| ma.is_finite() && ma >= 0.;
+        ok(self.channel_ma) && ok(self.idle_ma)
+    }
+
+    pub fn pixel_ma(&self, px: &ApaPixel) -> f32 {
+        let lit: f32 = intensity(px).iter().sum();
+        self.idle_ma + self.channel_ma * lit
+    }
+
+    pub fn frame_ma(&self, pixels: &[ApaPixel]) -> f32 {
+        pixels.iter().map(|px| self.pixel_ma(px)).sum()
+    }
+
+    /// Turn `leds` into `out` at `master` like
+    /// [`apa_pixel`](crate::output::apa_pixel), dimmed as far as needed to
+    /// stay under [`PowerModel::limit_ma`]. `strip_len` is how many LEDs are
+    /// physically there, the ones past `leds` are dark but still draw their
+    /// idle current.
+    pub fn limit(
+        &self,
+        leds: &[Led],
+        strip_len: usize,
+        master: f32,
+        curve: &Curve,
+        out: &mut Vec<ApaPixel>,
+    ) -> PowerEstimate {
+        self.limit_with(leds, strip_len, master, curve, out, |_, target| {
+            hdr_pixel(target)
+        })
+    }
+
+    /// [`limit`](Self::limit) with `pixel` picking the pixel for LED `idx`,
+    /// e.g. [`Dither::pixel`](crate::dither::Dither::pixel)
+    pub fn limit_with(
+        &self,
+        leds: &[Led],
+        strip_len: usize,
+        master: f32,
+        curve: &Curve,
+        out: &mut Vec<ApaPixel>,
+        mut pixel: impl FnMut(usize, Intensity) -> ApaPixel,
+    ) -> PowerEstimate {
+        // light is linear in the level, so the wanted light is enough to
+        // know how far to dim and every pixel is only picked once
+        let lit: u64 = leds
+            .iter()
+            .flat_map(|led| led_intensity(led, master, curve).channels())
+            .map(u64::from)
+            .sum();
+        let lit_ma = self.channel_ma * (lit as f32 / u16::MAX as f32);
+        let idle = self.idle_ma * strip_len.max(leds.len()) as f32;
+        let wanted_ma = idle + lit_ma;
+
+        let limit = self.limit_ma as f32;
+        let scale = if self.limit_ma == 0 || wanted_ma <= limit {
+            1.
+        } else if limit <= idle {
+            0.
+        } else {
+            // only the lit part scales, the idle current is always there
+            ((limit - idle) / lit_ma * HEADROOM).min(1.)
+        };
+
+        out.clear();
+        out.extend(
+            leds.iter()
+                .enumerate()
+                .map(|(idx, led)| pixel(idx, led_intensity(led, master * scale, curve))),
+        );
+        let dark_ma = self.idle_ma * strip_len.saturating_sub(leds.len()) as f32;
+        PowerEstimate {
+            wanted_ma,
+            drawn_ma: self.frame_ma(out) + dark_ma,
+            scale,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::strip::Srgb8;
+    use alloc::vec;
+
+    fn white(n: usize) -> Vec<Led> {
+        vec![Led::new(Srgb8::new(255, 255, 255), 100); n]
+    }
+
+    #[test]
+    fn estimates_channels_and_idle() {
+        let model = PowerModel::default();
+        let mut out = Vec::new();
+        let est = model.limit(&white(1), 1, 1., &Curve::default(), &mut out);
+        assert_eq!(est.wanted_ma, 61.);
+        assert_eq!(est.scale, 1.);
+
+        let dark = vec![Led::default(); 10];
+        assert_eq!(
+            model
+                .limit(&dark, 10, 1., &Curve::default(), &mut out)
+                .wanted_ma,
+            10.
+        );
+    }
+
+    #[test]
+    fn dark_leds_past_the_frame_still_idle() {
+        let model = PowerModel::default();
+        let mut out = Vec::new();
+        let est = model.limit(&white(1), 300, 1., &Curve::default(), &mut out);
+        assert_eq!(est.wanted_ma, 360.);
+        assert_eq!(out.len(), 1);
+
+        // the idle current of the whole strip is taken off the budget first
+        let model = PowerModel {
+            limit_ma: 1_000,
+            ..model
+        };
+        let est = model.limit(&white(100), 512, 1., &Curve::default(), &mut out);
+        assert!(est.drawn_ma <= 1_000., "{est:?}");
+        assert!(est.drawn_ma > 950., "{est:?}");
+    }
+
+    #[test]
+    fn dims_white_down_to_the_budget() {
+        let model = PowerModel::default();
+        let mut out = Vec::new();
+        // 512 white LEDs would want over 31A
+        let est = model.limit(&white(512), 512, 1., &Curve::default(), &mut out);
+        assert!(est.wanted_ma > 31_000.);
+        assert!(est.drawn_ma <= 2_000., "{est:?}");
+        // but not much below it
+        assert!(est.drawn_ma > 1_900., "{est:?}");
+        assert!(est.scale < 0.1);
+        assert_eq!(out.len(), 512);
+        assert!(model.frame_ma(&out) <= 2_000.);
+    }
+
+    #[test]
+    fn no_limit_and_impossible_limits() {
+        let mut model = PowerModel {
+            limit_ma: 0,
+            ..PowerModel::default()
+        };
+        let mut out = Vec::new();
+        assert_eq!(
+            model
+                .limit(&white(512), 512, 1., &Curve::default(), &mut out)
+                .scale,
+            1.
+        );
+
+        // not even enough for the idle current, so everything goes dark
+        model.limit_ma = 100;
+        let est = model.limit(&white(512), 512, 1., &Curve::default(), &mut out);
+        assert_eq!(est.scale, 0.);
+        assert!(out.iter().all(|px| px.global == 0));
+
+        assert!(PowerModel::default().is_valid());
+        model.channel_ma = f32::NAN;
+        assert!(!model.is_valid());
+    }
+}
@@ -0,0 +1,114 @@
+//! Named snapshots of the whole [`State`].
+//!
+//! Recalling one fades over with a [`Transition`](crate::transition::Transition).
+//!
+//! Presets are stored as `{"<name>": <schema envelope>, ...}` so each one is
+//! migrated like `segments.json` when the format changes.
+
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{
+    schema::{self, SchemaError},
+    strip::State,
+};
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Presets {
+    presets: BTreeMap<String, State>,
+}
+
+impl Presets {
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.presets.keys().map(String::as_str)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&State> {
+        self.presets.get(name)
+    }
+
+    /// Save `state` as `name`, replacing an older preset with that name
+    pub fn save(&mut self, name: impl Into<String>, state: State) {
+        self.presets.insert(name.into(), state);
+    }
+
+    /// `false` if there was nothing called `name`
+    pub fn delete(&mut self, name: &str) -> bool {
+        self.presets.remove(name).is_some()
+    }
+
+    pub fn from_json(bytes: &[u8]) -> Result<Self, SchemaError> {
+        let stored: Map<String, Value> = serde_json::from_slice(bytes)?;
+        let presets = stored
+            .into_iter()
+            .map(|(name, state)| Ok((name, schema::from_value(state)?)))
+            .collect::<Result<_, SchemaError>>()?;
+        Ok(Self { presets })
+    }
+
+    pub fn to_json(&self) -> Vec<u8> {
+        let stored: Map<String, Value> = self
+            .presets
+            .iter()
+            .map(|(name, state)| (name.clone(), schema::to_value(state)))
+            .collect();
+        serde_json::to_vec(&stored).expect("presets always serialize")
+    }
+}
+
+/// Body of a `POST /presets`, `GET /presets` lists the names
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum PresetRequest {
+    /// Snapshot the current state
+    Save {
+        name: String,
+    },
+    /// Make the preset the current state, fading over `fade_ms`
+    Recall {
+        name: String,
+        fade_ms: u32,
+    },
+    Delete {
+        name: String,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::strip::{SegmentBuilder, Srgb8};
+    use uuid::Uuid;
+
+    fn look(n: u128, color: Srgb8, brightness: u8) -> State {
+        let seg = SegmentBuilder::new(Uuid::from_u128(n))
+            .length(4)
+            .colors(color, color)
+            .brightness(brightness)
+            .build()
+            .unwrap();
+        State::new([seg].into_iter())
+    }
+
+    #[test]
+    fn presets_roundtrip_and_migrate() {
+        let mut presets = Presets::default();
+        presets.save("red", look(1, Srgb8::new(255, 0, 0), 10));
+        presets.save("blue", look(2, Srgb8::new(0, 0, 255), 20));
+        assert_eq!(presets.names().collect::<Vec<_>>(), ["blue", "red"]);
+
+        let json = presets.to_json();
+        assert_eq!(Presets::from_json(&json).unwrap(), presets);
+
+        assert!(presets.delete("red"));
+        assert!(!presets.delete("red"));
+        assert!(presets.get("red").is_none());
+
+        // presets saved before the envelope existed
+        let old = br#"{"old":{"1d3bd22e-3680-40aa-87e9-da3bdee55c4e":{"uuid":"1d3bd22e-3680-40aa-87e9-da3bdee55c4e","length":1,"bgr":false,"colors":[{"red":255,"green":150,"blue":0},{"red":255,"green":10,"blue":220}],"chill_idx":0,"chill_fac":500}}}"#;
+        let presets = Presets::from_json(old).unwrap();
+        assert_eq!(presets.get("old").unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,628 @@
+//! Time-of-day automation: switch presets, dim, turn off or fade in like a
+//! sunrise alarm, at fixed times or relative to sunrise and sunset.
+//!
+//! The [`Schedule`] is stored with the [`State`](crate::strip::State). All
+//! evaluation takes the wall time as unix milliseconds, so it runs the same
+//! against SNTP on a board and against a
+//! [`ManualClock`](crate::clock::ManualClock) in tests.
+
+use alloc::{string::String, vec::Vec};
+use core::f64::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+use crate::strip::ValidationError;
+
+const MINUTE_MS: i64 = 60_000;
+const DAY_MS: i64 = 24 * 60 * MINUTE_MS;
+/// Firings further back than this don't matter anymore, every rule repeats
+/// at least weekly
+const LOOKBACK_DAYS: i64 = 8;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Schedule {
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    /// Local time minus UTC, e.g. `120` for CEST
+    #[serde(default)]
+    pub utc_offset_min: i16,
+    /// Needed for [`At::Sunrise`] and [`At::Sunset`], those never fire
+    /// without it
+    #[serde(default)]
+    pub location: Option<Location>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Location {
+    /// Degrees, north is positive
+    pub latitude: f32,
+    /// Degrees, east is positive
+    pub longitude: f32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rule {
+    #[serde(default)]
+    pub days: Days,
+    pub at: At,
+    pub action: Action,
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+/// Weekdays a rule fires on, bit 0 is Monday
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Days(pub u8);
+
+impl Days {
+    pub const EVERY_DAY: Days = Days(0x7f);
+    pub const WEEKDAYS: Days = Days(0x1f);
+    pub const WEEKEND: Days = Days(0x60);
+    pub const NAMES: [&'static str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+    /// `weekday` counts from Monday = 0
+    pub fn contains(self, weekday: u8) -> bool {
+        weekday < 7 && self.0 & (1 << weekday) != 0
+    }
+
+    pub fn toggled(self, weekday: u8) -> Self {
+        Days((self.0 ^ (1 << weekday)) & Self::EVERY_DAY.0)
+    }
+}
+
+impl Default for Days {
+    fn default() -> Self {
+        Self::EVERY_DAY
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum At {
+    /// Local time of day
+    Time {
+        hour: u8,
+        minute: u8,
+    },
+    /// Minutes after sunrise, negative for before
+    Sunrise {
+        offset_min: i16,
+    },
+    Sunset {
+        offset_min: i16,
+    },
+}
+
+impl At {
+    pub const KINDS: [&'static str; 3] = ["time", "sunrise", "sunset"];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            At::Time { .. } => "time",
+            At::Sunrise { .. } => "sunrise",
+            At::Sunset { .. } => "sunset",
+        }
+    }
+
+    /// `name` with default parameters, for editors
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "time" => Some(At::Time { hour: 7, minute: 0 }),
+            "sunrise" => Some(At::Sunrise { offset_min: 0 }),
+            "sunset" => Some(At::Sunset { offset_min: 0 }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Action {
+    /// Recall a preset, see [`crate::preset`]
+    Preset {
+        name: String,
+        fade_ms: u32,
+    },
+    /// Fade the master level to `level` percent, e.g. a night mode
+    Dim {
+        level: u8,
+        fade_ms: u32,
+    },
+    Off {
+        fade_ms: u32,
+    },
+    On {
+        fade_ms: u32,
+    },
+    /// Start dark and fade in over `fade_ms`, fully on at the rule's time
+    Alarm {
+        fade_ms: u32,
+    },
+}
+
+impl Action {
+    pub const KINDS: [&'static str; 5] = ["preset", "dim", "off", "on", "alarm"];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::Preset { .. } => "preset",
+            Action::Dim { .. } => "dim",
+            Action::Off { .. } => "off",
+            Action::On { .. } => "on",
+            Action::Alarm { .. } => "alarm",
+        }
+    }
+
+    /// `name` with default parameters, for editors
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "preset" => Some(Action::Preset {
+                name: String::new(),
+                fade_ms: 2_000,
+            }),
+            "dim" => Some(Action::Dim {
+                level: 20,
+                fade_ms: 60_000,
+            }),
+            "off" => Some(Action::Off { fade_ms: 60_000 }),
+            "on" => Some(Action::On { fade_ms: 2_000 }),
+            "alarm" => Some(Action::Alarm {
+                fade_ms: 30 * 60_000,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn fade_ms(&self) -> u32 {
+        match *self {
+            Action::Preset { fade_ms, .. }
+            | Action::Dim { fade_ms, .. }
+            | Action::Off { fade_ms }
+            | Action::On { fade_ms }
+            | Action::Alarm { fade_ms } => fade_ms,
+        }
+    }
+
+    pub fn set_fade_ms(&mut self, ms: u32) {
+        match self {
+            Action::Preset { fade_ms, .. }
+            | Action::Dim { fade_ms, .. }
+            | Action::Off { fade_ms }
+            | Action::On { fade_ms }
+            | Action::Alarm { fade_ms } => *fade_ms = ms,
+        }
+    }
+
+    /// Master level this ends at, `None` for actions that don't touch it
+    fn level(&self) -> Option<f32> {
+        match *self {
+            Action::Preset { .. } => None,
+            Action::Dim { level, .. } => Some(level as f32 / 100.),
+            Action::Off { .. } => Some(0.),
+            Action::On { .. } | Action::Alarm { .. } => Some(1.),
+        }
+    }
+}
+
+/// One fade of the master level
+#[derive(Clone, Copy, Debug)]
+struct Ramp {
+    start: i64,
+    from: Option<f32>,
+    to: f32,
+    fade_ms: i64,
+}
+
+impl Ramp {
+    fn level_at(&self, from: f32, at: i64) -> f32 {
+        let from = self.from.unwrap_or(from);
+        if self.fade_ms <= 0 {
+            return self.to;
+        }
+        let t = ((at - self.start) as f32 / self.fade_ms as f32).clamp(0., 1.);
+        from + (self.to - from) * t
+    }
+}
+
+impl Schedule {
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        for (idx, rule) in self.rules.iter().enumerate() {
+            let bad_time = matches!(rule.at, At::Time { hour, minute } if hour > 23 || minute > 59);
+            let bad_level = matches!(rule.action, Action::Dim { level, .. } if level > 100);
+            if bad_time || bad_level {
+                return Err(ValidationError::InvalidRule { idx });
+            }
+        }
+        Ok(())
+    }
+
+    /// Master level between `0` and `1` that the rules ask for at `unix_ms`.
+    /// Full on without any level rules.
+    pub fn level_at(&self, unix_ms: u64) -> f32 {
+        let now = unix_ms as i64;
+        // alarms that are due later can already be fading in
+        let ahead = self
+            .rules
+            .iter()
+            .filter_map(|rule| match rule.action {
+                Action::Alarm { fade_ms } => Some(fade_ms as i64),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+        let mut ramps: Vec<Ramp> = self
+            .firings(now - LOOKBACK_DAYS * DAY_MS, now + ahead)
+            .filter_map(|(at, action)| {
+                let to = action.level()?;
+                let fade_ms = action.fade_ms() as i64;
+                Some(match action {
+                    // fully on at `at`, so it started a fade earlier
+                    Action::Alarm { .. } => Ramp {
+                        start: at - fade_ms,
+                        from: Some(0.),
+                        to,
+                        fade_ms,
+                    },
+                    _ => Ramp {
+                        start: at,
+                        from: None,
+                        to,
+                        fade_ms,
+                    },
+                })
+            })
+            .filter(|ramp| ramp.start <= now)
+            .collect();
+        ramps.sort_by_key(|ramp| ramp.start);
+
+        let mut level = 1.;
+        for (i, ramp) in ramps.iter().enumerate() {
+            let until = ramps.get(i + 1).map_or(now, |next| next.start);
+            level = ramp.level_at(level, until);
+        }
+        level
+    }
+
+    /// The newest preset rule that fired in `(after, until]`
+    fn latest_preset(&self, after: i64, until: i64) -> Option<(i64, &str, u32)> {
+        self.firings(after.saturating_add(1), until)
+            .filter_map(|(at, action)| match action {
+                Action::Preset { name, fade_ms } => Some((at, name.as_str(), *fade_ms)),
+                _ => None,
+            })
+            .max_by_key(|(at, ..)| *at)
+    }
+
+    /// Every enabled rule firing in `[from, until]` as `(unix_ms, action)`,
+    /// not sorted
+    fn firings(&self, from: i64, until: i64) -> impl Iterator<Item = (i64, &Action)> + '_ {
+        let offset = self.utc_offset_min as i64 * MINUTE_MS;
+        // a day early and late, sun offsets can cross midnight
+        let first_day = (from + offset).div_euclid(DAY_MS) - 1;
+        let last_day = (until + offset).div_euclid(DAY_MS) + 1;
+
+        (first_day..=last_day)
+            .flat_map(move |day| self.rules.iter().map(move |rule| (day, rule)))
+            .filter(|(day, rule)| rule.enabled && rule.days.contains(weekday(*day)))
+            .filter_map(move |(day, rule)| Some((self.fires_at(rule.at, day)?, &rule.action)))
+            .filter(move |(at, _)| (from..=until).contains(at))
+    }
+
+    /// When `at` happens on local `day`, in unix ms
+    fn fires_at(&self, at: At, day: i64) -> Option<i64> {
+        let offset = self.utc_offset_min as i64 * MINUTE_MS;
+        match at {
+            At::Time { hour, minute } => {
+                let minutes = hour as i64 * 60 + minute as i64;
+                Some(day * DAY_MS + minutes * MINUTE_MS - offset)
+            }
+            At::Sunrise { offset_min } => {
+                let (rise, _) = sun_times(self.location?, day)?;
+                Some(rise + offset_min as i64 * MINUTE_MS)
+            }
+            At::Sunset { offset_min } => {
+                let (_, set) = sun_times(self.location?, day)?;
+                Some(set + offset_min as i64 * MINUTE_MS)
+            }
+        }
+    }
+}
+
+/// Monday is `0`, day 0 of the epoch was a Thursday
+fn weekday(day: i64) -> u8 {
+    (day + 3).rem_euclid(7) as u8
+}
+
+/// Sunrise and sunset in unix ms around `day` (days since the epoch), after
+/// the usual sunrise equation, good to a minute or two. `None` during polar
+/// day or night.
+pub fn sun_times(location: Location, day: i64) -> Option<(i64, i64)> {
+    let rad = |deg: f64| deg * PI / 180.;
+    let lat = rad(location.latitude as f64);
+    let lon = location.longitude as f64;
+
+    // days since 2000-01-01 12:00 UTC
+    let n = (day - 10_957) as f64;
+    let mean_noon = n - lon / 360.;
+    let anomaly = (357.5291 + 0.985_600_28 * mean_noon).rem_euclid(360.);
+    let m = rad(anomaly);
+    let center = 1.9148 * libm::sin(m) + 0.02 * libm::sin(2. * m) + 0.0003 * libm::sin(3. * m);
+    let ecliptic = rad((anomaly + center + 180. + 102.9372).rem_euclid(360.));
+    let transit = mean_noon + 0.0053 * libm::sin(m) - 0.0069 * libm::sin(2. * ecliptic);
+
+    let declination = libm::asin(libm::sin(ecliptic) * libm::sin(rad(23.4397)));
+    let cos_hour_angle = (libm::sin(rad(-0.833)) - libm::sin(lat) * libm::sin(declination))
+        / (libm::cos(lat) * libm::cos(declination));
+    if !(-1. ..=1.).contains(&cos_hour_angle) {
+        return None;
+    }
+    let half_day = libm::acos(cos_hour_angle) / (2. * PI);
+
+    let to_unix = |j2000: f64| libm::round((j2000 + 10_957.5) * DAY_MS as f64) as i64;
+    Some((to_unix(transit - half_day), to_unix(transit + half_day)))
+}
+
+/// Fires [`Action::Preset`] rules once each as wall time passes them.
+///
+/// The first poll only remembers the time, so a board that boots at noon
+/// doesn't replay the morning. If the clock jumps, only the newest missed
+/// preset fires.
+#[derive(Clone, Debug, Default)]
+pub struct Scheduler {
+    last: Option<u64>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The preset to recall and its fade, if one came due since the last poll
+    pub fn poll(&mut self, schedule: &Schedule, unix_ms: u64) -> Option<(String, u32)> {
+        let last = self.last.replace(unix_ms)?;
+        if unix_ms <= last {
+            return None;
+        }
+        let (_, name, fade_ms) = schedule.latest_preset(last as i64, unix_ms as i64)?;
+        Some((name.into(), fade_ms))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{ManualClock, WallClock};
+    use alloc::vec;
+
+    // Tuesday 2022-06-21 00:00 UTC
+    const SOLSTICE: u64 = 1_655_769_600_000;
+    const BERLIN: Location = Location {
+        latitude: 52.52,
+        longitude: 13.405,
+    };
+
+    fn hours(h: f64) -> u64 {
+        (h * 3_600_000.) as u64
+    }
+
+    fn rule(days: Days, at: At, action: Action) -> Rule {
+        Rule {
+            days,
+            at,
+            action,
+            enabled: true,
+        }
+    }
+
+    fn time(hour: u8, minute: u8) -> At {
+        At::Time { hour, minute }
+    }
+
+    #[test]
+    fn weekdays_count_from_monday() {
+        let day = SOLSTICE as i64 / DAY_MS;
+        assert_eq!(weekday(day), 1);
+        assert_eq!(weekday(0), 3);
+        assert_eq!(weekday(-1), 2);
+        assert!(Days::WEEKDAYS.contains(4) && !Days::WEEKDAYS.contains(5));
+        assert!(Days::WEEKEND.contains(6) && !Days::WEEKEND.contains(7));
+        assert_eq!(Days::EVERY_DAY.toggled(0).toggled(0), Days::EVERY_DAY);
+    }
+
+    #[test]
+    fn sun_times_match_the_almanac() {
+        // Berlin: sunrise 02:43 UTC, sunset 19:33 UTC
+        let day = SOLSTICE as i64 / DAY_MS;
+        let (rise, set) = sun_times(BERLIN, day).unwrap();
+        let minutes = |ms: i64| (ms - SOLSTICE as i64) / MINUTE_MS;
+        assert!(
+            (minutes(rise) - (2 * 60 + 43)).abs() <= 2,
+            "{}",
+            minutes(rise)
+        );
+        assert!(
+            (minutes(set) - (19 * 60 + 33)).abs() <= 2,
+            "{}",
+            minutes(set)
+        );
+
+        let tromso = Location {
+            latitude: 69.65,
+            longitude: 18.96,
+        };
+        assert_eq!(sun_times(tromso, day), None);
+    }
+
+    #[test]
+    fn night_mode_and_alarm() {
+        let schedule = Schedule {
+            rules: vec![
+                rule(
+                    Days::EVERY_DAY,
+                    time(22, 0),
+                    Action::Dim {
+                        level: 20,
+                        fade_ms: 0,
+                    },
+                ),
+                rule(Days::EVERY_DAY, time(23, 30), Action::Off { fade_ms: 0 }),
+                rule(
+                    Days::WEEKDAYS,
+                    time(7, 0),
+                    Action::Alarm {
+                        fade_ms: 30 * 60_000,
+                    },
+                ),
+                rule(Days::WEEKEND, time(9, 0), Action::On { fade_ms: 0 }),
+            ],
+            utc_offset_min: 120,
+            location: None,
+        };
+        schedule.validate().unwrap();
+        let clock = ManualClock::new(SOLSTICE);
+        // local time on the Tuesday
+        let at = |h: f64| {
+            clock.set(SOLSTICE + hours(h) - hours(2.));
+            schedule.level_at(clock.unix_ms().unwrap())
+        };
+
+        assert_eq!(at(6.), 0.);
+        assert_eq!(at(6.5), 0.);
+        assert!((at(6.75) - 0.5).abs() < 1e-3);
+        assert_eq!(at(7.), 1.);
+        assert_eq!(at(12.), 1.);
+        assert!((at(22.5) - 0.2).abs() < 1e-6);
+        assert_eq!(at(23.75), 0.);
+
+        // Saturday stays dark until 9
+        assert_eq!(at(4. * 24. + 8.), 0.);
+        assert_eq!(at(4. * 24. + 9.), 1.);
+
+        assert_eq!(Schedule::default().level_at(SOLSTICE), 1.);
+    }
+
+    #[test]
+    fn dims_fade_from_where_they_are() {
+        let schedule = Schedule {
+            rules: vec![
+                rule(
+                    Days::EVERY_DAY,
+                    time(20, 0),
+                    Action::Dim {
+                        level: 50,
+                        fade_ms: 60 * 60_000,
+                    },
+                ),
+                rule(Days::EVERY_DAY, time(6, 0), Action::On { fade_ms: 0 }),
+                // disabled rules don't count
+                Rule {
+                    enabled: false,
+                    ..rule(Days::EVERY_DAY, time(20, 30), Action::Off { fade_ms: 0 })
+                },
+            ],
+            ..Schedule::default()
+        };
+        assert!((schedule.level_at(SOLSTICE + hours(20.5)) - 0.75).abs() < 1e-3);
+        assert_eq!(schedule.level_at(SOLSTICE + hours(21.)), 0.5);
+    }
+
+    #[test]
+    fn sunset_rules_need_a_location() {
+        let mut schedule = Schedule {
+            rules: vec![
+                rule(
+                    Days::EVERY_DAY,
+                    At::Sunset { offset_min: 30 },
+                    Action::Off { fade_ms: 0 },
+                ),
+                rule(Days::EVERY_DAY, time(6, 0), Action::On { fade_ms: 0 }),
+            ],
+            ..Schedule::default()
+        };
+        assert_eq!(schedule.level_at(SOLSTICE + hours(23.)), 1.);
+
+        schedule.location = Some(BERLIN);
+        assert_eq!(schedule.level_at(SOLSTICE + hours(19.)), 1.);
+        assert_eq!(schedule.level_at(SOLSTICE + hours(20.25)), 0.);
+    }
+
+    #[test]
+    fn scheduler_fires_each_preset_once() {
+        let preset = |name: &str| Action::Preset {
+            name: name.into(),
+            fade_ms: 1000,
+        };
+        let schedule = Schedule {
+            rules: vec![
+                rule(Days::EVERY_DAY, time(8, 0), preset("morning")),
+                rule(Days::EVERY_DAY, time(18, 0), preset("evening")),
+            ],
+            ..Schedule::default()
+        };
+        let mut scheduler = Scheduler::new();
+
+        // booting after a rule doesn't replay it
+        assert_eq!(scheduler.poll(&schedule, SOLSTICE + hours(9.)), None);
+        assert_eq!(scheduler.poll(&schedule, SOLSTICE + hours(17.9)), None);
+        assert_eq!(
+            scheduler.poll(&schedule, SOLSTICE + hours(18.)),
+            Some(("evening".into(), 1000))
+        );
+        assert_eq!(scheduler.poll(&schedule, SOLSTICE + hours(18.1)), None);
+
+        // a jump over both only recalls the newest
+        assert_eq!(
+            scheduler.poll(&schedule, SOLSTICE + hours(42.)),
+            Some(("evening".into(), 1000))
+        );
+        // and going backwards fires nothing
+        assert_eq!(scheduler.poll(&schedule, SOLSTICE + hours(7.)), None);
+        assert_eq!(
+            scheduler.poll(&schedule, SOLSTICE + hours(8.)),
+            Some(("morning".into(), 1000))
+        );
+    }
+
+    #[test]
+    fn rejects_impossible_rules() {
+        let mut schedule = Schedule {
+            rules: vec![rule(
+                Days::EVERY_DAY,
+                time(24, 0),
+                Action::On { fade_ms: 0 },
+            )],
+            ..Schedule::default()
+        };
+        assert_eq!(
+            schedule.validate(),
+            Err(ValidationError::InvalidRule { idx: 0 })
+        );
+        schedule.rules[0] = rule(
+            Days::EVERY_DAY,
+            time(23, 59),
+            Action::Dim {
+                level: 101,
+                fade_ms: 0,
+            },
+        );
+        assert!(schedule.validate().is_err());
+    }
+
+    #[test]
+    fn stored_with_the_state() {
+        let json = br#"{"version":1,"segments":{},"schedule":{"rules":[{"days":31,"at":{"type":"sunrise","offset_min":-15},"action":{"type":"alarm","fade_ms":900000}}],"utc_offset_min":60,"location":{"latitude":52.5,"longitude":13.4}}}"#;
+        let state = crate::schema::from_json(json).unwrap();
+        let rule = &state.schedule().rules[0];
+        assert_eq!(rule.days, Days::WEEKDAYS);
+        assert!(rule.enabled);
+        assert_eq!(rule.at, At::Sunrise { offset_min: -15 });
+
+        let again = crate::schema::from_json(&crate::schema::to_json(&state)).unwrap();
+        assert_eq!(again, state);
+    }
+}
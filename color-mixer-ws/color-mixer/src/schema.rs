@@ -0,0 +1,256 @@
+//! Versioned on-disk and on-the-wire format for a [`State`].
+//!
+//! ```json
+//! {"version": 1, "segments": {"<uuid>": {...}, ...}}
+//! ```
+//!
+//! Version 0 is what `segments.json` and `/data` used to be: the bare segment
+//! map, with or without `brightness`. Loading runs every migration from the
+//! stored version up to [`VERSION`], saving always writes [`VERSION`].
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::strip::{State, ValidationError, DEFAULT_BRIGHTNESS};
+
+/// What [`to_json`] writes
+pub const VERSION: u64 = 1;
+
+type Migration = fn(Value) -> Result<Value, SchemaError>;
+
+/// `MIGRATIONS[n]` turns version `n` into version `n + 1`
+const MIGRATIONS: &[Migration] = &[v0_to_v1];
+
+#[derive(Debug)]
+pub enum SchemaError {
+    Json(serde_json::Error),
+    /// Written by a newer version than this one
+    UnsupportedVersion(u64),
+    /// Not any shape we ever wrote
+    Malformed(&'static str),
+    /// Loads, but [`State::validate`] rejects it
+    Invalid(ValidationError),
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchemaError::Json(e) => write!(f, "{e}"),
+            SchemaError::UnsupportedVersion(v) => {
+                write!(f, "state version {v} is newer than {VERSION}")
+            }
+            SchemaError::Malformed(what) => write!(f, "malformed state: {what}"),
+            SchemaError::Invalid(e) => write!(f, "invalid state: {e}"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for SchemaError {}
+
+impl From<ValidationError> for SchemaError {
+    fn from(e: ValidationError) -> Self {
+        SchemaError::Invalid(e)
+    }
+}
+
+impl From<serde_json::Error> for SchemaError {
+    fn from(e: serde_json::Error) -> Self {
+        SchemaError::Json(e)
+    }
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    version: u64,
+    #[serde(flatten)]
+    state: &'a State,
+}
+
+/// Load a state written by any version
+pub fn from_json(bytes: &[u8]) -> Result<State, SchemaError> {
+    from_value(serde_json::from_slice(bytes)?)
+}
+
+pub fn from_value(value: Value) -> Result<State, SchemaError> {
+    let (mut version, mut value) = match value {
+        Value::Object(mut obj) if obj.contains_key("version") => {
+            let version = obj
+                .remove("version")
+                .and_then(|v| v.as_u64())
+                .ok_or(SchemaError::Malformed("version is not a number"))?;
+            (version, Value::Object(obj))
+        }
+        // the bare segment map
+        value => (0, value),
+    };
+
+    if version > VERSION {
+        return Err(SchemaError::UnsupportedVersion(version));
+    }
+    while version < VERSION {
+        value = MIGRATIONS[version as usize](value)?;
+        version += 1;
+    }
+
+    Ok(serde_json::from_value(value)?)
+}
+
+/// [`from_json`] for a strip of `max_leds`, everything in the state kept.
+/// Anything that gets past this is safe to hand to the render loop.
+pub fn decode_state(bytes: &[u8], max_leds: usize) -> Result<State, SchemaError> {
+    let state = from_json(bytes)?;
+    state.validate(max_leds)?;
+    Ok(state)
+}
+
+/// Always the current [`VERSION`]
+pub fn to_json(state: &State) -> Vec<u8> {
+    serde_json::to_vec(&envelope(state)).expect("a State always serializes")
+}
+
+pub fn to_value(state: &State) -> Value {
+    serde_json::to_value(envelope(state)).expect("a State always serializes")
+}
+
+fn envelope(state: &State) -> Envelope<'_> {
+    Envelope {
+        version: VERSION,
+        state,
+    }
+}
+
+/// Bare map to envelope. Segments from before `brightness` get the default.
+fn v0_to_v1(value: Value) -> Result<Value, SchemaError> {
+    let Value::Object(mut segments) = value else {
+        return Err(SchemaError::Malformed("segments are not a map"));
+    };
+    for seg in segments.values_mut() {
+        let Value::Object(seg) = seg else {
+            return Err(SchemaError::Malformed("segment is not an object"));
+        };
+        seg.entry("brightness")
+            .or_insert_with(|| Value::from(DEFAULT_BRIGHTNESS));
+    }
+
+    let mut v1 = Map::new();
+    v1.insert("segments".into(), Value::Object(segments));
+    Ok(Value::Object(v1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schedule::{Action, At, Days, Rule, Schedule};
+    use crate::strip::{Period, SegmentKind, Srgb8, FULL_BRIGHTNESS};
+    use crate::transition::{Settings, Style};
+    use alloc::vec;
+
+    // the samples `util/web_server.py` has been serving over time
+    const V0_NO_BRIGHTNESS: &str = r#"{"2e2765f4-7fb7-4a8a-b61f-6dc874db15e9":{"uuid":"2e2765f4-7fb7-4a8a-b61f-6dc874db15e9","length":1,"bgr":false,"colors":[{"red":255,"green":150,"blue":0},{"red":255,"green":10,"blue":120}],"chill_idx":0,"chill_fac":100},"ae98126f-915e-470d-93a0-4b40a853a0c8":{"uuid":"ae98126f-915e-470d-93a0-4b40a853a0c8","length":1,"bgr":false,"colors":[{"red":166,"green":0,"blue":255},{"red":2,"green":192,"blue":192}],"chill_idx":1,"chill_fac":100},"bdc6cf10-c223-4c9e-9b94-88495d81617a":{"uuid":"bdc6cf10-c223-4c9e-9b94-88495d81617a","length":1,"bgr":false,"colors":[{"red":20,"green":200,"blue":141},{"red":200,"green":176,"blue":20}],"chill_idx":2,"chill_fac":100},"cba45b51-fd9a-48f4-95b3-070099050887":{"uuid":"cba45b51-fd9a-48f4-95b3-070099050887","length":1,"bgr":false,"colors":[{"red":200,"green":20,"blue":30},{"red":200,"green":200,"blue":10}],"chill_idx":3,"chill_fac":100}}"#;
+    const V0_EMPTY: &str = "{}";
+    const V0: &str = r#"{"1d3bd22e-3680-40aa-87e9-da3bdee55c4e":{"uuid":"1d3bd22e-3680-40aa-87e9-da3bdee55c4e","length":1,"bgr":false,"colors":[{"red":255,"green":150,"blue":0},{"red":255,"green":10,"blue":220}],"chill_idx":0,"chill_fac":500,"brightness":10}}"#;
+
+    #[test]
+    fn loads_every_historical_sample() {
+        let state = from_json(V0_NO_BRIGHTNESS.as_bytes()).unwrap();
+        assert_eq!(state.len(), 4);
+        let seg = &state["cba45b51-fd9a-48f4-95b3-070099050887"];
+        assert_eq!(seg.brightness(), DEFAULT_BRIGHTNESS);
+        assert_eq!(seg.chill_idx(), 3);
+        assert_eq!(*seg.color_1(), Srgb8::new(200, 20, 30));
+        assert_eq!(seg.kind(), SegmentKind::Mix);
+        assert_eq!(seg.period(), Period::Chill);
+        assert_eq!(state.master_brightness(), FULL_BRIGHTNESS);
+
+        assert!(from_json(V0_EMPTY.as_bytes()).unwrap().is_empty());
+
+        let state = from_json(V0.as_bytes()).unwrap();
+        assert_eq!(state.len(), 1);
+        assert_eq!(state[0].chill_fac(), 500);
+    }
+
+    #[test]
+    fn roundtrips_current_version() {
+        let state = from_json(V0_NO_BRIGHTNESS.as_bytes()).unwrap();
+        let json = to_json(&state);
+        let value: Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(value["version"], VERSION);
+        assert_eq!(from_json(&json).unwrap(), state);
+    }
+
+    #[test]
+    fn decoding_keeps_the_settings() {
+        let mut state = from_json(V0.as_bytes()).unwrap();
+        state.set_master_brightness(35);
+        let night = Rule {
+            days: Days::WEEKDAYS,
+            at: At::Time {
+                hour: 22,
+                minute: 30,
+            },
+            action: Action::Dim {
+                level: 20,
+                fade_ms: 60_000,
+            },
+            enabled: true,
+        };
+        state.set_schedule(Schedule {
+            rules: vec![night.clone()],
+            utc_offset_min: 120,
+            location: None,
+        });
+        let wipe = Settings {
+            style: Style::Wipe,
+            ms: 1500,
+        };
+        state.set_transition(wipe);
+        let decoded = decode_state(&to_json(&state), 10).unwrap();
+        assert_eq!(decoded.master_brightness(), 35);
+        assert_eq!(decoded.schedule().rules, [night]);
+        assert_eq!(decoded.schedule().utc_offset_min, 120);
+        assert_eq!(decoded.transition(), wipe);
+        assert_eq!(decoded, state);
+
+        assert!(matches!(
+            decode_state(&to_json(&state), 0),
+            Err(SchemaError::Invalid(ValidationError::TooManyLeds { .. }))
+        ));
+        let mut broken = state.clone();
+        broken.set_schedule(Schedule {
+            rules: vec![Rule {
+                at: At::Time {
+                    hour: 25,
+                    minute: 0,
+                },
+                ..broken.schedule().rules[0].clone()
+            }],
+            ..Schedule::default()
+        });
+        assert!(matches!(
+            decode_state(&to_json(&broken), 10),
+            Err(SchemaError::Invalid(ValidationError::InvalidRule {
+                idx: 0
+            }))
+        ));
+    }
+
+    #[test]
+    fn rejects_newer_and_garbage() {
+        assert!(matches!(
+            from_json(br#"{"version":99,"segments":{}}"#),
+            Err(SchemaError::UnsupportedVersion(99))
+        ));
+        assert!(matches!(
+            from_json(b"[1,2]"),
+            Err(SchemaError::Malformed(_))
+        ));
+        assert!(matches!(
+            from_json(br#"{"version":1,"segments":{"a":{"uuid":"nope"}}}"#),
+            Err(SchemaError::Json(_))
+        ));
+    }
+}
@@ -1,4 +1,10 @@
-use std::{
+use alloc::{
+    collections::VecDeque,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use core::{
     fmt::Debug,
     hash::Hash,
     ops::{Deref, DerefMut},
@@ -10,13 +16,106 @@ use palette::{IntoColor, Luv, Mix, Srgb};
 pub type Srgb8 = palette::rgb::Rgb<palette::encoding::Srgb, u8>;
 
 use derive_more::{Deref, DerefMut, From, Into};
+use smart_leds::RGB8;
+
+/// `simple_easing::sine_in_out`, minus the `std` dependency
+fn sine_in_out(t: f32) -> f32 {
+    -(libm::cosf(core::f32::consts::PI * t) - 1.0) / 2.0
+}
 
 pub const CHILLED: &[u32] = &[
     7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
 ];
+
+/// For new segments, and old data from before there was brightness
+pub const DEFAULT_BRIGHTNESS: u8 = 10;
+
+/// Segment and master brightness are percentages, this is all the way up
+pub const FULL_BRIGHTNESS: u8 = 100;
+
+/// Why a [`Segment`] or [`State`] was rejected
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ValidationError {
+    /// `chill_idx` doesn't point into [`CHILLED`]
+    ChillIdxOutOfRange {
+        idx: usize,
+        len: usize,
+    },
+    /// `chill_fac` of 0 would make the period 0
+    ZeroChillFac,
+    /// A [`Period`] of 0ms, 0 beats or 0 bpm
+    ZeroPeriod,
+    ZeroLength,
+    /// All segments together are longer than the strip
+    TooManyLeds {
+        total: usize,
+        max: usize,
+    },
+    /// Schedule rule `idx` has a time or level that can't happen
+    InvalidRule {
+        idx: usize,
+    },
+    /// A [`SegmentKind`] parameter past what it can be
+    ParamOutOfRange {
+        name: &'static str,
+        value: u8,
+        max: u8,
+    },
+}
+
+impl core::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ValidationError::ChillIdxOutOfRange { idx, len } => {
+                write!(f, "chill_idx {idx} out of range, must be below {len}")
+            }
+            ValidationError::ZeroChillFac => write!(f, "chill_fac must not be 0"),
+            ValidationError::ZeroPeriod => write!(f, "period must not be 0"),
+            ValidationError::ZeroLength => write!(f, "length must not be 0"),
+            ValidationError::TooManyLeds { total, max } => {
+                write!(f, "{total} leds in total, at most {max} fit")
+            }
+            ValidationError::InvalidRule { idx } => write!(f, "schedule rule {idx} is invalid"),
+            ValidationError::ParamOutOfRange { name, value, max } => {
+                write!(f, "{name} {value} out of range, at most {max}")
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ValidationError {}
+
 #[derive(Clone, PartialEq, From, Into, Deref, DerefMut, Debug, Serialize, Deserialize)]
+#[serde(from = "Rgb8Repr", into = "Rgb8Repr")]
 pub struct Wrap(pub Srgb8);
 
+/// Serialized form of [`Wrap`]. Same shape as palette's own `serializing`
+/// output, which needs `std`.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+struct Rgb8Repr {
+    red: u8,
+    green: u8,
+    blue: u8,
+}
+
+impl From<Rgb8Repr> for Wrap {
+    fn from(c: Rgb8Repr) -> Self {
+        Wrap(Srgb8::new(c.red, c.green, c.blue))
+    }
+}
+
+impl From<Wrap> for Rgb8Repr {
+    fn from(c: Wrap) -> Self {
+        Rgb8Repr {
+            red: c.red,
+            green: c.green,
+            blue: c.blue,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
 impl Default for Segment {
     fn default() -> Self {
         Self::new(
@@ -26,20 +125,130 @@ impl Default for Segment {
             Srgb8::new(255, 10, 220),
             0,
             80,
-            10,
+            DEFAULT_BRIGHTNESS,
         )
     }
 }
 
 impl Hash for Wrap {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+    fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
         self.red.hash(state);
         self.green.hash(state);
         self.blue.hash(state);
     }
 }
 
+/// What a segment renders. `Mix` is the classic two-color fade, the others
+/// hand the segment's LEDs to one of the `effects`.
+///
+/// Percent-ish parameters are stored as integers so `Segment` stays `Hash`.
+#[derive(PartialEq, Eq, Clone, Copy, Hash, Debug, Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SegmentKind {
+    #[default]
+    Mix,
+    Rainbow {
+        /// HSL lightness in percent
+        lightness: u8,
+    },
+    OklabRainbow {
+        /// Oklch lightness in percent
+        lightness: u8,
+        /// Oklch chroma in hundredths
+        chroma: u8,
+    },
+    Chaser,
+    Progress,
+}
+
+impl SegmentKind {
+    pub const MAX_LIGHTNESS: u8 = 100;
+    /// Oklch chroma doesn't go much further inside sRGB
+    pub const MAX_CHROMA: u8 = 37;
+
+    pub const ALL: &'static [SegmentKind] = &[
+        SegmentKind::Mix,
+        SegmentKind::Rainbow { lightness: 50 },
+        SegmentKind::OklabRainbow {
+            lightness: 80,
+            chroma: 15,
+        },
+        SegmentKind::Chaser,
+        SegmentKind::Progress,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SegmentKind::Mix => "mix",
+            SegmentKind::Rainbow { .. } => "rainbow",
+            SegmentKind::OklabRainbow { .. } => "oklab_rainbow",
+            SegmentKind::Chaser => "chaser",
+            SegmentKind::Progress => "progress",
+        }
+    }
+
+    /// The kind called `name`, with default parameters
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().find(|kind| kind.name() == name).copied()
+    }
+
+    /// Parameters within range
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        let check = |name, value, max| {
+            if value > max {
+                return Err(ValidationError::ParamOutOfRange { name, value, max });
+            }
+            Ok(())
+        };
+        match *self {
+            SegmentKind::Rainbow { lightness } => {
+                check("lightness", lightness, Self::MAX_LIGHTNESS)
+            }
+            SegmentKind::OklabRainbow { lightness, chroma } => {
+                check("lightness", lightness, Self::MAX_LIGHTNESS)?;
+                check("chroma", chroma, Self::MAX_CHROMA)
+            }
+            SegmentKind::Mix | SegmentKind::Chaser | SegmentKind::Progress => Ok(()),
+        }
+    }
+}
+
+/// How long one cycle of a segment takes
+#[derive(PartialEq, Eq, Clone, Copy, Hash, Debug, Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Period {
+    /// `chill_fac * CHILLED[chill_idx]`. Primes, so segments never line up.
+    #[default]
+    Chill,
+    /// Exactly `ms` milliseconds
+    Millis { ms: u32 },
+    /// `beats` beats at `bpm`, for syncing to music
+    Beats { beats: u16, bpm: u16 },
+}
+
+impl Period {
+    pub const MODES: &'static [Period] = &[
+        Period::Chill,
+        Period::Millis { ms: 4000 },
+        Period::Beats { beats: 4, bpm: 120 },
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Period::Chill => "chill",
+            Period::Millis { .. } => "millis",
+            Period::Beats { .. } => "beats",
+        }
+    }
+
+    /// The mode called `name`, with default parameters
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::MODES.iter().find(|p| p.name() == name).copied()
+    }
+}
+
 #[derive(PartialEq, Clone, Hash, Debug, Serialize, Deserialize)]
+#[serde(try_from = "SegmentRepr")]
 pub struct Segment {
     uuid: Uuid,
     length: usize,
@@ -48,9 +257,151 @@ pub struct Segment {
     chill_idx: usize,
     chill_fac: u32,
     brightness: u8,
+    kind: SegmentKind,
+    period: Period,
+}
+
+/// Unchecked [`Segment`], everything coming in over the wire goes through
+/// [`Segment::validate`] on the way
+#[derive(Deserialize)]
+struct SegmentRepr {
+    uuid: Uuid,
+    length: usize,
+    bgr: bool,
+    colors: [Wrap; 2],
+    chill_idx: usize,
+    chill_fac: u32,
+    #[serde(default = "default_brightness")]
+    brightness: u8,
+    #[serde(default)]
+    kind: SegmentKind,
+    #[serde(default)]
+    period: Period,
+}
+
+fn default_brightness() -> u8 {
+    DEFAULT_BRIGHTNESS
+}
+
+fn full_brightness() -> u8 {
+    FULL_BRIGHTNESS
+}
+
+impl TryFrom<SegmentRepr> for Segment {
+    type Error = ValidationError;
+
+    fn try_from(repr: SegmentRepr) -> Result<Self, Self::Error> {
+        let seg = Segment {
+            uuid: repr.uuid,
+            length: repr.length,
+            bgr: repr.bgr,
+            colors: repr.colors,
+            chill_idx: repr.chill_idx,
+            chill_fac: repr.chill_fac,
+            brightness: repr.brightness,
+            kind: repr.kind,
+            period: repr.period,
+        };
+        seg.validate()?;
+        Ok(seg)
+    }
+}
+
+/// Builds a [`Segment`], checking it on [`build`](SegmentBuilder::build)
+#[derive(Clone, Debug)]
+pub struct SegmentBuilder {
+    seg: Segment,
+}
+
+impl SegmentBuilder {
+    pub fn new(uuid: Uuid) -> Self {
+        Self {
+            seg: Segment::new_with_uuid(
+                uuid,
+                1,
+                false,
+                Srgb8::new(255, 150, 0),
+                Srgb8::new(255, 10, 220),
+                0,
+                80,
+                DEFAULT_BRIGHTNESS,
+            ),
+        }
+    }
+
+    pub fn length(mut self, length: usize) -> Self {
+        self.seg.length = length;
+        self
+    }
+
+    pub fn bgr(mut self, bgr: bool) -> Self {
+        self.seg.bgr = bgr;
+        self
+    }
+
+    pub fn colors(mut self, c1: Srgb8, c2: Srgb8) -> Self {
+        self.seg.colors = [Wrap(c1), Wrap(c2)];
+        self
+    }
+
+    pub fn chill(mut self, chill_idx: usize, chill_fac: u32) -> Self {
+        self.seg.chill_idx = chill_idx;
+        self.seg.chill_fac = chill_fac;
+        self
+    }
+
+    pub fn brightness(mut self, brightness: u8) -> Self {
+        self.seg.brightness = brightness;
+        self
+    }
+
+    pub fn kind(mut self, kind: SegmentKind) -> Self {
+        self.seg.kind = kind;
+        self
+    }
+
+    pub fn period(mut self, period: Period) -> Self {
+        self.seg.period = period;
+        self
+    }
+
+    pub fn build(self) -> Result<Segment, ValidationError> {
+        self.seg.validate()?;
+        Ok(self.seg)
+    }
 }
 
 impl Segment {
+    #[cfg(feature = "std")]
+    pub fn builder() -> SegmentBuilder {
+        SegmentBuilder::new(Uuid::new_v4())
+    }
+
+    /// Anything that would make rendering misbehave
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        if self.chill_idx >= CHILLED.len() {
+            return Err(ValidationError::ChillIdxOutOfRange {
+                idx: self.chill_idx,
+                len: CHILLED.len(),
+            });
+        }
+        if self.chill_fac == 0 {
+            return Err(ValidationError::ZeroChillFac);
+        }
+        if self.length == 0 {
+            return Err(ValidationError::ZeroLength);
+        }
+        match self.period {
+            Period::Chill => {}
+            Period::Millis { ms: 0 }
+            | Period::Beats { beats: 0, .. }
+            | Period::Beats { bpm: 0, .. } => return Err(ValidationError::ZeroPeriod),
+            Period::Millis { .. } | Period::Beats { .. } => {}
+        }
+        self.kind.validate()
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub fn new_with_uuid(
         uuid: Uuid,
         length: usize,
@@ -69,9 +420,13 @@ impl Segment {
             chill_idx,
             chill_fac,
             brightness,
+            kind: SegmentKind::Mix,
+            period: Period::Chill,
         }
     }
 
+    #[cfg(feature = "std")]
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         length: usize,
         bgr: bool,
@@ -93,30 +448,103 @@ impl Segment {
         )
     }
 
-    pub fn mix(&self, mut t: f32) -> Srgb8 {
+    pub fn mix(&self, t: f32) -> Srgb8 {
+        self.mix_exact(t).into_format()
+    }
+
+    /// [`mix`](Self::mix) before rounding to 8 bit
+    pub fn mix_exact(&self, mut t: f32) -> Srgb {
         let mut c1: Luv = self.color_1().into_format().into_color();
         let mut c2: Luv = self.color_2().into_format().into_color();
         if t >= 0.5 {
             (c1, c2) = (c2, c1);
             t -= 0.5;
         }
-        t = simple_easing::sine_in_out(t * 2.0);
+        t = sine_in_out(t * 2.0);
 
         let res = c1.mix(c2, t);
         // TODO: bgr
-        let res: Srgb = res.into_color();
-        res.into_format()
+        res.into_color()
     }
 
+    /// Cycle length. Never 0 and never panics, even for a segment that
+    /// wouldn't [`validate`](Self::validate).
     pub fn chill_ms(&self) -> u32 {
-        self.chill_fac * CHILLED[self.chill_idx]
+        let prime = CHILLED
+            .get(self.chill_idx)
+            .or(CHILLED.last())
+            .copied()
+            .unwrap_or(1);
+        self.chill_fac.max(1).saturating_mul(prime)
+    }
+
+    /// The cycle as `at_millis * scale % len`, so beats at a BPM stay exact
+    fn cycle(&self) -> (u64, u64) {
+        match self.period {
+            Period::Chill => (1, self.chill_ms() as u64),
+            Period::Millis { ms } => (1, ms.max(1) as u64),
+            Period::Beats { beats, bpm } => (bpm.max(1) as u64, beats.max(1) as u64 * 60_000),
+        }
+    }
+
+    /// Length of one cycle in milliseconds, whatever the [`Period`]
+    pub fn period_ms(&self) -> f32 {
+        let (scale, len) = self.cycle();
+        len as f32 / scale as f32
+    }
+
+    /// Position within the current cycle, in `[0, 1)`
+    ///
+    /// The modulo happens in integers before going to `f32`, so there is no
+    /// wrap point and no precision loss for large `at_millis`.
+    pub fn phase_at(&self, at_millis: u64) -> f32 {
+        let (scale, len) = self.cycle();
+        let wrapped = (at_millis as u128 * scale as u128 % len as u128) as f32;
+        wrapped / len as f32
+    }
+
+    pub fn color_at(&self, at_millis: u64) -> Srgb8 {
+        self.mix(self.phase_at(at_millis))
     }
 
-    pub fn color_at(&self, at_millis: u32) -> Srgb8 {
-        let wrapped = (at_millis % self.chill_ms()) as f32;
-        let chill = self.chill_ms() as f32;
-        let t = wrapped / chill;
-        self.mix(t)
+    /// Render the segment at `at_millis` into `leds`, one color per LED.
+    ///
+    /// `leds` is usually `length()` long but any length works.
+    pub fn render(&self, at_millis: u64, leds: &mut [Srgb8]) {
+        let t = self.phase_at(at_millis);
+
+        let mut data = vec![RGB8::default(); leds.len()];
+        match self.kind {
+            SegmentKind::Mix => {
+                leds.fill(self.mix(t));
+                return;
+            }
+            SegmentKind::Rainbow { lightness } => {
+                let time = (t * 256.) as u8;
+                effects::rainborrow_slice(time, lightness as f32 / 100., &mut data);
+            }
+            SegmentKind::OklabRainbow { lightness, chroma } => {
+                let time = (t * 360.) as u16;
+                effects::rainborrok_slice(
+                    time,
+                    lightness as f32 / 100.,
+                    chroma as f32 / 100.,
+                    &mut data,
+                );
+            }
+            SegmentKind::Chaser => {
+                let time = (t * leds.len() as f32) as u16;
+                effects::chaser_slice(time, &mut data);
+            }
+            SegmentKind::Progress => {
+                let time = (t * u16::MAX as f32) as u16;
+                effects::progress_slice(time, &mut data);
+            }
+        }
+
+        for (led, rgb) in leds.iter_mut().zip(data) {
+            *led = Srgb8::new(rgb.r, rgb.g, rgb.b);
+        }
     }
 
     pub fn color_1(&self) -> &Srgb8 {
@@ -163,6 +591,14 @@ impl Segment {
         self.length = length;
     }
 
+    pub fn bgr(&self) -> bool {
+        self.bgr
+    }
+
+    pub fn set_bgr(&mut self, bgr: bool) {
+        self.bgr = bgr;
+    }
+
     pub fn brightness(&self) -> u8 {
         self.brightness
     }
@@ -170,91 +606,68 @@ impl Segment {
     pub fn set_brightness(&mut self, brightness: u8) {
         self.brightness = brightness;
     }
-}
 
-#[cfg(feature = "wasm")]
-mod imp {
-    use chrono::{DateTime, Utc};
-    pub struct Control {
-        start: DateTime<Utc>,
-        now: DateTime<Utc>,
+    pub fn kind(&self) -> SegmentKind {
+        self.kind
     }
-    impl Control {
-        pub fn new() -> Self {
-            let now = Utc::now();
-            Self { start: now, now }
-        }
-
-        pub fn tick(&mut self) -> u32 {
-            self.now = Utc::now();
-            self.ms_since_start()
-        }
 
-        pub fn set_now(&mut self, now: DateTime<Utc>) {
-            self.now = now;
-        }
-
-        pub fn ms_since_start(&self) -> u32 {
-            let dt = self
-                .now
-                .signed_duration_since(self.start)
-                .to_std()
-                .unwrap()
-                .as_millis();
-            dt as u32 // :&
-        }
+    pub fn set_kind(&mut self, kind: SegmentKind) {
+        self.kind = kind;
     }
-}
 
-#[cfg(not(feature = "wasm"))]
-mod imp {
-    pub struct Control {
-        // start: DateTime<Utc>,
-        // now: DateTime<Utc>,
-        start: u32,
-        now: u32,
+    pub fn period(&self) -> Period {
+        self.period
     }
-    impl Control {
-        pub fn new() -> Self {
-            // let now = Utc::now();
-            let now = 0;
-            Self { start: now, now }
-        }
 
-        pub fn set_now(&mut self, now: u32) {
-            self.now = now;
-        }
+    pub fn set_period(&mut self, period: Period) {
+        self.period = period;
     }
 }
 
-pub use imp::Control;
+pub use crate::clock::Control;
+use crate::patch::{Field, Op};
+use crate::schedule::Schedule;
+use crate::transition;
 
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-#[cfg(feature = "wasm")]
-type MAP = IndexMap<String, Segment>;
-
-#[cfg(feature = "esp")]
+#[cfg(feature = "std")]
+#[allow(clippy::upper_case_acronyms)]
 type MAP = IndexMap<String, Segment>;
 // type MAP = IndexMap<String, Segment, std::hash::BuildHasherDefault<hashers::fx_hash::FxHasher>>;
 
+// no `RandomState` without std
+#[cfg(not(feature = "std"))]
+#[allow(clippy::upper_case_acronyms)]
+type MAP = IndexMap<String, Segment, core::hash::BuildHasherDefault<fnv::FnvHasher>>;
+
 #[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
 pub struct State {
     segments: MAP,
+    /// Scales every segment's brightness, so dimming the whole strip keeps
+    /// the balance between segments
+    #[serde(default = "full_brightness")]
+    master_brightness: u8,
+    #[serde(default)]
+    schedule: Schedule,
+    /// How changes come in
+    #[serde(default)]
+    transition: transition::Settings,
 }
 
 impl State {
     pub fn new(segments: impl Iterator<Item = Segment>) -> Self {
         Self {
             segments: segments.map(|seg| (seg.uuid().to_string(), seg)).collect(),
+            master_brightness: FULL_BRIGHTNESS,
+            schedule: Schedule::default(),
+            transition: transition::Settings::default(),
         }
     }
 
     pub fn new_empty() -> Self {
-        Self {
-            segments: MAP::new(),
-        }
+        Self::from(MAP::default())
     }
 
     pub fn insert(&mut self, seg: Segment) -> Option<Segment> {
@@ -268,6 +681,155 @@ impl State {
     pub fn segments(&self) -> &MAP {
         &self.segments
     }
+
+    pub fn into_segments(self) -> MAP {
+        self.segments
+    }
+
+    pub fn master_brightness(&self) -> u8 {
+        self.master_brightness
+    }
+
+    /// Capped at [`FULL_BRIGHTNESS`]
+    pub fn set_master_brightness(&mut self, brightness: u8) {
+        self.master_brightness = brightness.min(FULL_BRIGHTNESS);
+    }
+
+    /// [`State::master_brightness`] as a factor between `0` and `1`
+    pub fn master_level(&self) -> f32 {
+        self.master_brightness.min(FULL_BRIGHTNESS) as f32 / FULL_BRIGHTNESS as f32
+    }
+
+    pub fn schedule(&self) -> &Schedule {
+        &self.schedule
+    }
+
+    pub fn set_schedule(&mut self, schedule: Schedule) {
+        self.schedule = schedule;
+    }
+
+    pub fn transition(&self) -> transition::Settings {
+        self.transition
+    }
+
+    pub fn set_transition(&mut self, transition: transition::Settings) {
+        self.transition = transition;
+    }
+
+    /// Every segment is valid, together they fit on `max_leds` and the
+    /// schedule makes sense
+    pub fn validate(&self, max_leds: usize) -> Result<(), ValidationError> {
+        validate_segments(self.segments.values(), max_leds)?;
+        self.schedule.validate()
+    }
+
+    /// Sum of all segment lengths
+    pub fn total_length(&self) -> usize {
+        self.segments.values().map(Segment::length).sum()
+    }
+
+    /// Render the whole strip, segments back to back. LEDs past the last
+    /// segment are turned off, segments past the end of `leds` are cut off.
+    pub fn render(&self, at_millis: u64, leds: &mut [Led]) {
+        let mut colors = Vec::new();
+        let mut rest = &mut *leds;
+        for seg in self.segments.values() {
+            let len = seg.length().min(rest.len());
+            let (these, next) = rest.split_at_mut(len);
+            rest = next;
+            if seg.kind() == SegmentKind::Mix {
+                // keeps what's between 8 bit steps, for slow fades
+                let exact = seg.mix_exact(seg.phase_at(at_millis));
+                these.fill(Led::from_exact(exact, seg.brightness()));
+                continue;
+            }
+            colors.clear();
+            colors.resize(len, Srgb8::default());
+            seg.render(at_millis, &mut colors);
+            for (led, color) in these.iter_mut().zip(&colors) {
+                *led = Led::new(*color, seg.brightness());
+            }
+        }
+        rest.fill(Led::default());
+    }
+}
+
+/// One rendered LED: a color and the brightness of the segment it's in
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct Led {
+    pub color: Srgb8,
+    pub brightness: u8,
+    /// What rounding `color` to 8 bit took off, per channel in 1/256 of a
+    /// step. Set by fades, so the output can show them in between steps.
+    pub fine: [i8; 3],
+}
+
+impl Led {
+    pub const fn new(color: Srgb8, brightness: u8) -> Self {
+        Self {
+            color,
+            brightness,
+            fine: [0; 3],
+        }
+    }
+
+    /// `color` rounded to 8 bit, with the rest in `fine`
+    pub fn from_exact(color: Srgb, brightness: u8) -> Self {
+        let rounded: Srgb8 = color.into_format();
+        let fine = |exact: f32, rounded: u8| {
+            libm::roundf((exact * 255. - rounded as f32) * 256.).clamp(-128., 127.) as i8
+        };
+        Self {
+            color: rounded,
+            brightness,
+            fine: [
+                fine(color.red, rounded.red),
+                fine(color.green, rounded.green),
+                fine(color.blue, rounded.blue),
+            ],
+        }
+    }
+
+    /// The color `from_exact` was given, as far as `fine` goes
+    pub fn exact(&self) -> Srgb {
+        let channel = |c: u8, fine: i8| ((c as f32 + fine as f32 / 256.) / 255.).clamp(0., 1.);
+        Srgb::new(
+            channel(self.color.red, self.fine[0]),
+            channel(self.color.green, self.fine[1]),
+            channel(self.color.blue, self.fine[2]),
+        )
+    }
+}
+
+/// [`State::validate`] for segments that aren't in a `State`, like the
+/// firmware's `/data` payload
+pub fn validate_segments<'a>(
+    segments: impl IntoIterator<Item = &'a Segment>,
+    max_leds: usize,
+) -> Result<(), ValidationError> {
+    let mut total = 0usize;
+    for seg in segments {
+        seg.validate()?;
+        total = total.saturating_add(seg.length());
+    }
+    if total > max_leds {
+        return Err(ValidationError::TooManyLeds {
+            total,
+            max: max_leds,
+        });
+    }
+    Ok(())
+}
+
+impl From<MAP> for State {
+    fn from(segments: MAP) -> Self {
+        Self {
+            segments,
+            master_brightness: FULL_BRIGHTNESS,
+            schedule: Schedule::default(),
+            transition: transition::Settings::default(),
+        }
+    }
 }
 
 impl Deref for State {
@@ -283,3 +845,385 @@ impl DerefMut for State {
         &mut self.segments
     }
 }
+
+/// Which fields of which segments an edit touched, `None` for anything but
+/// plain field changes
+type EditKey = Option<Vec<(Uuid, core::mem::Discriminant<Field>)>>;
+
+fn edit_key(before: &State, after: &State) -> EditKey {
+    crate::patch::diff(before, after)
+        .into_iter()
+        .map(|op| match op {
+            Op::Set { id, field } => Some((id, core::mem::discriminant(&field))),
+            _ => None,
+        })
+        .collect()
+}
+
+struct Step {
+    state: State,
+    at: u64,
+    key: EditKey,
+}
+
+/// Bounded undo/redo for a [`State`].
+///
+/// Edits that change the same fields as the previous one within `group_ms`
+/// are merged, so dragging a slider is one step and not one per pixel.
+pub struct History {
+    undo: VecDeque<Step>,
+    redo: Vec<State>,
+    limit: usize,
+    group_ms: u64,
+}
+
+impl History {
+    pub fn new(limit: usize, group_ms: u64) -> Self {
+        Self {
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            limit,
+            group_ms,
+        }
+    }
+
+    /// Remember that `before` became `after` at `at` (in ms)
+    pub fn record(&mut self, before: &State, after: &State, at: u64) {
+        if before == after {
+            return;
+        }
+        self.redo.clear();
+
+        let key = edit_key(before, after);
+        if let Some(last) = self.undo.back_mut() {
+            let rapid = at.saturating_sub(last.at) < self.group_ms;
+            if rapid && key.is_some() && key == last.key {
+                last.at = at;
+                return;
+            }
+        }
+
+        self.undo.push_back(Step {
+            state: before.clone(),
+            at,
+            key,
+        });
+        while self.undo.len() > self.limit {
+            self.undo.pop_front();
+        }
+    }
+
+    /// The state before the last edit to `current`
+    pub fn undo(&mut self, current: &State) -> Option<State> {
+        let step = self.undo.pop_back()?;
+        self.redo.push(current.clone());
+        Some(step.state)
+    }
+
+    pub fn redo(&mut self, current: &State) -> Option<State> {
+        let state = self.redo.pop()?;
+        self.undo.push_back(Step {
+            state: current.clone(),
+            at: 0,
+            key: None,
+        });
+        Some(state)
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new(64, 1000)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+
+    fn segment(kind: SegmentKind) -> Segment {
+        let mut seg = Segment::new_with_uuid(
+            Uuid::from_u128(kind.name().len() as u128),
+            8,
+            false,
+            Srgb8::new(255, 0, 0),
+            Srgb8::new(0, 0, 255),
+            0,
+            100,
+            10,
+        );
+        seg.set_kind(kind);
+        seg
+    }
+
+    #[test]
+    fn mix_starts_at_first_color() {
+        let seg = segment(SegmentKind::Mix);
+        assert_eq!(seg.color_at(0), *seg.color_1());
+        assert_eq!(seg.color_at(seg.chill_ms() as u64), *seg.color_1());
+    }
+
+    #[test]
+    fn render_fills_every_led() {
+        for kind in SegmentKind::ALL {
+            let seg = segment(*kind);
+            for len in [0, 1, seg.length(), 100] {
+                let mut leds = vec![Srgb8::new(1, 2, 3); len];
+                seg.render(123, &mut leds);
+                assert!(leds.iter().all(|c| *c != Srgb8::new(1, 2, 3)), "{kind:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn serde_roundtrip() {
+        let state = State::new(SegmentKind::ALL.iter().map(|kind| segment(*kind)));
+        let json = serde_json::to_string(&state).unwrap();
+        let de: State = serde_json::from_str(&json).unwrap();
+        assert_eq!(state, de);
+    }
+
+    #[test]
+    fn kind_defaults_to_mix() {
+        let json = r#"{"uuid":"1d3bd22e-3680-40aa-87e9-da3bdee55c4e","length":1,"bgr":false,"colors":[{"red":255,"green":150,"blue":0},{"red":255,"green":10,"blue":220}],"chill_idx":0,"chill_fac":500,"brightness":10}"#;
+        let seg: Segment = serde_json::from_str(json).unwrap();
+        assert_eq!(seg.kind(), SegmentKind::Mix);
+        assert_eq!(*seg.color_1(), Srgb8::new(255, 150, 0));
+    }
+
+    #[test]
+    fn rejects_invalid_json() {
+        let json = |idx: usize, fac: u32, len: usize| {
+            format!(
+                r#"{{"uuid":"1d3bd22e-3680-40aa-87e9-da3bdee55c4e","length":{len},"bgr":false,"colors":[{{"red":255,"green":150,"blue":0}},{{"red":255,"green":10,"blue":220}}],"chill_idx":{idx},"chill_fac":{fac},"brightness":10}}"#
+            )
+        };
+        assert!(serde_json::from_str::<Segment>(&json(0, 500, 1)).is_ok());
+        for (idx, fac, len, expected) in [
+            (
+                CHILLED.len(),
+                500,
+                1,
+                ValidationError::ChillIdxOutOfRange {
+                    idx: CHILLED.len(),
+                    len: CHILLED.len(),
+                },
+            ),
+            (0, 0, 1, ValidationError::ZeroChillFac),
+            (0, 500, 0, ValidationError::ZeroLength),
+        ] {
+            let err = serde_json::from_str::<Segment>(&json(idx, fac, len)).unwrap_err();
+            assert!(err.to_string().contains(&expected.to_string()), "{err}");
+        }
+    }
+
+    #[test]
+    fn builder_validates() {
+        let seg = SegmentBuilder::new(Uuid::from_u128(1))
+            .length(30)
+            .chill(3, 50)
+            .kind(SegmentKind::Chaser)
+            .build()
+            .unwrap();
+        assert_eq!(seg.chill_ms(), 50 * CHILLED[3]);
+        assert_eq!(
+            SegmentBuilder::new(Uuid::from_u128(1)).chill(0, 0).build(),
+            Err(ValidationError::ZeroChillFac)
+        );
+    }
+
+    #[test]
+    fn kind_params_are_range_checked() {
+        for kind in SegmentKind::ALL {
+            assert_eq!(kind.validate(), Ok(()), "{kind:?}");
+        }
+        let build = |kind| SegmentBuilder::new(Uuid::from_u128(1)).kind(kind).build();
+        assert_eq!(
+            build(SegmentKind::Rainbow { lightness: 101 }),
+            Err(ValidationError::ParamOutOfRange {
+                name: "lightness",
+                value: 101,
+                max: 100
+            })
+        );
+        assert_eq!(
+            build(SegmentKind::OklabRainbow {
+                lightness: 80,
+                chroma: 200
+            }),
+            Err(ValidationError::ParamOutOfRange {
+                name: "chroma",
+                value: 200,
+                max: SegmentKind::MAX_CHROMA
+            })
+        );
+
+        // posted ones too
+        let json = r#"{"uuid":"1d3bd22e-3680-40aa-87e9-da3bdee55c4e","length":1,"bgr":false,"colors":[{"red":255,"green":150,"blue":0},{"red":255,"green":10,"blue":220}],"chill_idx":0,"chill_fac":500,"kind":{"type":"rainbow","lightness":250}}"#;
+        let err = serde_json::from_str::<Segment>(json).unwrap_err();
+        assert!(
+            err.to_string().contains("lightness 250 out of range"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn invalid_segments_still_render() {
+        let mut seg = segment(SegmentKind::Mix);
+        seg.set_chill_idx(usize::MAX);
+        seg.set_chill_fac(0);
+        assert!(seg.validate().is_err());
+        assert!(seg.chill_ms() > 0);
+        seg.color_at(12345);
+        seg.set_chill_fac(u32::MAX);
+        seg.color_at(u64::MAX);
+    }
+
+    #[test]
+    fn state_must_fit_the_strip() {
+        let state = State::new(SegmentKind::ALL.iter().map(|kind| segment(*kind)));
+        let total = SegmentKind::ALL.len() * 8;
+        assert_eq!(state.validate(total), Ok(()));
+        assert_eq!(
+            state.validate(total - 1),
+            Err(ValidationError::TooManyLeds {
+                total,
+                max: total - 1
+            })
+        );
+    }
+
+    #[test]
+    fn exact_periods() {
+        let mut seg = segment(SegmentKind::Mix);
+        seg.set_period(Period::Millis { ms: 4000 });
+        assert_eq!(seg.period_ms(), 4000.);
+        assert_eq!(seg.phase_at(4000 * 1000 + 1000), 0.25);
+
+        // 468.75ms per beat, doesn't drift off the beat
+        seg.set_period(Period::Beats { beats: 1, bpm: 128 });
+        assert_eq!(seg.period_ms(), 468.75);
+        assert_eq!(seg.phase_at(15_000), 0.0);
+        assert_eq!(seg.phase_at(3_600_000 * 24 * 365), 0.0);
+        assert_eq!(seg.phase_at(15_000 + 234), 234. * 128. / 60_000.);
+    }
+
+    #[test]
+    fn period_defaults_to_chill() {
+        let json = r#"{"uuid":"1d3bd22e-3680-40aa-87e9-da3bdee55c4e","length":1,"bgr":false,"colors":[{"red":255,"green":150,"blue":0},{"red":255,"green":10,"blue":220}],"chill_idx":0,"chill_fac":500,"brightness":10}"#;
+        let seg: Segment = serde_json::from_str(json).unwrap();
+        assert_eq!(seg.period(), Period::Chill);
+        assert_eq!(seg.period_ms(), (500 * CHILLED[0]) as f32);
+
+        for period in [Period::Millis { ms: 0 }, Period::Beats { beats: 4, bpm: 0 }] {
+            assert_eq!(
+                SegmentBuilder::new(seg.uuid()).period(period).build(),
+                Err(ValidationError::ZeroPeriod)
+            );
+        }
+    }
+
+    #[test]
+    fn history_groups_slider_drags() {
+        let mut history = History::new(3, 500);
+        let mut state = State::new(SegmentKind::ALL.iter().map(|kind| segment(*kind)));
+        let original = state.clone();
+
+        // one drag, 100ms between updates
+        for (i, fac) in (200..210).enumerate() {
+            let before = state.clone();
+            state[0].set_chill_fac(fac);
+            history.record(&before, &state, i as u64 * 100);
+        }
+        // a different field right after is its own step
+        let before = state.clone();
+        state[0].set_brightness(99);
+        history.record(&before, &state, 1000);
+        let dragged = before;
+
+        let before = state.clone();
+        state.shift_remove_index(1);
+        history.record(&before, &state, 1100);
+        let removed = state.clone();
+
+        assert_eq!(history.undo(&state).as_ref(), Some(&before));
+        assert_eq!(history.undo(&before).as_ref(), Some(&dragged));
+        assert_eq!(history.undo(&dragged).as_ref(), Some(&original));
+        assert!(!history.can_undo());
+
+        assert_eq!(history.redo(&original).as_ref(), Some(&dragged));
+        assert_eq!(history.redo(&dragged).as_ref(), Some(&before));
+        assert_eq!(history.redo(&before).as_ref(), Some(&removed));
+        assert!(!history.can_redo());
+        assert!(history.can_undo());
+    }
+
+    #[test]
+    fn history_is_bounded_and_new_edits_drop_redo() {
+        let mut history = History::new(2, 0);
+        let mut state = State::new([segment(SegmentKind::Mix)].into_iter());
+        for brightness in 0..5 {
+            let before = state.clone();
+            state[0].set_brightness(brightness);
+            history.record(&before, &state, brightness as u64);
+        }
+        assert_eq!(history.undo(&state).unwrap()[0].brightness(), 3);
+        let undone = history.undo(&state).unwrap();
+        assert_eq!(undone[0].brightness(), 2);
+        assert!(history.undo(&state).is_none());
+
+        let mut edited = undone.clone();
+        edited[0].set_chill_fac(1234);
+        history.record(&undone, &edited, 10);
+        assert!(!history.can_redo());
+    }
+
+    #[test]
+    fn state_renders_segments_back_to_back() {
+        let mut short = segment(SegmentKind::Mix);
+        short.set_length(2);
+        short.set_brightness(7);
+        let state = State::new([short.clone(), segment(SegmentKind::Chaser)].into_iter());
+        assert_eq!(state.total_length(), 10);
+
+        let mut leds = vec![Led::new(Srgb8::new(1, 2, 3), 1); 12];
+        state.render(0, &mut leds);
+        assert_eq!(leds[0].color, short.color_at(0));
+        assert_eq!(leds[1].brightness, 7);
+        assert_eq!(leds[2].brightness, 10);
+        assert_eq!(leds[10..], [Led::default(); 2]);
+
+        // too short is fine too
+        state.render(0, &mut leds[..5]);
+    }
+
+    #[test]
+    fn no_glitch_at_u32_wrap() {
+        let seg = segment(SegmentKind::Mix);
+        let wrap = u32::MAX as u64 + 1;
+        for at in [wrap - 1, wrap, wrap + 1] {
+            let expected = ((at % seg.chill_ms() as u64) as f32) / seg.chill_ms() as f32;
+            assert_eq!(seg.phase_at(at), expected);
+        }
+        let step = seg.phase_at(wrap) - seg.phase_at(wrap - 1);
+        assert!((step - 1. / seg.chill_ms() as f32).abs() < 1e-6, "{step}");
+    }
+
+    #[test]
+    fn phase_stays_in_range_for_huge_times() {
+        let seg = segment(SegmentKind::Mix);
+        for at in [u64::MAX, u64::MAX - 1, 1 << 53, (1 << 53) + 1] {
+            let phase = seg.phase_at(at);
+            assert!((0.0..1.0).contains(&phase), "{at}: {phase}");
+        }
+    }
+}
@@ -0,0 +1,471 @@
+//! Estimating a remote clock (the board's `/now`) from round trips.
+//!
+//! Each request gives a [`Sample`]: local time before sending, the server's
+//! answer and local time after receiving. [`ClockSync`] keeps a window of them,
+//! trusts the ones with the smallest round trip time (queueing delay only ever
+//! makes a round trip longer, so the fastest ones are the least distorted),
+//! fits a drift rate through those and slews its output towards the estimate
+//! instead of stepping, so animations driven by it don't jump or run
+//! backwards once synced.
+
+use alloc::collections::VecDeque;
+
+/// One request/response exchange, all times in milliseconds
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sample {
+    /// local time when the request was sent
+    pub sent: u64,
+    /// server time contained in the response
+    pub server: u64,
+    /// local time when the response arrived
+    pub received: u64,
+}
+
+impl Sample {
+    pub fn new(sent: u64, server: u64, received: u64) -> Self {
+        Self {
+            sent,
+            server,
+            received,
+        }
+    }
+
+    pub fn rtt(&self) -> u64 {
+        self.received.saturating_sub(self.sent)
+    }
+
+    /// Local time at which the server presumably read its clock
+    pub fn midpoint(&self) -> u64 {
+        self.sent + self.rtt() / 2
+    }
+
+    /// `server - local`, assuming symmetric latency
+    pub fn offset(&self) -> i64 {
+        self.server as i64 - self.midpoint() as i64
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SyncConfig {
+    /// how many samples to keep
+    pub window: usize,
+    /// samples with an RTT up to this much above the window's minimum are
+    /// considered good
+    pub rtt_tolerance_ms: u64,
+    /// maximum correction speed, in ms of offset change per ms of local time
+    pub max_slew: f64,
+    /// errors larger than this are fixed with a step instead of slewing
+    pub step_threshold_ms: f64,
+    /// drift is only estimated from good samples spanning at least this long
+    pub min_drift_span_ms: u64,
+}
+
+impl Default for SyncConfig {
+    fn default() -> Self {
+        Self {
+            window: 16,
+            rtt_tolerance_ms: 4,
+            max_slew: 0.05,
+            step_threshold_ms: 1_000.,
+            min_drift_span_ms: 10_000,
+        }
+    }
+}
+
+/// Where the estimate is anchored: the remote clock read `offset + local` at
+/// `local`, and runs `drift` ms/ms faster than ours.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Estimate {
+    local: u64,
+    offset: f64,
+    drift: f64,
+}
+
+impl Estimate {
+    fn offset_at(&self, local: u64) -> f64 {
+        self.offset + self.drift * (local as f64 - self.local as f64)
+    }
+}
+
+/// Turns a jumpy offset estimate into a smooth, monotonic clock.
+///
+/// The offset actually applied follows the target at most `max_slew` ms per
+/// ms of local time, unless they are more than `step_threshold_ms` apart.
+#[derive(Clone, Debug)]
+pub struct Slew {
+    max_slew: f64,
+    step_threshold_ms: f64,
+    /// the offset currently applied, and when it was last updated
+    applied: Option<(u64, f64)>,
+    last_output: u64,
+}
+
+impl Slew {
+    pub fn new(max_slew: f64, step_threshold_ms: f64) -> Self {
+        Self {
+            max_slew,
+            step_threshold_ms,
+            applied: None,
+            last_output: 0,
+        }
+    }
+
+    /// `local` plus an offset moving towards `target`. Without a target the
+    /// current offset is kept.
+    ///
+    /// Never returns less than the previous call, except when stepping: for
+    /// the first target and for errors beyond the step threshold.
+    pub fn apply(&mut self, local: u64, target: Option<f64>) -> u64 {
+        let (offset, step) = match (self.applied, target) {
+            // nothing to go on yet
+            (None, None) => {
+                self.last_output = local.max(self.last_output);
+                return self.last_output;
+            }
+            (None, Some(target)) => (target, true),
+            (Some((_, applied)), None) => (applied, false),
+            (Some((last_local, applied)), Some(target)) => {
+                let error = target - applied;
+                if libm::fabs(error) > self.step_threshold_ms {
+                    (target, true)
+                } else {
+                    let elapsed = local.saturating_sub(last_local) as f64;
+                    let max_step = elapsed * self.max_slew;
+                    (applied + error.clamp(-max_step, max_step), false)
+                }
+            }
+        };
+        self.applied = Some((local, offset));
+
+        let out = (local as f64 + offset).max(0.) as u64;
+        // a step backwards is the one thing a running animation can't take,
+        // short of being told it is way off
+        let out = if step { out } else { out.max(self.last_output) };
+        self.last_output = out;
+        out
+    }
+
+    /// The offset applied by the last `apply`
+    pub fn offset(&self) -> Option<f64> {
+        self.applied.map(|(_, offset)| offset)
+    }
+}
+
+pub struct ClockSync {
+    config: SyncConfig,
+    samples: VecDeque<Sample>,
+    estimate: Option<Estimate>,
+    slew: Slew,
+}
+
+impl ClockSync {
+    pub fn new(config: SyncConfig) -> Self {
+        Self {
+            samples: VecDeque::with_capacity(config.window),
+            slew: Slew::new(config.max_slew, config.step_threshold_ms),
+            config,
+            estimate: None,
+        }
+    }
+
+    pub fn add_sample(&mut self, sample: Sample) {
+        if sample.received < sample.sent {
+            return;
+        }
+        if let Some(estimate) = self.estimate {
+            // way off from everything before it, e.g. the board rebooted:
+            // the old samples describe a clock that no longer exists
+            let error = sample.offset() as f64 - estimate.offset_at(sample.midpoint());
+            if libm::fabs(error) > self.config.step_threshold_ms + sample.rtt() as f64 {
+                self.samples.clear();
+            }
+        }
+        if self.samples.len() == self.config.window {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+        self.estimate = self.compute_estimate();
+    }
+
+    /// At least one sample has arrived
+    pub fn is_synced(&self) -> bool {
+        self.estimate.is_some()
+    }
+
+    /// Best current guess of `server - local` at `local`, in ms
+    pub fn offset_at(&self, local: u64) -> Option<i64> {
+        self.estimate.map(|e| e.offset_at(local) as i64)
+    }
+
+    /// Estimated drift of the server clock relative to ours, in parts per
+    /// million. Positive means the server runs fast.
+    pub fn drift_ppm(&self) -> f64 {
+        self.estimate.map(|e| e.drift * 1e6).unwrap_or(0.)
+    }
+
+    pub fn sample_count(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// The smallest round trip time in the window
+    pub fn min_rtt(&self) -> Option<u64> {
+        self.samples.iter().map(Sample::rtt).min()
+    }
+
+    /// Server time corresponding to `local`. Moves towards the estimate at
+    /// most `max_slew` ms per ms, so successive calls with non-decreasing
+    /// `local` don't go backwards. The first estimate and errors beyond
+    /// `step_threshold_ms` are stepped to. Without samples this is `local`.
+    pub fn server_time(&mut self, local: u64) -> u64 {
+        let target = self.estimate.map(|e| e.offset_at(local));
+        self.slew.apply(local, target)
+    }
+
+    fn compute_estimate(&self) -> Option<Estimate> {
+        let min_rtt = self.min_rtt()?;
+        let cutoff = min_rtt + self.config.rtt_tolerance_ms;
+        let good = || self.samples.iter().filter(move |s| s.rtt() <= cutoff);
+
+        let best = good().min_by_key(|s| s.rtt())?;
+
+        let first = good().map(Sample::midpoint).min()?;
+        let last = good().map(Sample::midpoint).max()?;
+        let drift = if last - first >= self.config.min_drift_span_ms {
+            fit_drift(good())
+        } else {
+            0.
+        };
+
+        // anchor on the average of the good samples, projected onto the newest
+        // one with the fitted drift; a single min-RTT sample is noisier
+        let (n, sum) = good().fold((0usize, 0f64), |(n, sum), s| {
+            let projected = s.offset() as f64 + drift * (last as f64 - s.midpoint() as f64);
+            (n + 1, sum + projected)
+        });
+        let offset = if n > 0 {
+            sum / n as f64
+        } else {
+            best.offset() as f64
+        };
+
+        Some(Estimate {
+            local: last,
+            offset,
+            drift,
+        })
+    }
+}
+
+impl Default for ClockSync {
+    fn default() -> Self {
+        Self::new(SyncConfig::default())
+    }
+}
+
+/// Least squares slope of offset over local time
+fn fit_drift<'a>(samples: impl Iterator<Item = &'a Sample> + Clone) -> f64 {
+    let (n, sum_x, sum_y) = samples.clone().fold((0f64, 0f64, 0f64), |(n, x, y), s| {
+        (n + 1., x + s.midpoint() as f64, y + s.offset() as f64)
+    });
+    if n < 2. {
+        return 0.;
+    }
+    let (mean_x, mean_y) = (sum_x / n, sum_y / n);
+    let (cov, var) = samples.fold((0f64, 0f64), |(cov, var), s| {
+        let dx = s.midpoint() as f64 - mean_x;
+        let dy = s.offset() as f64 - mean_y;
+        (cov + dx * dy, var + dx * dx)
+    });
+    if var == 0. {
+        0.
+    } else {
+        cov / var
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// xorshift, so the "network" is the same on every run
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+    }
+
+    /// A server whose clock is `offset + local * (1 + drift)`
+    struct Net {
+        rng: Rng,
+        offset: f64,
+        drift: f64,
+        latency: fn(&mut Rng) -> u64,
+    }
+
+    impl Net {
+        fn server_at(&self, local: u64) -> u64 {
+            (self.offset + local as f64 * (1. + self.drift)) as u64
+        }
+
+        fn exchange(&mut self, sent: u64) -> Sample {
+            let there = (self.latency)(&mut self.rng);
+            let back = (self.latency)(&mut self.rng);
+            Sample::new(sent, self.server_at(sent + there), sent + there + back)
+        }
+    }
+
+    fn uniform(rng: &mut Rng) -> u64 {
+        5 + rng.below(10)
+    }
+
+    /// a quiet LAN
+    fn steady(rng: &mut Rng) -> u64 {
+        9 + rng.below(3)
+    }
+
+    /// mostly fast, every few requests stuck in a queue for a while
+    fn spiky(rng: &mut Rng) -> u64 {
+        if rng.below(4) == 0 {
+            50 + rng.below(400)
+        } else {
+            8 + rng.below(4)
+        }
+    }
+
+    fn run(mut net: Net, rounds: u64) -> (ClockSync, Net, u64) {
+        let mut sync = ClockSync::default();
+        let mut local = 1_000;
+        for _ in 0..rounds {
+            let sample = net.exchange(local);
+            local = sample.received;
+            sync.add_sample(sample);
+            local += 2_000;
+        }
+        (sync, net, local)
+    }
+
+    #[test]
+    fn offset_and_rtt() {
+        let s = Sample::new(100, 5_000, 120);
+        assert_eq!(s.rtt(), 20);
+        assert_eq!(s.midpoint(), 110);
+        assert_eq!(s.offset(), 4_890);
+    }
+
+    #[test]
+    fn unsynced_passes_local_time_through() {
+        let mut sync = ClockSync::default();
+        assert!(!sync.is_synced());
+        assert_eq!(sync.server_time(1234), 1234);
+    }
+
+    #[test]
+    fn converges_with_uniform_latency() {
+        let net = Net {
+            rng: Rng(0x5eed),
+            offset: 123_456.,
+            drift: 0.,
+            latency: uniform,
+        };
+        let (sync, net, local) = run(net, 20);
+        let error = sync.offset_at(local).unwrap() - (net.server_at(local) as i64 - local as i64);
+        assert!(error.abs() <= 3, "{error}");
+    }
+
+    #[test]
+    fn min_rtt_filter_ignores_spikes() {
+        let net = Net {
+            rng: Rng(42),
+            offset: 9_000.,
+            drift: 0.,
+            latency: spiky,
+        };
+        let (sync, net, local) = run(net, 40);
+        assert!(sync.min_rtt().unwrap() < 30);
+        let error = sync.offset_at(local).unwrap() - (net.server_at(local) as i64 - local as i64);
+        assert!(error.abs() <= 3, "{error}");
+    }
+
+    #[test]
+    fn estimates_drift() {
+        let net = Net {
+            rng: Rng(7),
+            offset: 0.,
+            drift: 200e-6,
+            latency: steady,
+        };
+        let (mut sync, net, local) = run(net, 16);
+        assert!(
+            (sync.drift_ppm() - 200.).abs() < 50.,
+            "{}",
+            sync.drift_ppm()
+        );
+
+        // extrapolating a minute ahead without new samples stays close
+        let later = local + 60_000;
+        sync.server_time(local);
+        let error = sync.server_time(later) as i64 - net.server_at(later) as i64;
+        assert!(error.abs() <= 5, "{error}");
+    }
+
+    #[test]
+    fn slews_instead_of_stepping() {
+        let mut sync = ClockSync::default();
+        sync.add_sample(Sample::new(0, 10_000, 10));
+        sync.server_time(10);
+
+        // the server is suddenly 200ms ahead of what we thought
+        sync.add_sample(Sample::new(1_000, 11_205, 1_010));
+        let mut last = sync.server_time(1_010);
+        for local in (1_026..10_000).step_by(16) {
+            let now = sync.server_time(local);
+            assert!(now >= last);
+            assert!(now - last <= 16 + 1, "jumped by {}", now - last);
+            last = now;
+        }
+        let target = last as i64 - sync.offset_at(10_000).unwrap() - 10_000;
+        assert!(target.abs() <= 16, "{target}");
+    }
+
+    #[test]
+    fn large_errors_step() {
+        let mut sync = ClockSync::default();
+        sync.add_sample(Sample::new(0, 10_000, 10));
+        sync.server_time(10);
+        sync.add_sample(Sample::new(100, 500_000, 110));
+        assert_eq!(sync.server_time(110), 500_005);
+    }
+
+    #[test]
+    fn never_goes_backwards() {
+        let mut net = Net {
+            rng: Rng(99),
+            offset: 50_000.,
+            drift: -300e-6,
+            latency: spiky,
+        };
+        let mut sync = ClockSync::default();
+        let mut last = 0;
+        let mut local = 0;
+        for i in 0..2_000u64 {
+            local += 16;
+            if i % 100 == 0 {
+                let sample = net.exchange(local);
+                local = sample.received;
+                sync.add_sample(sample);
+            }
+            let now = sync.server_time(local);
+            assert!(now >= last);
+            last = now;
+        }
+    }
+}
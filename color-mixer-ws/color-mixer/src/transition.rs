@@ -0,0 +1,338 @@
+//! Going from one look to the next without a jump.
+//!
+//! A [`Transition`] takes over from whatever was showing, a [`State`] or
+//! another transition still running, and brings in what's rendered now in
+//! one of a few [`Style`]s. It runs on the same `at_millis` as
+//! [`Segment::color_at`](crate::strip::Segment::color_at), and the old look
+//! keeps animating until it's gone.
+
+use alloc::{boxed::Box, vec::Vec};
+
+use palette::{LinSrgb, Mix, Srgb};
+use serde::{Deserialize, Serialize};
+
+use crate::strip::{Led, Srgb8, State};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Style {
+    /// Every LED mixes from old to new at once
+    #[default]
+    Crossfade,
+    /// New sweeps in from the start of the strip
+    Wipe,
+    /// LEDs switch one by one, in random order
+    Dissolve,
+    /// Old fades out, then new fades in
+    FadeThroughBlack,
+}
+
+impl Style {
+    pub const ALL: &'static [Style] = &[
+        Style::Crossfade,
+        Style::Wipe,
+        Style::Dissolve,
+        Style::FadeThroughBlack,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Style::Crossfade => "crossfade",
+            Style::Wipe => "wipe",
+            Style::Dissolve => "dissolve",
+            Style::FadeThroughBlack => "fade through black",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|style| style.name() == name)
+    }
+}
+
+/// How segment edits and recalled presets come in. Body of
+/// `POST /transition`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Settings {
+    pub style: Style,
+    /// `0` switches right away
+    pub ms: u32,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            style: Style::Crossfade,
+            ms: 500,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Transition {
+    from: State,
+    /// Still bringing in `from` when this one started
+    under: Option<Box<Transition>>,
+    start: u64,
+    duration_ms: u64,
+    style: Style,
+    scratch: Vec<Led>,
+}
+
+impl Transition {
+    pub fn new(from: State, start: u64, duration_ms: u64, style: Style) -> Self {
+        Self {
+            from,
+            under: None,
+            start,
+            duration_ms,
+            style,
+            scratch: Vec::new(),
+        }
+    }
+
+    /// Start from what `running` shows at `at_millis` rather than from the
+    /// end of it, for a change that comes in while another one is still
+    /// going. `running` should be going into this one's `from`.
+    pub fn over(mut self, running: Transition, at_millis: u64) -> Self {
+        if !running.is_done(at_millis) {
+            self.under = Some(Box::new(running));
+        }
+        self
+    }
+
+    pub fn style(&self) -> Style {
+        self.style
+    }
+
+    /// How far along, `0` is all `from` and `1` is done
+    pub fn progress(&self, at_millis: u64) -> f32 {
+        if self.duration_ms == 0 {
+            return 1.;
+        }
+        let elapsed = at_millis.saturating_sub(self.start).min(self.duration_ms);
+        elapsed as f32 / self.duration_ms as f32
+    }
+
+    pub fn is_done(&self, at_millis: u64) -> bool {
+        self.progress(at_millis) >= 1.
+    }
+
+    /// Bring the old look into `leds`, which hold the new one rendered at
+    /// `at_millis`
+    pub fn blend(&mut self, at_millis: u64, leds: &mut [Led]) {
+        let t = self.progress(at_millis);
+        self.scratch.clear();
+        self.scratch.resize(leds.len(), Led::default());
+        self.from.render(at_millis, &mut self.scratch);
+        if let Some(under) = &mut self.under {
+            under.blend(at_millis, &mut self.scratch);
+            if under.is_done(at_millis) {
+                self.under = None;
+            }
+        }
+
+        let len = leds.len() as f32;
+        for (i, (led, old)) in leds.iter_mut().zip(&self.scratch).enumerate() {
+            *led = match self.style {
+                Style::Crossfade => mix_led(old, led, t),
+                // a soft edge one LED wide
+                Style::Wipe => mix_led(old, led, (t * len - i as f32).clamp(0., 1.)),
+                Style::Dissolve if t > threshold(i, self.start) => *led,
+                Style::Dissolve => *old,
+                Style::FadeThroughBlack if t < 0.5 => mix_led(old, &black(old), t * 2.),
+                Style::FadeThroughBlack => mix_led(&black(led), led, t * 2. - 1.),
+            };
+        }
+    }
+}
+
+/// Mixes in linear light, so the fade doesn't dip in the middle
+fn mix_led(a: &Led, b: &Led, t: f32) -> Led {
+    let lin = |led: &Led| -> LinSrgb { led.exact().into_linear() };
+    let color: Srgb = Srgb::from_linear(lin(a).mix(lin(b), t));
+    let brightness = a.brightness as f32 + (b.brightness as f32 - a.brightness as f32) * t;
+    Led::from_exact(color, libm::roundf(brightness) as u8)
+}
+
+/// Off, but at `led`'s brightness so only the color fades
+fn black(led: &Led) -> Led {
+    Led::new(Srgb8::default(), led.brightness)
+}
+
+/// When LED `index` switches in a dissolve, in `0..1`. Different for every
+/// `seed`, so dissolves don't all look the same.
+fn threshold(index: usize, seed: u64) -> f32 {
+    let mut x = (index as u64 ^ seed.rotate_left(32)).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    x ^= x >> 29;
+    x = x.wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x ^= x >> 32;
+    (x >> 40) as f32 / (1u64 << 24) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::strip::SegmentBuilder;
+    use uuid::Uuid;
+
+    fn look(n: u128, color: Srgb8, brightness: u8) -> State {
+        let seg = SegmentBuilder::new(Uuid::from_u128(n))
+            .length(4)
+            .colors(color, color)
+            .brightness(brightness)
+            .build()
+            .unwrap();
+        State::new([seg].into_iter())
+    }
+
+    fn frame(transition: &mut Transition, new: &State, at: u64) -> [Led; 4] {
+        let mut leds = [Led::default(); 4];
+        new.render(at, &mut leds);
+        transition.blend(at, &mut leds);
+        leds
+    }
+
+    const RED: Srgb8 = Srgb8::new(255, 0, 0);
+    const BLUE: Srgb8 = Srgb8::new(0, 0, 255);
+
+    #[test]
+    fn crossfade_goes_from_old_to_new() {
+        let old = look(1, RED, 10);
+        let new = look(2, BLUE, 30);
+        let mut fade = Transition::new(old.clone(), 1000, 2000, Style::Crossfade);
+
+        assert_eq!(frame(&mut fade, &new, 500)[0].color, RED);
+        assert_eq!(frame(&mut fade, &new, 1000)[0].brightness, 10);
+
+        let mid = frame(&mut fade, &new, 2000)[0];
+        assert_eq!(mid.brightness, 20);
+        // linear light halfway is brighter than the sRGB halfway of 128
+        assert!(mid.color.red > 180 && mid.color.blue > 180, "{mid:?}");
+
+        assert!(!fade.is_done(2999));
+        assert!(fade.is_done(3000));
+        assert_eq!(frame(&mut fade, &new, 3000)[0].color, BLUE);
+        assert_eq!(frame(&mut fade, &new, 3000)[0].brightness, 30);
+
+        assert!(Transition::new(old, 0, 0, Style::Crossfade).is_done(0));
+    }
+
+    #[test]
+    fn every_style_starts_old_and_ends_new() {
+        let old = look(1, RED, 10);
+        let new = look(2, BLUE, 30);
+        for &style in Style::ALL {
+            let mut transition = Transition::new(old.clone(), 0, 1000, style);
+            let first = frame(&mut transition, &new, 0);
+            let last = frame(&mut transition, &new, 1000);
+            assert!(first.iter().all(|led| *led == old_led()), "{style:?}");
+            assert!(last.iter().all(|led| *led == new_led()), "{style:?}");
+        }
+
+        fn old_led() -> Led {
+            Led::new(RED, 10)
+        }
+        fn new_led() -> Led {
+            Led::new(BLUE, 30)
+        }
+    }
+
+    #[test]
+    fn styles_in_between() {
+        let old = look(1, RED, 10);
+        let new = look(2, BLUE, 30);
+        let mid = |style| frame(&mut Transition::new(old.clone(), 0, 1000, style), &new, 500);
+
+        // half the strip is new, the other half old
+        let wipe = mid(Style::Wipe);
+        assert_eq!(
+            wipe[..2].iter().map(|led| led.color).collect::<Vec<_>>(),
+            [BLUE; 2]
+        );
+        assert_eq!(
+            wipe[2..].iter().map(|led| led.color).collect::<Vec<_>>(),
+            [RED; 2]
+        );
+
+        // every LED is either
+        let dissolve = mid(Style::Dissolve);
+        assert!(dissolve
+            .iter()
+            .all(|led| led.color == RED || led.color == BLUE));
+
+        // dark in the middle
+        let black = mid(Style::FadeThroughBlack);
+        assert!(black.iter().all(|led| led.color == Srgb8::default()));
+        let early = frame(
+            &mut Transition::new(old.clone(), 0, 1000, Style::FadeThroughBlack),
+            &new,
+            250,
+        );
+        assert!(early[0].color.red > 0 && early[0].color.blue == 0);
+    }
+
+    #[test]
+    fn dissolves_switch_every_led_once() {
+        let thresholds: Vec<f32> = (0..1000).map(|i| threshold(i, 1234)).collect();
+        assert!(thresholds.iter().all(|t| (0. ..1.).contains(t)));
+        // roughly evenly spread
+        let early = thresholds.iter().filter(|t| **t < 0.5).count();
+        assert!((400..600).contains(&early), "{early}");
+        assert_ne!(threshold(0, 1), threshold(0, 2));
+    }
+
+    #[test]
+    fn changes_during_a_transition_pick_up_where_it_is() {
+        let a = look(1, RED, 10);
+        let b = look(2, BLUE, 10);
+        let c = look(3, Srgb8::new(0, 255, 0), 10);
+
+        let mut ab = Transition::new(a, 0, 1000, Style::Crossfade);
+        let shown = frame(&mut ab, &b, 500);
+
+        // c comes in halfway through a to b
+        let mut bc = Transition::new(b, 500, 1000, Style::Crossfade).over(ab, 500);
+        assert_eq!(frame(&mut bc, &c, 500), shown);
+        assert!(bc.under.is_some());
+        // a to b ends under it and is dropped
+        frame(&mut bc, &c, 1000);
+        assert!(bc.under.is_none());
+        assert_eq!(frame(&mut bc, &c, 1500)[0].color, Srgb8::new(0, 255, 0));
+
+        // finished ones aren't kept at all
+        let done = Transition::new(look(4, RED, 10), 0, 10, Style::Wipe);
+        let next = Transition::new(look(5, RED, 10), 20, 10, Style::Wipe).over(done, 20);
+        assert!(next.under.is_none());
+    }
+
+    #[test]
+    fn leds_past_a_shorter_look_fade_to_dark() {
+        let old = look(1, RED, 10);
+        let seg = SegmentBuilder::new(Uuid::from_u128(2))
+            .length(2)
+            .colors(BLUE, BLUE)
+            .brightness(10)
+            .build()
+            .unwrap();
+        let new = State::new([seg].into_iter());
+        let mut fade = Transition::new(old, 0, 1000, Style::Crossfade);
+
+        // the frame stays as long as the old look
+        let mid = frame(&mut fade, &new, 500);
+        assert!(mid[2..]
+            .iter()
+            .all(|led| led.color.red > 0 && led.color.red < 255));
+        let last = frame(&mut fade, &new, 1000);
+        assert!(last[2..].iter().all(|led| led.color == Srgb8::default()));
+    }
+
+    #[test]
+    fn style_names_roundtrip() {
+        for style in Style::ALL {
+            assert_eq!(Style::from_name(style.name()), Some(*style));
+        }
+        let settings: Settings =
+            serde_json::from_str(r#"{"style": "fade_through_black", "ms": 800}"#).unwrap();
+        assert_eq!(settings.style, Style::FadeThroughBlack);
+    }
+}
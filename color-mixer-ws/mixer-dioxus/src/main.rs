@@ -1,5 +1,17 @@
 use chrono::Utc;
-use color_mixer::strip::{Control, Segment, Srgb8, State, Wrap, CHILLED};
+use color_mixer::calibration::{Calibration, TestPatch, TEST_PATCHES};
+use color_mixer::compose::{Compositor, Flash};
+use color_mixer::patch::{self, Op, Patch};
+use color_mixer::power::{PowerEstimate, PowerModel};
+use color_mixer::preset::PresetRequest;
+use color_mixer::schedule::{Action, At, Days, Location, Rule, Schedule};
+use color_mixer::schema;
+use color_mixer::strip::{
+    Control, History, Led, Period, Segment, SegmentKind, Srgb8, State, Wrap, CHILLED,
+    DEFAULT_BRIGHTNESS, FULL_BRIGHTNESS,
+};
+use color_mixer::timesync::{ClockSync, Sample, SyncConfig};
+use color_mixer::transition::{self, Style, Transition};
 use dioxus::{core::to_owned, prelude::*};
 use fermi::{use_atom_state, use_read, Atom, AtomState};
 use futures::StreamExt;
@@ -8,8 +20,19 @@ use indexmap::IndexMap;
 use log::debug;
 
 pub static STATE_ATOM: Atom<Option<SegMap>> = |_| None;
+/// Bumped when segments change behind the editors' backs, to remount them
+pub static GENERATION_ATOM: Atom<u64> = |_| 0;
+/// The board's transition settings, for the preview to go through the same
+pub static TRANSITION_ATOM: Atom<transition::Settings> = |_| transition::Settings::default();
 
 const DEBOUNCE_MS: u64 = 300;
+const PREVIEW_MAX_LEDS: usize = 60;
+const SYNC_INTERVAL_MS: u32 = 2_000;
+const SYNC_BURST_INTERVAL_MS: u32 = 200;
+const SYNC_BURST_SAMPLES: usize = 8;
+const DEFAULT_FADE_MS: u32 = 2_000;
+const POWER_POLL_MS: u32 = 1_000;
+const FLASH_MS: u32 = 1_000;
 
 type Res<T> = Result<T, Box<dyn std::error::Error>>;
 
@@ -46,65 +69,78 @@ fn App2(cx: Scope) -> Element {
 #[allow(non_snake_case)]
 #[inline_props]
 fn AppServerSync(cx: Scope, base_url: UseState<String>) -> Element {
-    let update = use_coroutine(&cx, |mut rx: UnboundedReceiver<SegMap>| {
-        to_owned![base_url];
+    // sequence number of the server state we last saw
+    let seq = use_ref(&cx, || 0u64);
+    let history = use_ref(&cx, History::default);
+    let segments_state = use_atom_state(&cx, STATE_ATOM).to_owned();
+    let generation = use_atom_state(&cx, GENERATION_ATOM).to_owned();
+
+    let update = use_coroutine(&cx, |mut rx: UnboundedReceiver<Vec<Op>>| {
+        to_owned![base_url, seq, segments_state, generation];
         async move {
             let mut last_update = Utc::now();
 
-            let inner = async move {
-                while let Some(mut data) = rx.next().await {
-                    loop {
-                        match rx.try_next() {
-                            Ok(None) => {
-                                log::info!("shutting down updater");
-                            }
-                            Ok(Some(next_data)) => {
-                                log::debug!("but wait, there's more!");
-                                data = next_data;
-                                continue;
-                            }
-                            Err(_e) => {
-                                // channel has been drained
-                                log::debug!("I can't believe it's not bu^Wmore!");
-                                break;
-                            }
+            while let Some(mut ops) = rx.next().await {
+                loop {
+                    match rx.try_next() {
+                        Ok(None) => {
+                            log::info!("shutting down updater");
+                        }
+                        Ok(Some(more_ops)) => {
+                            log::debug!("but wait, there's more!");
+                            ops.extend(more_ops);
+                            continue;
+                        }
+                        Err(_e) => {
+                            // channel has been drained
+                            log::debug!("I can't believe it's not bu^Wmore!");
+                            break;
                         }
                     }
+                }
 
-                    let now = Utc::now();
-                    let debounce_amount = std::time::Duration::from_millis(DEBOUNCE_MS);
-
-                    let dt = now
-                        .signed_duration_since(last_update)
-                        .to_std()
-                        .unwrap_or(debounce_amount);
-
-                    if let Some(wait) = debounce_amount.checked_sub(dt) {
-                        log::debug!("debounce: {wait:?}");
-                        TimeoutFuture::new(wait.as_millis() as u32).await;
-                    }
+                let now = Utc::now();
+                let debounce_amount = std::time::Duration::from_millis(DEBOUNCE_MS);
 
-                    let latest_base_url = base_url.current();
-                    let url = format!("{latest_base_url}data");
-                    log::debug!("updating DATA at {url}");
+                let dt = now
+                    .signed_duration_since(last_update)
+                    .to_std()
+                    .unwrap_or(debounce_amount);
 
-                    let ser = serde_json::to_vec(&data)?;
-                    let mut req = surf::post(url).body_bytes(&ser).await?;
-                    let _loaded = req.body_bytes().await?;
+                if let Some(wait) = debounce_amount.checked_sub(dt) {
+                    log::debug!("debounce: {wait:?}");
+                    TimeoutFuture::new(wait.as_millis() as u32).await;
+                }
 
-                    last_update = Utc::now();
+                let latest_base_url = base_url.current();
+                let patch = Patch {
+                    base: *seq.read(),
+                    ops,
+                };
+                match send_patch(&latest_base_url, &patch).await {
+                    Ok(new_seq) => *seq.write() = new_seq,
+                    Err(e) => {
+                        // rejected or lost, start over from what the server has
+                        // and keep going with the next edit
+                        log::warn!("patch failed: {e:?}");
+                        match load_data(&latest_base_url).await {
+                            Ok((segments, new_seq)) => {
+                                *seq.write() = new_seq;
+                                segments_state.set(Some(segments));
+                                generation.modify(|g| g + 1);
+                            }
+                            Err(e) => log::error!("could not reload after a failed patch: {e:?}"),
+                        }
+                    }
                 }
-                Ok(())
-            };
 
-            let res: Res<()> = inner.await;
-            if let Err(e) = res {
-                log::error!("someone went wrong: {e:?}");
+                last_update = Utc::now();
             }
         }
     });
 
-    cx.provide_context(UpdateState(update.to_owned()));
+    cx.provide_context(Seq(seq.clone()));
+    cx.provide_context(UpdateState(update.to_owned(), history.clone()));
 
     cx.render(rsx!(AppOutestest {
         base_url: base_url.to_string()
@@ -117,11 +153,37 @@ fn Color2(
     cx: Scope,
     prime_idx: usize,
     fac: u32,
-    now: u32,
+    now: u64,
     c1: UseState<Srgb8>,
     c2: UseState<Srgb8>,
+    kind: SegmentKind,
+    period: Period,
+    length: usize,
 ) -> Element {
-    let seg = Segment::new(1, false, **c1, **c2, *prime_idx, *fac, 0);
+    let mut seg = Segment::new(*length, false, **c1, **c2, *prime_idx, *fac, 0);
+    seg.set_kind(*kind);
+    seg.set_period(*period);
+
+    if *kind != SegmentKind::Mix {
+        let mut leds = vec![Srgb8::default(); *length];
+        seg.render(*now, &mut leds);
+        let leds = leds
+            .into_iter()
+            .take(PREVIEW_MAX_LEDS)
+            .enumerate()
+            .map(|(i, led)| {
+                rsx!(span {
+                    key: "led-{i}",
+                    class: "led",
+                    style: format_args!("background-color: #{:x}", led),
+                })
+            });
+        return cx.render(rsx!(div {
+            class: "strip",
+            leds
+        }));
+    }
+
     let col = seg.color_at(*now);
 
     let pc: piet::Color = piet::Color::rgb8(col.red, col.green, col.blue);
@@ -141,9 +203,11 @@ fn Color2(
 
 type SegMap = IndexMap<String, Segment>;
 
-fn send_update(update: Option<UpdateState>, segments: SegMap) {
+fn send_update(update: Option<UpdateState>, ops: Vec<Op>) {
     if let Some(ref update) = update {
-        update.0.send(segments);
+        if !ops.is_empty() {
+            update.0.send(ops);
+        }
     }
 }
 
@@ -155,15 +219,46 @@ fn edit_segments(
     segments.modify(|segments| {
         let mut segments = segments.to_owned();
         if let Some(ref mut segments) = segments {
+            let before = State::from(segments.clone());
             wat(segments);
+            let after = State::from(segments.clone());
 
-            send_update(update, segments.clone());
+            if let Some(UpdateState(_, history)) = &update {
+                let at = Utc::now().timestamp_millis() as u64;
+                history.write_silent().record(&before, &after, at);
+            }
+            send_update(update, patch::diff(&before, &after));
         }
 
         segments
     });
 }
 
+fn undo_redo(
+    segments: &AtomState<Option<SegMap>>,
+    generation: &AtomState<u64>,
+    update: Option<UpdateState>,
+    redo: bool,
+) {
+    let Some(UpdateState(_, history)) = &update else {
+        return;
+    };
+    let Some(current) = segments.get().as_ref().map(|s| State::from(s.clone())) else {
+        return;
+    };
+
+    let restored = if redo {
+        history.write().redo(&current)
+    } else {
+        history.write().undo(&current)
+    };
+    if let Some(restored) = restored {
+        send_update(update.clone(), patch::diff(&current, &restored));
+        segments.set(Some(restored.into_segments()));
+        generation.modify(|g| g + 1);
+    }
+}
+
 #[allow(non_snake_case)]
 #[inline_props]
 fn ChillInput(cx: Scope, segment_id: String, chill_idx: UseState<usize>) -> Element {
@@ -190,6 +285,206 @@ fn ChillInput(cx: Scope, segment_id: String, chill_idx: UseState<usize>) -> Elem
     })
 }
 
+fn edit_period(
+    segments: &AtomState<Option<SegMap>>,
+    update: Option<UpdateState>,
+    segment_id: &str,
+    period: Period,
+) {
+    edit_segments(segments, update, |segments| {
+        if let Some(segment) = segments.get_mut(segment_id) {
+            segment.set_period(period);
+        }
+    });
+}
+
+#[allow(non_snake_case)]
+#[inline_props]
+fn PeriodInput(
+    cx: Scope,
+    segment_id: String,
+    period: UseState<Period>,
+    chill_idx: UseState<usize>,
+) -> Element {
+    let segments: &AtomState<Option<SegMap>> = use_atom_state(&cx, STATE_ATOM);
+    let update: Option<UpdateState> = cx.consume_context::<UpdateState>();
+    let update_too = update.clone();
+    let update_tooer = update.clone();
+
+    let current = period.name();
+    let options = Period::MODES.iter().map(|p| {
+        let name = p.name();
+        rsx!(option {
+            key: "{name}",
+            value: "{name}",
+            "{name}"
+        })
+    });
+
+    let params = match **period {
+        Period::Chill => rsx!(ChillInput {
+            segment_id: segment_id.clone(),
+            chill_idx: chill_idx.clone()
+        }),
+        Period::Millis { ms } => rsx!(
+            input {
+                r#type: "number",
+                name: "period_ms",
+                value: "{ms}",
+                min: "1",
+                oninput: move |ev| {
+                    let ms = ev.value.parse().unwrap_or(ms).max(1);
+                    let new_period = Period::Millis { ms };
+                    period.set(new_period);
+                    edit_period(segments, update_too.clone(), segment_id, new_period);
+                },
+            }
+            "ms"
+        ),
+        Period::Beats { beats, bpm } => rsx!(
+            input {
+                r#type: "number",
+                name: "beats",
+                value: "{beats}",
+                min: "1",
+                oninput: move |ev| {
+                    let beats = ev.value.parse().unwrap_or(beats).max(1);
+                    let new_period = Period::Beats { beats, bpm };
+                    period.set(new_period);
+                    edit_period(segments, update_too.clone(), segment_id, new_period);
+                },
+            }
+            "beats at"
+            input {
+                r#type: "number",
+                name: "bpm",
+                value: "{bpm}",
+                min: "1",
+                max: "999",
+                oninput: move |ev| {
+                    let bpm = ev.value.parse().unwrap_or(bpm).max(1);
+                    let new_period = Period::Beats { beats, bpm };
+                    period.set(new_period);
+                    edit_period(segments, update_tooer.clone(), segment_id, new_period);
+                },
+            }
+            "bpm"
+        ),
+    };
+
+    cx.render(rsx! {
+        select {
+            name: "period",
+            value: "{current}",
+            oninput: move |ev| {
+                if let Some(new_period) = Period::from_name(&ev.value) {
+                    period.set(new_period);
+                    edit_period(segments, update.clone(), segment_id, new_period);
+                }
+            },
+            options
+        }
+        params
+    })
+}
+
+fn edit_kind(
+    segments: &AtomState<Option<SegMap>>,
+    update: Option<UpdateState>,
+    segment_id: &str,
+    kind: SegmentKind,
+) {
+    edit_segments(segments, update, |segments| {
+        if let Some(segment) = segments.get_mut(segment_id) {
+            segment.set_kind(kind);
+        }
+    });
+}
+
+#[allow(non_snake_case)]
+#[inline_props]
+fn KindInput(cx: Scope, segment_id: String, kind: UseState<SegmentKind>) -> Element {
+    let segments: &AtomState<Option<SegMap>> = use_atom_state(&cx, STATE_ATOM);
+    let update: Option<UpdateState> = cx.consume_context::<UpdateState>();
+    let update_too = update.clone();
+    let update_tooer = update.clone();
+
+    let current = kind.name();
+    let options = SegmentKind::ALL.iter().map(|k| {
+        let name = k.name();
+        rsx!(option {
+            key: "{name}",
+            value: "{name}",
+            "{name}"
+        })
+    });
+
+    let params = match **kind {
+        SegmentKind::Mix | SegmentKind::Chaser | SegmentKind::Progress => None,
+        SegmentKind::Rainbow { lightness } => Some(rsx!(
+            "lightness"
+            input {
+                r#type: "range",
+                name: "lightness",
+                value: "{lightness}",
+                min: "0",
+                max: format_args!("{}", SegmentKind::MAX_LIGHTNESS),
+                oninput: move |ev| {
+                    let lightness = ev.value.parse().unwrap_or(lightness);
+                    let new_kind = SegmentKind::Rainbow { lightness };
+                    kind.set(new_kind);
+                    edit_kind(segments, update_too.clone(), segment_id, new_kind);
+                },
+            }
+        )),
+        SegmentKind::OklabRainbow { lightness, chroma } => Some(rsx!(
+            "lightness"
+            input {
+                r#type: "range",
+                name: "lightness",
+                value: "{lightness}",
+                min: "0",
+                max: format_args!("{}", SegmentKind::MAX_LIGHTNESS),
+                oninput: move |ev| {
+                    let lightness = ev.value.parse().unwrap_or(lightness);
+                    let new_kind = SegmentKind::OklabRainbow { lightness, chroma };
+                    kind.set(new_kind);
+                    edit_kind(segments, update_too.clone(), segment_id, new_kind);
+                },
+            }
+            "chroma"
+            input {
+                r#type: "range",
+                name: "chroma",
+                value: "{chroma}",
+                min: "0",
+                max: format_args!("{}", SegmentKind::MAX_CHROMA),
+                oninput: move |ev| {
+                    let chroma = ev.value.parse().unwrap_or(chroma);
+                    let new_kind = SegmentKind::OklabRainbow { lightness, chroma };
+                    kind.set(new_kind);
+                    edit_kind(segments, update_tooer.clone(), segment_id, new_kind);
+                },
+            }
+        )),
+    };
+
+    cx.render(rsx! {
+        select {
+            name: "kind",
+            value: "{current}",
+            oninput: move |ev| {
+                if let Some(new_kind) = SegmentKind::from_name(&ev.value) {
+                    kind.set(new_kind);
+                    edit_kind(segments, update.clone(), segment_id, new_kind);
+                }
+            },
+            options
+        }
+        params
+    })
+}
+
 #[allow(non_snake_case)]
 #[inline_props]
 fn ColorInput(cx: Scope, segment_id: String, color_idx: usize, val: UseState<Srgb8>) -> Element {
@@ -219,7 +514,7 @@ fn ColorInput(cx: Scope, segment_id: String, color_idx: usize, val: UseState<Srg
 
 #[allow(non_snake_case)]
 #[inline_props]
-fn SegmentN(cx: Scope, seg: Segment, prime_idx: usize, fac: u32, now: u32) -> Element {
+fn SegmentN(cx: Scope, seg: Segment, prime_idx: usize, fac: u32, now: u64) -> Element {
     let segments: &AtomState<Option<SegMap>> = use_atom_state(&cx, STATE_ATOM);
 
     let id = seg.to_uuid_string();
@@ -227,21 +522,22 @@ fn SegmentN(cx: Scope, seg: Segment, prime_idx: usize, fac: u32, now: u32) -> El
 
     let cms = segments
         .as_ref()
-        .map(|ss| {
-            ss.get(&id)
-                .map(|s| format!("{:.2}", s.chill_ms() as f32 / 1000.))
-        })
+        .map(|ss| ss.get(&id).map(|s| format!("{:.2}", s.period_ms() / 1000.)))
         .flatten();
     // let cms = segments.as_ref().map(|ss| 1);
     let update: Option<UpdateState> = cx.consume_context::<UpdateState>();
     let update_too = update.clone();
     let update_tooer = update.clone();
+    let update_brightness = update.clone();
 
     let c1 = use_state(&cx, || seg.color_1().to_owned());
     let c2 = use_state(&cx, || seg.color_2().to_owned());
     let chill_idx = use_state(&cx, || seg.chill_idx());
+    let kind = use_state(&cx, || seg.kind());
+    let period = use_state(&cx, || seg.period());
 
     let len = use_state(&cx, || seg.length());
+    let brightness = use_state(&cx, || seg.brightness());
 
     // let dur_s = cms.as_ref().unwrap_or_else(|| &Some("?".to_string())).unwrap_or_else(|| "?".to_string());
     let dur_s = cms.unwrap_or_else(|| "?".to_string());
@@ -250,10 +546,12 @@ fn SegmentN(cx: Scope, seg: Segment, prime_idx: usize, fac: u32, now: u32) -> El
         div {
             class: "segment",
             h2 {"c0lors"}
-            Color2{prime_idx: *prime_idx, fac: *fac, now: *now, c1: c1.clone(), c2: c2.clone()}
+            Color2{prime_idx: *prime_idx, fac: *fac, now: *now, c1: c1.clone(), c2: c2.clone(), kind: **kind, period: **period, length: **len}
+            KindInput{segment_id: id.clone(), kind: kind.clone()}
+            br {}
             ColorInput{segment_id: id.clone(), color_idx: 0, val: c1.clone()}
             ColorInput{segment_id: id.clone(), color_idx: 1, val: c2.clone()}
-            ChillInput{segment_id: id.clone(), chill_idx:chill_idx.clone()}
+            PeriodInput{segment_id: id.clone(), period: period.clone(), chill_idx: chill_idx.clone()}
             "{dur_s}sec"
 
             h2 {"num leds"}
@@ -292,6 +590,25 @@ fn SegmentN(cx: Scope, seg: Segment, prime_idx: usize, fac: u32, now: u32) -> El
                 },
             }
 
+            h2 {"brightness"}
+            input {
+                r#type: "range",
+                name: "brightness",
+                value: "{brightness}",
+                min: "0",
+                max: "{FULL_BRIGHTNESS}",
+                oninput: move |ev| {
+                    let val = ev.value.parse().unwrap_or(DEFAULT_BRIGHTNESS);
+                    brightness.set(val);
+                    edit_segments(segments, update_brightness.clone(), |segments| {
+                        if let Some(segment) = segments.get_mut(&seg.to_uuid_string()) {
+                            segment.set_brightness(val);
+                        }
+                    });
+                },
+            }
+            "{brightness}%"
+
             br {}
 
             button {
@@ -303,12 +620,49 @@ fn SegmentN(cx: Scope, seg: Segment, prime_idx: usize, fac: u32, now: u32) -> El
 }
 
 #[derive(Clone)]
-struct UpdateState(CoroutineHandle<SegMap>);
+struct UpdateState(CoroutineHandle<Vec<Op>>, UseRef<History>);
+
+#[derive(Clone)]
+struct Seq(UseRef<u64>);
+
+/// Send `patch`, returns the server's new sequence number. A rejected patch
+/// is an error too.
+async fn send_patch(base_url: &str, patch: &Patch) -> Res<u64> {
+    let url = format!("{base_url}patch");
+    log::debug!("patching DATA at {url}");
+
+    let ser = serde_json::to_vec(patch)?;
+    let mut res = surf::post(url).body_bytes(&ser).await?;
+    let body = res.body_string().await?;
+    if !res.status().is_success() {
+        // somebody else got there first
+        return Err(format!("patch rejected: {body}").into());
+    }
+    Ok(body.trim().parse()?)
+}
+
+/// The server's segments and their sequence number
+async fn load_data(base_url: &str) -> Res<(SegMap, u64)> {
+    let url = format!("{base_url}data");
+    debug!("load DATA from {url}");
+
+    let mut res = surf::get(url).await?;
+    let body = res.body_bytes().await?;
+    let seq = res
+        .header("x-seq")
+        .and_then(|seq| seq.as_str().parse().ok())
+        .unwrap_or_default();
+
+    let loaded_segments: SegMap = schema::from_json(&body)?.into_segments();
+    debug!("loaded {loaded_segments:?} at {seq}");
+    Ok((loaded_segments, seq))
+}
 
 #[allow(non_snake_case)]
 #[inline_props]
-fn Segments(cx: Scope, fac: UseState<u32>, now: u32) -> Element {
+fn Segments(cx: Scope, fac: UseState<u32>, now: u64) -> Element {
     let global_segments = use_read(&cx, STATE_ATOM);
+    let generation = use_read(&cx, GENERATION_ATOM);
 
     let content = match global_segments {
         None => rsx!(div {"loading..."}),
@@ -317,7 +671,7 @@ fn Segments(cx: Scope, fac: UseState<u32>, now: u32) -> Element {
             let inner = segments.iter().map(|(segment_id, seg)| {
                 rsx! {
                     div {
-                        key: "seg-{segment_id}",
+                        key: "seg-{segment_id}-{generation}",
                         SegmentN{seg:seg.clone(), prime_idx: seg.chill_idx(), fac: **fac, now: *now}}
                 }
             });
@@ -328,113 +682,1040 @@ fn Segments(cx: Scope, fac: UseState<u32>, now: u32) -> Element {
     cx.render(rsx!(div { content }))
 }
 
+/// What the preview showed last frame and how it's getting from there to
+/// what it shows now, the way the board's main loop does it
+#[derive(Default)]
+struct Shown {
+    state: Option<State>,
+    transition: Option<Transition>,
+    /// Kept at the longest look while a transition runs, so LEDs a shorter
+    /// one doesn't cover fade to dark instead of disappearing
+    len: usize,
+}
+
+impl Shown {
+    fn frame(&mut self, state: State, settings: transition::Settings, now: u64) -> Vec<Led> {
+        if let Some(shown) = &self.state {
+            if shown.segments() != state.segments() {
+                let next = Transition::new(shown.clone(), now, settings.ms as u64, settings.style);
+                self.transition = Some(match self.transition.take() {
+                    Some(running) => next.over(running, now),
+                    None => next,
+                });
+            }
+        }
+        if self.transition.is_none() {
+            self.len = 0;
+        }
+        self.len = self.len.max(state.total_length());
+
+        let mut leds = vec![Led::default(); self.len];
+        state.render(now, &mut leds);
+        if let Some(running) = &mut self.transition {
+            running.blend(now, &mut leds);
+            if running.is_done(now) {
+                self.transition = None;
+            }
+        }
+        self.state = Some(state);
+        leds
+    }
+}
+
+/// The whole strip like the board shows it, segments back to back going
+/// through the board's transition on every change, with flashes on top
 #[allow(non_snake_case)]
 #[inline_props]
-fn AppOutestest(cx: Scope, base_url: String) -> Element {
-    let segments_state = use_atom_state(&cx, STATE_ATOM).to_owned();
-
-    let _doberman: &UseFuture<()> = use_future(&cx, base_url, |base_url| async move {
-        to_owned![base_url];
-        let url = base_url;
-        let inner = async move {
-            debug!("load DATA from {url}");
-            let url = format!("{url}data");
+fn StripPreview(cx: Scope, base_url: String, now: u64) -> Element {
+    let global_segments = use_read(&cx, STATE_ATOM);
+    let settings = *use_read(&cx, TRANSITION_ATOM);
+    let shown = use_ref(&cx, Shown::default);
+    let layers = use_ref(&cx, Compositor::new);
+    let flash_color = use_state(&cx, || Srgb8::new(255, 255, 255));
 
-            let mut res = surf::get(url).await?;
-            let body = res.body_bytes().await?;
+    let Some(segments) = global_segments else {
+        return None;
+    };
+    let state = State::from(segments.clone());
+    let mut leds = shown.write_silent().frame(state, settings, *now);
+    layers.write_silent().render(*now, &mut leds);
+
+    let leds = leds.iter().enumerate().map(|(i, led)| {
+        let opacity = led.brightness as f32 / FULL_BRIGHTNESS as f32;
+        rsx!(span {
+            key: "strip-led-{i}",
+            class: "led",
+            style: format_args!("background-color: #{:x}; opacity: {opacity}", led.color),
+        })
+    });
 
-            let loaded_segments: IndexMap<String, Segment> = serde_json::from_slice(&body)?;
-            debug!("loaded {loaded_segments:?}");
-            segments_state.set(Some(loaded_segments));
-            Ok(())
-        };
-        let res: Res<()> = inner.await;
-        if let Err(e) = res {
-            log::error!("could not load data: {:?}", e);
+    cx.render(rsx!(
+        div {
+            class: "strip",
+            leds
         }
-    });
+        input {
+            r#type: "color",
+            value: format_args!("#{:x}", **flash_color),
+            oninput: move |ev| {
+                if let Ok(color) = ev.value.parse() {
+                    flash_color.set(color);
+                }
+            },
+        }
+        button {
+            onclick: move |_| {
+                let flash = Flash {
+                    color: Wrap(**flash_color),
+                    ms: FLASH_MS,
+                };
+                layers.write().push(flash.layer(*now));
+                spawn_post(&cx, format!("{base_url}flash"), flash);
+            },
+            "flash"
+        }
+    ))
+}
 
-    let segments: &AtomState<Option<SegMap>> = use_atom_state(&cx, STATE_ATOM);
+async fn preset_request(base_url: &str, request: &PresetRequest) -> Res<()> {
+    let url = format!("{base_url}presets");
+    let ser = serde_json::to_vec(request)?;
+    let res = surf::post(url).body_bytes(&ser).await?;
+    if !res.status().is_success() {
+        return Err(format!("{request:?} failed: {}", res.status()).into());
+    }
+    Ok(())
+}
 
-    let content = match segments.get() {
-        Some(segments) => rsx!(App {
-            base_url: base_url.clone(),
-            segments: segments.clone()
-        }),
-        None => rsx!(h2{"loading"}),
-    };
+/// Save or delete, then reload the list
+async fn list_changing_request(base_url: String, request: PresetRequest, refresh: UseState<u32>) {
+    match preset_request(&base_url, &request).await {
+        Ok(()) => refresh.modify(|r| r + 1),
+        Err(e) => log::error!("{e:?}"),
+    }
+}
 
-    cx.render(content)
+/// Recall on the server, then pick up the result like any other edit
+async fn recall_preset(
+    base_url: String,
+    name: String,
+    fade_ms: u32,
+    segments: AtomState<Option<SegMap>>,
+    generation: AtomState<u64>,
+    update: Option<UpdateState>,
+    seq: Option<Seq>,
+) -> Res<()> {
+    preset_request(&base_url, &PresetRequest::Recall { name, fade_ms }).await?;
+    let (loaded, loaded_seq) = load_data(&base_url).await?;
+
+    if let Some(Seq(seq)) = seq {
+        *seq.write() = loaded_seq;
+    }
+    if let (Some(UpdateState(_, history)), Some(before)) = (&update, segments.get()) {
+        let at = Utc::now().timestamp_millis() as u64;
+        history.write().record(
+            &State::from(before.clone()),
+            &State::from(loaded.clone()),
+            at,
+        );
+    }
+    segments.set(Some(loaded));
+    generation.modify(|g| g + 1);
+    Ok(())
 }
 
 #[allow(non_snake_case)]
 #[inline_props]
-fn App(cx: Scope, base_url: String, segments: SegMap) -> Element {
-    let control = use_ref(&cx, || Control::new());
-    let global_segments: &AtomState<Option<SegMap>> = use_atom_state(&cx, STATE_ATOM);
+fn PresetPanel(cx: Scope, base_url: String) -> Element {
+    let segments = use_atom_state(&cx, STATE_ATOM);
+    let generation = use_atom_state(&cx, GENERATION_ATOM);
     let update: Option<UpdateState> = cx.consume_context::<UpdateState>();
-    let update_too = update.clone();
-    let update_tooest = update.clone();
-
-    let now = control.write().tick();
-    let now = use_state(&cx, || now);
-
-    let delta = use_state(&cx, || 0i64);
-    let delta_too = delta.clone();
-    let delta_est = delta.clone();
-
-    let initial_val = segments
-        .iter()
-        .next()
-        .map(|(_id, seg)| seg.chill_fac())
-        .unwrap_or(500);
-    let chill_val = use_state(&cx, || initial_val);
-    let brightness_val = use_state(&cx, || 10u8);
-    let brightness_val_too = brightness_val.clone();
-
-    to_owned![delta, control];
-    let control_too = control.clone();
-    let control_tooest = control.clone();
-    let now_too = now.clone();
-    let _old_delta = 0;
-    let _english_setter: &UseFuture<Res<_>> = use_future(&cx, &control, |c| async move {
-        let dat_now = c.with_mut(|c| c.tick());
-        let mut _new_delta = *delta;
-        now_too.set(dat_now + (*delta as u32));
-        Ok(())
-    });
-
-    let _irish_setter: &UseFuture<Res<()>> = use_future(&cx, base_url, |base_url| async move {
-        loop {
-            let url = format!("{base_url}now");
-            debug!("load NOW from {url}");
-            let req_start = Utc::now();
-            let mut res = surf::get(url).await?;
-            let req_duration = Utc::now().signed_duration_since(req_start);
-            let text = res.body_string().await?;
-            let latency_estimate = req_duration.num_milliseconds() / 2;
-            debug!("rd {latency_estimate}");
-            let server_now = text.parse::<i64>()? + latency_estimate;
-            debug!("{server_now}");
-            let ms_since_start = control_too.with(|c| c.ms_since_start());
-            let delta_value = server_now - ms_since_start as i64;
+    let seq: Option<Seq> = cx.consume_context::<Seq>();
 
-            delta_too.with_mut(|set| *set = delta_value);
+    let names = use_state(&cx, Vec::<String>::new);
+    let new_name = use_state(&cx, String::new);
+    let fade_ms = use_state(&cx, || DEFAULT_FADE_MS);
+    let refresh = use_state(&cx, || 0u32);
 
-            TimeoutFuture::new(2_000).await;
+    let _names_loader: &UseFuture<()> = use_future(&cx, (base_url, &**refresh), |(base_url, _)| {
+        to_owned![names];
+        async move {
+            let inner = async move {
+                let url = format!("{base_url}presets");
+                let loaded: Vec<String> = surf::get(url).recv_json().await?;
+                names.set(loaded);
+                Ok(())
+            };
+            let res: Res<()> = inner.await;
+            if let Err(e) = res {
+                log::error!("could not load presets: {:?}", e);
+            }
         }
     });
 
-    // TODO range + Default newtype]
-    let default_chill_fac = 150;
-
-    let mss = control_tooest.read().ms_since_start();
+    let presets = names.get().iter().map(|name| {
+        let update = update.clone();
+        let seq = seq.clone();
+        rsx!(li {
+            key: "{name}",
+            "{name} "
+            button {
+                onclick: move |_evt| {
+                    let recall = recall_preset(
+                        base_url.clone(),
+                        name.clone(),
+                        **fade_ms,
+                        segments.to_owned(),
+                        generation.to_owned(),
+                        update.clone(),
+                        seq.clone(),
+                    );
+                    cx.spawn(async move {
+                        if let Err(e) = recall.await {
+                            log::error!("could not recall preset: {:?}", e);
+                        }
+                    });
+                },
+                "recall"
+            }
+            button {
+                onclick: move |_evt| {
+                    let delete = PresetRequest::Delete { name: name.clone() };
+                    cx.spawn(list_changing_request(base_url.clone(), delete, refresh.clone()));
+                },
+                "delete"
+            }
+        })
+    });
 
-    let content = rsx! (
+    cx.render(rsx! {
+        div {
+            class: "presets",
+            h2 {"presets"}
+            ul { presets }
+            input {
+                r#type: "text",
+                name: "preset_name",
+                placeholder: "preset name",
+                value: "{new_name}",
+                oninput: move |ev| new_name.set(ev.value.clone()),
+            }
+            button {
+                disabled: "{new_name.is_empty()}",
+                onclick: move |_evt| {
+                    let save = PresetRequest::Save { name: (**new_name).clone() };
+                    cx.spawn(list_changing_request(base_url.clone(), save, refresh.clone()));
+                    new_name.set(String::new());
+                },
+                "save"
+            }
+            br {}
+            "fade "
+            input {
+                r#type: "range",
+                name: "fade_ms",
+                value: "{fade_ms}",
+                min: "0",
+                max: "10000",
+                step: "100",
+                oninput: move |ev| fade_ms.set(ev.value.parse().unwrap_or(DEFAULT_FADE_MS)),
+            }
+            "{**fade_ms as f32 / 1000.:.1}sec"
+        }
+    })
+}
+
+#[derive(serde::Deserialize)]
+struct PowerReport {
+    model: PowerModel,
+    estimate: PowerEstimate,
+}
+
+async fn post_power_model(base_url: String, model: PowerModel) -> Res<()> {
+    let url = format!("{base_url}power");
+    let ser = serde_json::to_vec(&model)?;
+    let res = surf::post(url).body_bytes(&ser).await?;
+    if !res.status().is_success() {
+        return Err(format!("saving the power model failed: {}", res.status()).into());
+    }
+    Ok(())
+}
+
+/// What the strip draws according to the board, and its supply limit
+#[allow(non_snake_case)]
+#[inline_props]
+fn PowerPanel(cx: Scope, base_url: String) -> Element {
+    let report = use_state(&cx, || None::<PowerReport>);
+
+    let _poller: &UseFuture<()> = use_future(&cx, base_url, |base_url| {
+        to_owned![report];
+        async move {
+            loop {
+                let url = format!("{base_url}power");
+                match surf::get(url).recv_json::<PowerReport>().await {
+                    Ok(loaded) => report.set(Some(loaded)),
+                    Err(e) => log::error!("could not load power: {:?}", e),
+                }
+                TimeoutFuture::new(POWER_POLL_MS).await;
+            }
+        }
+    });
+
+    let Some(PowerReport { model, estimate }) = report.get() else {
+        return cx.render(rsx!(div { class: "power", h2 {"power"} "loading" }));
+    };
+    let model = *model;
+    let drawn = estimate.drawn_ma.round();
+    let wanted = estimate.wanted_ma.round();
+    let dimmed = if estimate.scale < 1. {
+        format!(
+            ", dimmed to {:.0}% of the {wanted}mA it wants",
+            estimate.scale * 100.
+        )
+    } else {
+        String::new()
+    };
+    let limit = model.limit_ma;
+
+    cx.render(rsx!(div {
+        class: "power",
+        h2 {"power"}
+        p { "drawing {drawn}mA{dimmed}" }
+        "supply limit "
+        input {
+            r#type: "number",
+            name: "limit_ma",
+            value: "{limit}",
+            min: "0",
+            step: "100",
+            onchange: move |ev| {
+                let Ok(limit_ma) = ev.value.parse() else { return };
+                let save = post_power_model(base_url.clone(), PowerModel { limit_ma, ..model });
+                cx.spawn(async move {
+                    if let Err(e) = save.await {
+                        log::error!("{e:?}");
+                    }
+                });
+            },
+        }
+        "mA (0 for none)"
+    }))
+}
+
+async fn post_json(url: String, body: &impl serde::Serialize) -> Res<()> {
+    let ser = serde_json::to_vec(body)?;
+    let res = surf::post(&url).body_bytes(&ser).await?;
+    if !res.status().is_success() {
+        return Err(format!("POST {url} failed: {}", res.status()).into());
+    }
+    Ok(())
+}
+
+fn spawn_post(cx: &ScopeState, url: String, body: impl serde::Serialize + 'static) {
+    cx.spawn(async move {
+        if let Err(e) = post_json(url, &body).await {
+            log::error!("{e:?}");
+        }
+    });
+}
+
+const CHANNELS: [&str; 3] = ["red", "green", "blue"];
+
+/// Per device gamma, white point and measured curves, plus test patches that
+/// show the same color on the strip and here to compare
+#[allow(non_snake_case)]
+#[inline_props]
+fn CalibrationPanel(cx: Scope, base_url: String) -> Element {
+    let calibration = use_state(&cx, || None::<Calibration>);
+    let patch = use_state(&cx, || None::<Srgb8>);
+
+    let _loader: &UseFuture<()> = use_future(&cx, base_url, |base_url| {
+        to_owned![calibration];
+        async move {
+            let url = format!("{base_url}calibration");
+            match surf::get(url).recv_json::<Calibration>().await {
+                Ok(loaded) => calibration.set(Some(loaded)),
+                Err(e) => log::error!("could not load calibration: {:?}", e),
+            }
+        }
+    });
+
+    let Some(current) = calibration.get() else {
+        return cx.render(rsx!(div { class: "calibration", h2 {"calibration"} "loading" }));
+    };
+
+    let edit = move |f: &dyn Fn(&mut Calibration)| {
+        calibration.with_mut(|calibration| {
+            if let Some(calibration) = calibration {
+                f(calibration);
+            }
+        })
+    };
+    let show_patch = move |color: Option<Srgb8>| {
+        patch.set(color);
+        let body = TestPatch {
+            color: color.map(Wrap),
+        };
+        spawn_post(&cx, format!("{base_url}calibration/patch"), body);
+    };
+
+    let channels = CHANNELS.iter().enumerate().map(|(ch, name)| {
+        let gamma = current.gamma[ch];
+        let white = (current.white[ch] * 100.).round();
+        let lut = current
+            .lut
+            .as_ref()
+            .map(|lut| {
+                lut[ch]
+                    .iter()
+                    .map(|p| p.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            })
+            .unwrap_or_default();
+        rsx!(div {
+            key: "{name}",
+            "{name}: gamma "
+            input {
+                r#type: "number",
+                value: "{gamma}",
+                min: "0.1",
+                max: "5",
+                step: "0.05",
+                oninput: move |ev| {
+                    if let Ok(gamma) = ev.value.parse::<f32>() {
+                        edit(&|c| c.gamma[ch] = gamma.max(0.1));
+                    }
+                },
+            }
+            " white "
+            input {
+                r#type: "range",
+                value: "{white}",
+                min: "0",
+                max: "100",
+                oninput: move |ev| {
+                    if let Ok(white) = ev.value.parse::<f32>() {
+                        edit(&|c| c.white[ch] = (white / 100.).clamp(0., 1.));
+                    }
+                },
+            }
+            "{white}% measured "
+            input {
+                r#type: "text",
+                placeholder: "0, 0.05, 0.2, 0.5, 1",
+                value: "{lut}",
+                onchange: move |ev| {
+                    let points: Vec<f32> = ev
+                        .value
+                        .split(',')
+                        .filter_map(|p| p.trim().parse().ok())
+                        .collect();
+                    edit(&|c| {
+                        let lut = c.lut.get_or_insert_with(Default::default);
+                        lut[ch] = points.clone();
+                        // a channel without points falls back to gamma
+                        if lut.iter().all(|points| points.len() < 2) {
+                            c.lut = None;
+                        }
+                    });
+                },
+            }
+        })
+    });
+
+    let patches = TEST_PATCHES.iter().map(|&(r, g, b)| {
+        let color = Srgb8::new(r, g, b);
+        let selected = if **patch == Some(color) { "3px solid black" } else { "1px solid gray" };
+        rsx!(span {
+            key: "{r}-{g}-{b}",
+            style: "display: inline-block; width: 48px; height: 48px; margin: 2px; background: #{color:x}; border: {selected};",
+            onclick: move |_| show_patch(Some(color)),
+        })
+    });
+    let valid = current.is_valid();
+
+    cx.render(rsx!(div {
+        class: "calibration",
+        h2 {"calibration"}
+        channels
+        button {
+            disabled: "{!valid}",
+            onclick: move |_| {
+                if let Some(current) = calibration.get().clone() {
+                    spawn_post(&cx, format!("{base_url}calibration"), current);
+                }
+            },
+            "save calibration"
+        }
+        button {
+            onclick: move |_| calibration.set(Some(Calibration::default())),
+            "reset"
+        }
+        h3 {"test patches"}
+        p {"click one, the strip shows it too. adjust until they match, then save."}
+        div { patches }
+        button {
+            disabled: "{patch.is_none()}",
+            onclick: move |_| show_patch(None),
+            "back to the show"
+        }
+    }))
+}
+
+async fn post_brightness(base_url: String, brightness: u8) {
+    let url = format!("{base_url}brightness");
+    match surf::post(url).body_string(brightness.to_string()).await {
+        Ok(res) if res.status().is_success() => {}
+        Ok(res) => log::error!("setting the master brightness failed: {}", res.status()),
+        Err(e) => log::error!("setting the master brightness failed: {e:?}"),
+    }
+}
+
+/// The board's master brightness, on top of every segment's own. Sent once
+/// the slider is let go.
+#[allow(non_snake_case)]
+#[inline_props]
+fn MasterBrightness(cx: Scope, base_url: String) -> Element {
+    let brightness = use_state(&cx, || None::<u8>);
+
+    let _loader: &UseFuture<()> = use_future(&cx, base_url, |base_url| {
+        to_owned![brightness];
+        async move {
+            let inner = async move {
+                let url = format!("{base_url}brightness");
+                let loaded: u8 = surf::get(url).recv_string().await?.trim().parse()?;
+                brightness.set(Some(loaded));
+                Ok(())
+            };
+            let res: Res<()> = inner.await;
+            if let Err(e) = res {
+                log::error!("could not load master brightness: {:?}", e);
+            }
+        }
+    });
+
+    let Some(current) = **brightness else {
+        return cx.render(rsx!(h3 { "master brightness: loading" }));
+    };
+
+    cx.render(rsx!(
+        input {
+            r#type: "range",
+            name: "master_brightness",
+            value: "{current}",
+            min: "0",
+            max: "{FULL_BRIGHTNESS}",
+            oninput: move |ev| {
+                if let Ok(val) = ev.value.parse() {
+                    brightness.set(Some(val));
+                }
+            },
+            onchange: move |ev| {
+                if let Ok(val) = ev.value.parse() {
+                    cx.spawn(post_brightness(base_url.clone(), val));
+                }
+            },
+        }
+        h3 { "master brightness: {current}%" }
+    ))
+}
+
+/// How the board goes from one look to the next when segments are edited or
+/// a preset is recalled. Recalls still fade over their own duration.
+#[allow(non_snake_case)]
+#[inline_props]
+fn TransitionPanel(cx: Scope, base_url: String) -> Element {
+    let settings = use_state(&cx, || None::<transition::Settings>);
+    let preview_settings = use_atom_state(&cx, TRANSITION_ATOM).to_owned();
+
+    let _loader: &UseFuture<()> = use_future(&cx, base_url, |base_url| {
+        to_owned![settings, preview_settings];
+        async move {
+            let url = format!("{base_url}transition");
+            match surf::get(url).recv_json::<transition::Settings>().await {
+                Ok(loaded) => {
+                    settings.set(Some(loaded));
+                    preview_settings.set(loaded);
+                }
+                Err(e) => log::error!("could not load transition settings: {:?}", e),
+            }
+        }
+    });
+
+    let Some(current) = **settings else {
+        return cx.render(rsx!(h3 { "transition: loading" }));
+    };
+
+    let send = move |new: transition::Settings| {
+        settings.set(Some(new));
+        preview_settings.set(new);
+        spawn_post(&cx, format!("{base_url}transition"), new);
+    };
+    let style = current.style.name();
+    let options = Style::ALL.iter().map(|s| {
+        let name = s.name();
+        rsx!(option {
+            key: "{name}",
+            value: "{name}",
+            "{name}"
+        })
+    });
+
+    cx.render(rsx!(
+        h3 { "transition" }
+        select {
+            name: "transition_style",
+            value: "{style}",
+            oninput: move |ev| {
+                if let Some(style) = Style::from_name(&ev.value) {
+                    send(transition::Settings { style, ..current });
+                }
+            },
+            options
+        }
+        input {
+            r#type: "number",
+            name: "transition_ms",
+            value: "{current.ms}",
+            min: "0",
+            step: "100",
+            onchange: move |ev| {
+                if let Ok(ms) = ev.value.parse() {
+                    send(transition::Settings { ms, ..current });
+                }
+            },
+        }
+        "ms"
+    ))
+}
+
+async fn post_schedule(base_url: String, schedule: Schedule) -> Res<()> {
+    let url = format!("{base_url}schedule");
+    let ser = serde_json::to_vec(&schedule)?;
+    let res = surf::post(url).body_bytes(&ser).await?;
+    if !res.status().is_success() {
+        return Err(format!("saving the schedule failed: {}", res.status()).into());
+    }
+    Ok(())
+}
+
+fn edit_rule(schedule: &UseState<Option<Schedule>>, idx: usize, edit: impl FnOnce(&mut Rule)) {
+    schedule.with_mut(|schedule| {
+        if let Some(rule) = schedule.as_mut().and_then(|s| s.rules.get_mut(idx)) {
+            edit(rule);
+        }
+    });
+}
+
+/// One row of the [`ScheduleEditor`]. `rule` is only here so the row
+/// rerenders when it changes.
+#[allow(non_snake_case)]
+#[inline_props]
+fn RuleInput(cx: Scope, schedule: UseState<Option<Schedule>>, idx: usize, rule: Rule) -> Element {
+    let idx = *idx;
+
+    let days = Days::NAMES.iter().enumerate().map(|(day, name)| {
+        let checked = rule.days.contains(day as u8);
+        rsx!(label {
+            key: "{name}",
+            input {
+                r#type: "checkbox",
+                checked: "{checked}",
+                onclick: move |_| edit_rule(schedule, idx, |rule| rule.days = rule.days.toggled(day as u8)),
+            }
+            "{name}"
+        })
+    });
+
+    let at_options = At::KINDS
+        .iter()
+        .map(|name| rsx!(option { key: "{name}", value: "{name}", "{name}" }));
+    let at_params = match rule.at {
+        At::Time { hour, minute } => rsx!(input {
+            r#type: "time",
+            value: "{hour:02}:{minute:02}",
+            oninput: move |ev| {
+                let mut parts = ev.value.split(':').map(|part| part.parse::<u8>());
+                if let (Some(Ok(hour)), Some(Ok(minute))) = (parts.next(), parts.next()) {
+                    edit_rule(schedule, idx, |rule| rule.at = At::Time { hour, minute });
+                }
+            },
+        }),
+        At::Sunrise { offset_min } | At::Sunset { offset_min } => rsx!(
+            input {
+                r#type: "number",
+                value: "{offset_min}",
+                min: "-720",
+                max: "720",
+                oninput: move |ev| {
+                    let offset_min = ev.value.parse().unwrap_or(offset_min);
+                    edit_rule(schedule, idx, |rule| {
+                        rule.at = match rule.at {
+                            At::Sunrise { .. } => At::Sunrise { offset_min },
+                            _ => At::Sunset { offset_min },
+                        }
+                    });
+                },
+            }
+            "min after"
+        ),
+    };
+
+    let action_options = Action::KINDS
+        .iter()
+        .map(|name| rsx!(option { key: "{name}", value: "{name}", "{name}" }));
+    let action_params = match &rule.action {
+        Action::Preset { name, .. } => rsx!(input {
+            r#type: "text",
+            placeholder: "preset name",
+            value: "{name}",
+            oninput: move |ev| edit_rule(schedule, idx, |rule| {
+                if let Action::Preset { name, .. } = &mut rule.action {
+                    *name = ev.value.clone();
+                }
+            }),
+        }),
+        Action::Dim { level, .. } => rsx!(
+            input {
+                r#type: "number",
+                value: "{level}",
+                min: "0",
+                max: "100",
+                oninput: move |ev| edit_rule(schedule, idx, |rule| {
+                    if let Action::Dim { level, .. } = &mut rule.action {
+                        *level = ev.value.parse().unwrap_or(*level).min(100);
+                    }
+                }),
+            }
+            "%"
+        ),
+        _ => rsx!(""),
+    };
+    let fade_s = rule.action.fade_ms() / 1000;
+    let enabled = rule.enabled;
+
+    cx.render(rsx!(li {
+        input {
+            r#type: "checkbox",
+            checked: "{enabled}",
+            onclick: move |_| edit_rule(schedule, idx, |rule| rule.enabled = !rule.enabled),
+        }
+        days
+        select {
+            value: "{rule.at.name()}",
+            oninput: move |ev| {
+                if let Some(at) = At::from_name(&ev.value) {
+                    edit_rule(schedule, idx, |rule| rule.at = at);
+                }
+            },
+            at_options
+        }
+        at_params
+        select {
+            value: "{rule.action.name()}",
+            oninput: move |ev| {
+                if let Some(action) = Action::from_name(&ev.value) {
+                    edit_rule(schedule, idx, |rule| rule.action = action);
+                }
+            },
+            action_options
+        }
+        action_params
+        "fade "
+        input {
+            r#type: "number",
+            value: "{fade_s}",
+            min: "0",
+            oninput: move |ev| {
+                let fade_s: u32 = ev.value.parse().unwrap_or(fade_s);
+                edit_rule(schedule, idx, |rule| rule.action.set_fade_ms(fade_s.saturating_mul(1000)));
+            },
+        }
+        "sec"
+        button {
+            onclick: move |_| schedule.with_mut(|schedule| {
+                if let Some(schedule) = schedule {
+                    schedule.rules.remove(idx);
+                }
+            }),
+            "remove"
+        }
+    }))
+}
+
+/// Edits a copy of the board's [`Schedule`] until "save" sends it
+#[allow(non_snake_case)]
+#[inline_props]
+fn ScheduleEditor(cx: Scope, base_url: String) -> Element {
+    let schedule = use_state(&cx, || None::<Schedule>);
+
+    let _loader: &UseFuture<()> = use_future(&cx, base_url, |base_url| {
+        to_owned![schedule];
+        async move {
+            let inner = async move {
+                let url = format!("{base_url}schedule");
+                let loaded: Schedule = surf::get(url).recv_json().await?;
+                schedule.set(Some(loaded));
+                Ok(())
+            };
+            let res: Res<()> = inner.await;
+            if let Err(e) = res {
+                log::error!("could not load schedule: {:?}", e);
+            }
+        }
+    });
+
+    let Some(current) = schedule.get() else {
+        return cx.render(rsx!(div { class: "schedule", h2 {"schedule"} "loading" }));
+    };
+
+    let rules = current.rules.iter().enumerate().map(|(idx, rule)| {
+        rsx!(RuleInput {
+            key: "{idx}",
+            schedule: schedule.clone(),
+            idx: idx,
+            rule: rule.clone(),
+        })
+    });
+    let utc_offset_min = current.utc_offset_min;
+    let (latitude, longitude) = current
+        .location
+        .map_or((String::new(), String::new()), |l| {
+            (l.latitude.to_string(), l.longitude.to_string())
+        });
+
+    let edit_location = move |value: &str, lat: bool| {
+        let value = value.parse::<f32>().ok();
+        schedule.with_mut(|schedule| {
+            let Some(schedule) = schedule else { return };
+            let mut location = schedule.location.unwrap_or(Location {
+                latitude: 0.,
+                longitude: 0.,
+            });
+            match (value, lat) {
+                (Some(value), true) => location.latitude = value.clamp(-90., 90.),
+                (Some(value), false) => location.longitude = value.clamp(-180., 180.),
+                // clearing either forgets the location
+                (None, _) => {
+                    schedule.location = None;
+                    return;
+                }
+            }
+            schedule.location = Some(location);
+        });
+    };
+
+    cx.render(rsx! {
+        div {
+            class: "schedule",
+            h2 {"schedule"}
+            ul { rules }
+            button {
+                onclick: move |_| schedule.with_mut(|schedule| {
+                    if let Some(schedule) = schedule {
+                        schedule.rules.push(Rule {
+                            days: Days::WEEKDAYS,
+                            at: At::Time { hour: 7, minute: 0 },
+                            action: Action::from_name("alarm").unwrap(),
+                            enabled: true,
+                        });
+                    }
+                }),
+                "add rule"
+            }
+            br {}
+            "utc offset "
+            input {
+                r#type: "number",
+                value: "{utc_offset_min}",
+                min: "-720",
+                max: "840",
+                oninput: move |ev| {
+                    let offset = ev.value.parse().unwrap_or(utc_offset_min);
+                    schedule.with_mut(|schedule| {
+                        if let Some(schedule) = schedule {
+                            schedule.utc_offset_min = offset;
+                        }
+                    });
+                },
+            }
+            "min"
+            button {
+                onclick: move |_| {
+                    let offset = chrono::Local::now().offset().local_minus_utc() / 60;
+                    schedule.with_mut(|schedule| {
+                        if let Some(schedule) = schedule {
+                            schedule.utc_offset_min = offset as i16;
+                        }
+                    });
+                },
+                "use this browser's"
+            }
+            br {}
+            "location (for sunrise and sunset) "
+            input {
+                r#type: "number",
+                placeholder: "latitude",
+                value: "{latitude}",
+                step: "any",
+                oninput: move |ev| edit_location(&ev.value, true),
+            }
+            input {
+                r#type: "number",
+                placeholder: "longitude",
+                value: "{longitude}",
+                step: "any",
+                oninput: move |ev| edit_location(&ev.value, false),
+            }
+            br {}
+            button {
+                onclick: move |_| {
+                    if let Some(current) = schedule.get().clone() {
+                        let save = post_schedule(base_url.clone(), current);
+                        cx.spawn(async move {
+                            if let Err(e) = save.await {
+                                log::error!("{e:?}");
+                            }
+                        });
+                    }
+                },
+                "save schedule"
+            }
+        }
+    })
+}
+
+#[allow(non_snake_case)]
+#[inline_props]
+fn AppOutestest(cx: Scope, base_url: String) -> Element {
+    let segments_state = use_atom_state(&cx, STATE_ATOM).to_owned();
+    let seq = cx.consume_context::<Seq>();
+
+    let _doberman: &UseFuture<()> = use_future(&cx, base_url, |base_url| async move {
+        to_owned![base_url];
+        let inner = async move {
+            let (loaded_segments, loaded_seq) = load_data(&base_url).await?;
+            if let Some(Seq(seq)) = seq {
+                *seq.write() = loaded_seq;
+            }
+            segments_state.set(Some(loaded_segments));
+            Ok(())
+        };
+        let res: Res<()> = inner.await;
+        if let Err(e) = res {
+            log::error!("could not load data: {:?}", e);
+        }
+    });
+
+    let segments: &AtomState<Option<SegMap>> = use_atom_state(&cx, STATE_ATOM);
+
+    let content = match segments.get() {
+        Some(segments) => rsx!(App {
+            base_url: base_url.clone(),
+            segments: segments.clone()
+        }),
+        None => rsx!(h2{"loading"}),
+    };
+
+    cx.render(content)
+}
+
+#[allow(non_snake_case)]
+#[inline_props]
+fn App(cx: Scope, base_url: String, segments: SegMap) -> Element {
+    let control = use_ref(&cx, || Control::new());
+    let global_segments: &AtomState<Option<SegMap>> = use_atom_state(&cx, STATE_ATOM);
+    let update: Option<UpdateState> = cx.consume_context::<UpdateState>();
+    let update_too = update.clone();
+    let update_undo = update.clone();
+    let update_redo = update.clone();
+    let update_keys = update.clone();
+    let generation = use_atom_state(&cx, GENERATION_ATOM);
+    let (can_undo, can_redo) = update
+        .as_ref()
+        .map(|UpdateState(_, history)| {
+            let history = history.read();
+            (history.can_undo(), history.can_redo())
+        })
+        .unwrap_or_default();
+
+    let clock_sync = use_ref(&cx, || ClockSync::new(SyncConfig::default()));
+
+    let now = control.write().tick();
+    let now = use_state(&cx, || now);
+
+    let initial_val = segments
+        .iter()
+        .next()
+        .map(|(_id, seg)| seg.chill_fac())
+        .unwrap_or(500);
+    let chill_val = use_state(&cx, || initial_val);
+
+    to_owned![control, clock_sync];
+    let control_too = control.clone();
+    let control_tooest = control.clone();
+    let clock_sync_too = clock_sync.clone();
+    let now_too = now.clone();
+    let _english_setter: &UseFuture<Res<_>> = use_future(&cx, &control, |c| async move {
+        let dat_now = c.with_mut(|c| c.tick());
+        now_too.set(clock_sync.with_mut(|sync| sync.server_time(dat_now)));
+        Ok(())
+    });
+
+    let _irish_setter: &UseFuture<Res<()>> = use_future(&cx, base_url, |base_url| async move {
+        loop {
+            let url = format!("{base_url}now");
+            debug!("load NOW from {url}");
+            let sent = control_too.with_mut(|c| c.tick());
+            let mut res = surf::get(url).await?;
+            let text = res.body_string().await?;
+            let received = control_too.with_mut(|c| c.tick());
+            let sample = Sample::new(sent, text.parse()?, received);
+            debug!("rtt {}, offset {}", sample.rtt(), sample.offset());
+
+            let samples = clock_sync_too.with_mut(|sync| {
+                sync.add_sample(sample);
+                sync.sample_count()
+            });
+
+            // a quick burst first so the minimum RTT filter has something to pick from
+            let wait = if samples < SYNC_BURST_SAMPLES {
+                SYNC_BURST_INTERVAL_MS
+            } else {
+                SYNC_INTERVAL_MS
+            };
+            TimeoutFuture::new(wait).await;
+        }
+    });
+
+    // TODO range + Default newtype]
+    let default_chill_fac = 150;
+
+    let mss = control_tooest.read().ms_since_start();
+    let (delta_est, drift_est) = clock_sync_too.with(|sync| {
+        (
+            sync.offset_at(mss).unwrap_or_default(),
+            sync.drift_ppm().round(),
+        )
+    });
+
+    let content = rsx! (
      div {
         style: "text-align: center;",
+        tabindex: "0",
+        // ctrl+z, ctrl+shift+z and ctrl+y, cmd on macs
+        onkeydown: move |evt| {
+            if evt.ctrl_key || evt.meta_key {
+                match evt.key.to_lowercase().as_str() {
+                    "z" => undo_redo(global_segments, generation, update_keys.clone(), evt.shift_key),
+                    "y" => undo_redo(global_segments, generation, update_keys.clone(), true),
+                    _ => {}
+                }
+            }
+        },
         h1 { "LED zeppelin" }
-        p { "our time: {now}, mss: {mss}, delta: {delta_est}"}
+        p { "our time: {now}, mss: {mss}, delta: {delta_est}, drift: {drift_est}ppm"}
         form {
             input {
                 r#type: "range",
@@ -455,40 +1736,36 @@ fn App(cx: Scope, base_url: String, segments: SegMap) -> Element {
             },
             }
             h3 { "chill: {chill_val}"}
-
-            input {
-                r#type: "range",
-                name: "brightness",
-                value: "{brightness_val}",
-                min: "1",
-                max: "128",
-                oninput: move |ev| {
-                let val = ev.value.clone();
-                let brightness = val.parse().unwrap_or(10u8);
-                brightness_val.set(brightness);
-                edit_segments(global_segments, update_tooest.clone(), |segments| {
-                    for (_id, segment) in segments.iter_mut() {
-                        segment.set_brightness(brightness);
-                    }
-                });
-
-                },
-            }
         }
-        h3 { "brightness: {brightness_val}"}
+        MasterBrightness {base_url: base_url.clone()}
+        TransitionPanel {base_url: base_url.clone()}
+        PowerPanel {base_url: base_url.clone()}
+        CalibrationPanel {base_url: base_url.clone()}
 
 
+        StripPreview {base_url: base_url.clone(), now: **now}
         Segments {fac: chill_val.clone(), now: **now}
+        PresetPanel {base_url: base_url.clone()}
+        ScheduleEditor {base_url: base_url.clone()}
         button {
             onclick: move |_evt| edit_segments(global_segments, update_too.clone(),  |segments| {
                 let mut seg = Segment::default();
                 seg.set_chill_fac(**chill_val);
-                seg.set_brightness(*brightness_val_too);
                 segments.insert(seg.to_uuid_string(), seg);
 
             }),
             "new"
         }
+        button {
+            disabled: "{!can_undo}",
+            onclick: move |_evt| undo_redo(global_segments, generation, update_undo.clone(), false),
+            "undo"
+        }
+        button {
+            disabled: "{!can_redo}",
+            onclick: move |_evt| undo_redo(global_segments, generation, update_redo.clone(), true),
+            "redo"
+        }
     }
     );
     cx.render(content)
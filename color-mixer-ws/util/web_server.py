@@ -4,11 +4,21 @@ from http.server import BaseHTTPRequestHandler, HTTPServer, SimpleHTTPRequestHan
 from sys import argv
 import time
 import os
+import json
 
 hostName = "localhost"
 serverPort = 8081
 
 start = time.time()
+seq = 0
+presets = {}
+schedule = {"rules": [], "utc_offset_min": 0, "location": None}
+master_brightness = 100
+transition = {"style": "crossfade", "ms": 500}
+calibration = {"gamma": [2.2, 2.2, 2.2], "white": [1.0, 1.0, 1.0], "lut": None}
+# there's no strip here, so the estimate never changes
+power = {"model": {"channel_ma": 20.0, "idle_ma": 1.0, "limit_ma": 2000},
+         "estimate": {"wanted_ma": 60.0, "drawn_ma": 60.0, "scale": 1.0}}
 
 data = """{"2e2765f4-7fb7-4a8a-b61f-6dc874db15e9":{"uuid":"2e2765f4-7fb7-4a8a-b61f-6dc874db15e9","length":1,"bgr":false,"colors":[{"red":255,"green":150,"blue":0},{"red":255,"green":10,"blue":120}],"chill_idx":0,"chill_fac":100},"ae98126f-915e-470d-93a0-4b40a853a0c8":{"uuid":"ae98126f-915e-470d-93a0-4b40a853a0c8","length":1,"bgr":false,"colors":[{"red":166,"green":0,"blue":255},{"red":2,"green":192,"blue":192}],"chill_idx":1,"chill_fac":100},"bdc6cf10-c223-4c9e-9b94-88495d81617a":{"uuid":"bdc6cf10-c223-4c9e-9b94-88495d81617a","length":1,"bgr":false,"colors":[{"red":20,"green":200,"blue":141},{"red":200,"green":176,"blue":20}],"chill_idx":2,"chill_fac":100},"cba45b51-fd9a-48f4-95b3-070099050887":{"uuid":"cba45b51-fd9a-48f4-95b3-070099050887","length":1,"bgr":false,"colors":[{"red":200,"green":20,"blue":30},{"red":200,"green":200,"blue":10}],"chill_idx":3,"chill_fac":100}}""".encode(
     'utf-8')
@@ -17,6 +27,29 @@ data = '{"1d3bd22e-3680-40aa-87e9-da3bdee55c4e":{"uuid":"1d3bd22e-3680-40aa-87e9
     'utf-8')
 
 
+def apply_patch(data, patch):
+    """Apply a Patch's ops to the stored /data, either schema version"""
+    state = json.loads(data)
+    segments = state['segments'] if 'version' in state else state
+    for op in patch['ops']:
+        kind = op['op']
+        if kind == 'insert':
+            segments[op['segment']['uuid']] = op['segment']
+        elif kind == 'remove':
+            segments.pop(op['id'], None)
+        elif kind == 'move':
+            items = list(segments.items())
+            item = next(i for i in items if i[0] == op['id'])
+            items.remove(item)
+            items.insert(op['to'], item)
+            segments.clear()
+            segments.update(items)
+        elif kind == 'set':
+            field = op['field']
+            segments[op['id']][field['name']] = field['value']
+    return json.dumps(state).encode('utf-8')
+
+
 class MyServer(SimpleHTTPRequestHandler):
     # protocol_version = "HTTP/1.1"
 
@@ -33,7 +66,8 @@ class MyServer(SimpleHTTPRequestHandler):
         self.end_headers()
 
     def do_POST(self):
-        self.send_response(204)
+        # only /patch answers with something, the new seq
+        self.send_response(200 if self.path == '/patch' else 204)
         self.send_header('Access-Control-Allow-Origin', '*')
         self.send_header('Access-Control-Allow-Methods',
                          'POST')
@@ -43,7 +77,48 @@ class MyServer(SimpleHTTPRequestHandler):
 
         self.end_headers()
         self.flush_headers()
-        global data
+        global data, seq, schedule, master_brightness, calibration, transition
+        seq += 1
+        if self.path == '/patch':
+            # applied like the board does, minus the stale check
+            data = apply_patch(data, json.loads(self.rfile.read()))
+            self.wfile.write(f'{seq}'.encode('ascii'))
+            return
+        if self.path == '/presets':
+            # same as the board, minus the crossfade
+            req = json.loads(self.rfile.read())
+            name = req['name']
+            if req['action'] == 'save':
+                presets[name] = data
+            elif req['action'] == 'recall':
+                data = presets.get(name, data)
+            elif req['action'] == 'delete':
+                presets.pop(name, None)
+            return
+        if self.path == '/calibration':
+            calibration = json.loads(self.rfile.read())
+            return
+        if self.path == '/calibration/patch':
+            # no strip to show it on
+            print(self.rfile.read())
+            return
+        if self.path == '/flash':
+            # no strip to show it on either
+            print(self.rfile.read())
+            return
+        if self.path == '/power':
+            power['model'] = json.loads(self.rfile.read())
+            return
+        if self.path == '/brightness':
+            master_brightness = int(self.rfile.read())
+            return
+        if self.path == '/schedule':
+            schedule = json.loads(self.rfile.read())
+            print(schedule)
+            return
+        if self.path == '/transition':
+            transition = json.loads(self.rfile.read())
+            return
         data = self.rfile.read()
         self.log_message("read")
         self.log_message("response")
@@ -58,6 +133,8 @@ class MyServer(SimpleHTTPRequestHandler):
             self.send_header('Access-Control-Allow-Origin', '*')
             self.send_header('Access-Control-Allow-Methods', '*')
             self.send_header('Access-Control-Allow-Headers', '*')
+            self.send_header('Access-Control-Expose-Headers', 'x-seq')
+            self.send_header('x-seq', f'{seq}')
 
             self.end_headers()
             self.wfile.write(wat)
@@ -68,6 +145,18 @@ class MyServer(SimpleHTTPRequestHandler):
 
         elif self.path == '/data':
             get(data)
+        elif self.path == '/presets':
+            get(json.dumps(sorted(presets)).encode('utf-8'))
+        elif self.path == '/calibration':
+            get(json.dumps(calibration).encode('utf-8'))
+        elif self.path == '/power':
+            get(json.dumps(power).encode('utf-8'))
+        elif self.path == '/brightness':
+            get(f'{master_brightness}'.encode('ascii'))
+        elif self.path == '/schedule':
+            get(json.dumps(schedule).encode('utf-8'))
+        elif self.path == '/transition':
+            get(json.dumps(transition).encode('utf-8'))
         else:
             super().do_GET()
 
@@ -0,0 +1,53 @@
+//! `cargo bench` on the host: float vs fixed point effects over a 512 LED
+//! strip. The C3 has no FPU at all, so the gap there is a lot wider.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use effects::fixed;
+use smart_leds::RGB8;
+
+const LEDS: usize = 512;
+const FRAMES: u32 = 200;
+
+fn time(mut frame: impl FnMut(u32, &mut [RGB8])) -> Duration {
+    let mut data = [RGB8::default(); LEDS];
+    let start = Instant::now();
+    for i in 0..FRAMES {
+        frame(i, &mut data);
+        black_box(&data);
+    }
+    start.elapsed() / FRAMES
+}
+
+fn compare(name: &str, float: impl FnMut(u32, &mut [RGB8]), fixed: impl FnMut(u32, &mut [RGB8])) {
+    let (float, fixed) = (time(float), time(fixed));
+    println!(
+        "{:<10} float {:>10.1?}/frame  fixed {:>10.1?}/frame  {:.1}x",
+        name,
+        float,
+        fixed,
+        float.as_secs_f64() / fixed.as_secs_f64()
+    );
+}
+
+fn main() {
+    if cfg!(feature = "fixed-point") {
+        println!("fixed-point is on, both sides are fixed point");
+    }
+    compare(
+        "rainborrow",
+        |i, data| effects::rainborrow_slice(i as u8, 0.5, data),
+        |i, data| fixed::rainborrow_slice(i as u8, 0.5, data),
+    );
+    compare(
+        "rainborrok",
+        |i, data| effects::rainborrok_slice(i as u16, 0.7, 0.12, data),
+        |i, data| fixed::rainborrok_slice(i as u16, 0.7, 0.12, data),
+    );
+    compare(
+        "chaser",
+        |i, data| effects::chaser_slice(i as u16, data),
+        |i, data| fixed::chaser_slice(i as u16, data),
+    );
+}
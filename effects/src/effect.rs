@@ -0,0 +1,327 @@
+//! One interface for all the effects.
+//!
+//! The free functions all take their own kind of time (`u8` ticks, `u16`
+//! degrees, ...). An [`Effect`] takes milliseconds and keeps whatever it
+//! needs between frames in `self`, so any of them can be swapped for another
+//! at runtime, split across a strip with [`Split`], or driven by an
+//! [`Animator`].
+
+use palette::{FromColor, Hsl};
+use smart_leds::{SmartLedsWrite, RGB8};
+
+use crate::{
+    chaser_slice, expanding_circle_layout, layout::Layout, progress_slice, rainborrok_slice,
+    rainborrow_slice,
+};
+
+pub trait Effect {
+    /// Render the frame at `time_ms` into `data`, which may be any length
+    fn render(&mut self, time_ms: u64, data: &mut [RGB8]);
+}
+
+impl<E: Effect + ?Sized> Effect for &mut E {
+    fn render(&mut self, time_ms: u64, data: &mut [RGB8]) {
+        (**self).render(time_ms, data)
+    }
+}
+
+/// How far into a `period_ms` cycle `time_ms` is, in `0..steps`
+fn phase(time_ms: u64, period_ms: u64, steps: u64) -> u64 {
+    let period_ms = period_ms.max(1);
+    (time_ms % period_ms) * steps / period_ms
+}
+
+/// [`rainborrow_slice`], once around the hue circle per `period_ms`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rainbow {
+    pub period_ms: u64,
+    pub brightness: f32,
+}
+
+impl Effect for Rainbow {
+    fn render(&mut self, time_ms: u64, data: &mut [RGB8]) {
+        let time = phase(time_ms, self.period_ms, 256) as u8;
+        rainborrow_slice(time, self.brightness, data)
+    }
+}
+
+/// [`rainborrok_slice`], once around per `period_ms`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OklabRainbow {
+    pub period_ms: u64,
+    pub lightness: f32,
+    pub chroma: f32,
+}
+
+impl Effect for OklabRainbow {
+    fn render(&mut self, time_ms: u64, data: &mut [RGB8]) {
+        let time = phase(time_ms, self.period_ms, 360) as u16;
+        rainborrok_slice(time, self.lightness, self.chroma, data)
+    }
+}
+
+/// [`chaser_slice`], one LED further every `step_ms`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Chaser {
+    pub step_ms: u64,
+}
+
+impl Effect for Chaser {
+    fn render(&mut self, time_ms: u64, data: &mut [RGB8]) {
+        // chaser_slice only clears the LED behind it, which misses when a
+        // frame skips steps
+        data.fill(RGB8::default());
+        chaser_slice((time_ms / self.step_ms.max(1)) as u16, data)
+    }
+}
+
+/// [`progress_slice`], empty to full per `period_ms`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Progress {
+    pub period_ms: u64,
+}
+
+impl Effect for Progress {
+    fn render(&mut self, time_ms: u64, data: &mut [RGB8]) {
+        data.fill(RGB8::default());
+        let time = phase(time_ms, self.period_ms, u16::MAX as u64 + 1) as u16;
+        progress_slice(time, data)
+    }
+}
+
+/// [`expanding_circle_layout`], one tick per `step_ms`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ExpandingCircle<'a> {
+    pub layout: Layout<'a>,
+    pub step_ms: u64,
+    pub dampen: u8,
+    pub amplify: u8,
+    pub grb: bool,
+}
+
+impl Effect for ExpandingCircle<'_> {
+    fn render(&mut self, time_ms: u64, data: &mut [RGB8]) {
+        data.fill(RGB8::default());
+        let time = (time_ms / self.step_ms.max(1)) as u8;
+        expanding_circle_layout(
+            time,
+            self.dampen,
+            self.amplify,
+            &self.layout,
+            data,
+            self.grb,
+        );
+    }
+}
+
+/// The hue spread [`fader`](crate::fader) shows, standing still
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Fader;
+
+impl Effect for Fader {
+    fn render(&mut self, _time_ms: u64, data: &mut [RGB8]) {
+        for (j, led) in data.iter_mut().enumerate() {
+            let h = ((j * 7) % 360) as f32 - 180.;
+            let rgb = palette::Srgb::from_color(Hsl::new(h, 0.96, 0.4)).into_format::<u8>();
+            *led = RGB8 {
+                r: rgb.red,
+                g: rgb.green,
+                b: rgb.blue,
+            };
+        }
+    }
+}
+
+/// `first` on the LEDs before `at`, `second` on the rest
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Split<A, B> {
+    pub first: A,
+    pub second: B,
+    pub at: usize,
+}
+
+impl<A: Effect, B: Effect> Effect for Split<A, B> {
+    fn render(&mut self, time_ms: u64, data: &mut [RGB8]) {
+        let (first, second) = data.split_at_mut(self.at.min(data.len()));
+        self.first.render(time_ms, first);
+        self.second.render(time_ms, second);
+    }
+}
+
+/// Drives an effect into a strip at a fixed frame rate without blocking.
+/// Call [`poll`](Self::poll) as often as convenient, it renders and writes
+/// only when a frame is due. The effect is passed in each time, so switching
+/// to another one is just passing that instead.
+pub struct Animator<W, const NUM_LEDS: usize> {
+    writer: W,
+    data: [RGB8; NUM_LEDS],
+    frame_ms: u64,
+    next: Option<u64>,
+}
+
+impl<W, const NUM_LEDS: usize> Animator<W, NUM_LEDS>
+where
+    W: SmartLedsWrite,
+    W::Color: From<RGB8>,
+{
+    pub fn new(writer: W, frame_ms: u64) -> Self {
+        Self {
+            writer,
+            data: [RGB8::default(); NUM_LEDS],
+            frame_ms: frame_ms.max(1),
+            next: None,
+        }
+    }
+
+    /// Render and write a frame if one is due at `now_ms`. Returns whether it
+    /// did.
+    pub fn poll(&mut self, effect: &mut dyn Effect, now_ms: u64) -> Result<bool, W::Error> {
+        match self.next {
+            Some(next) if now_ms < next => return Ok(false),
+            // late frames don't pile up, the next one is a whole frame away
+            _ => self.next = Some(now_ms + self.frame_ms),
+        }
+        effect.render(now_ms, &mut self.data);
+        self.writer.write(self.data.iter().copied())?;
+        Ok(true)
+    }
+
+    /// The last frame written
+    pub fn data(&self) -> &[RGB8; NUM_LEDS] {
+        &self.data
+    }
+
+    pub fn writer(&mut self) -> &mut W {
+        &mut self.writer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLACK: RGB8 = RGB8 { r: 0, g: 0, b: 0 };
+
+    fn rainbow() -> Rainbow {
+        Rainbow {
+            period_ms: 1_000,
+            brightness: 0.5,
+        }
+    }
+
+    /// Keeps the last frame and counts writes
+    #[derive(Default)]
+    struct Strip {
+        last: [RGB8; 8],
+        writes: usize,
+    }
+
+    impl SmartLedsWrite for Strip {
+        type Error = ();
+        type Color = RGB8;
+
+        fn write<T, I>(&mut self, iterator: T) -> Result<(), ()>
+        where
+            T: Iterator<Item = I>,
+            I: Into<RGB8>,
+        {
+            for (led, color) in self.last.iter_mut().zip(iterator) {
+                *led = color.into();
+            }
+            self.writes += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn every_effect_takes_any_length() {
+        let mut effects: [&mut dyn Effect; 7] = [
+            &mut rainbow(),
+            &mut OklabRainbow {
+                period_ms: 1_000,
+                lightness: 0.7,
+                chroma: 0.1,
+            },
+            &mut Chaser { step_ms: 50 },
+            &mut Progress { period_ms: 1_000 },
+            &mut ExpandingCircle {
+                layout: Layout::new(7, 7),
+                step_ms: 20,
+                dampen: 1,
+                amplify: 1,
+                grb: false,
+            },
+            &mut Fader,
+            &mut Split {
+                first: Chaser { step_ms: 10 },
+                second: rainbow(),
+                at: 3,
+            },
+        ];
+        for effect in effects.iter_mut() {
+            for len in [0, 1, 49, 300] {
+                let mut data = [BLACK; 300];
+                for t in [0, 17, 999, 123_456] {
+                    effect.render(t, &mut data[..len]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn matches_the_free_functions() {
+        let mut expected = [BLACK; 10];
+        rainborrow_slice(64, 0.5, &mut expected);
+        let mut data = [BLACK; 10];
+        rainbow().render(250, &mut data);
+        assert_eq!(data, expected);
+
+        // time wraps with the period
+        rainbow().render(1_250, &mut data);
+        assert_eq!(data, expected);
+    }
+
+    #[test]
+    fn chaser_leaves_no_trail_when_skipping() {
+        let mut chaser = Chaser { step_ms: 10 };
+        let mut data = [BLACK; 10];
+        chaser.render(10, &mut data);
+        chaser.render(50, &mut data);
+        assert_eq!(data.iter().filter(|c| **c != BLACK).count(), 1);
+        assert_ne!(data[5], BLACK);
+    }
+
+    #[test]
+    fn split_renders_both_sides() {
+        let mut split = Split {
+            first: Progress { period_ms: 100 },
+            second: Fader,
+            at: 4,
+        };
+        let mut data = [BLACK; 8];
+        split.render(99, &mut data);
+        let mut fader = [BLACK; 4];
+        Fader.render(0, &mut fader);
+        assert_eq!(data[4..], fader);
+        assert!(data[..3].iter().all(|c| *c == RGB8::new(64, 64, 64)));
+    }
+
+    #[test]
+    fn animator_writes_only_due_frames() {
+        let mut animator: Animator<Strip, 8> = Animator::new(Strip::default(), 10);
+        let mut effect = rainbow();
+        assert_eq!(animator.poll(&mut effect, 0), Ok(true));
+        assert_eq!(animator.poll(&mut effect, 5), Ok(false));
+        assert_eq!(animator.poll(&mut effect, 10), Ok(true));
+        assert_eq!(animator.writer().writes, 2);
+        let shown = *animator.data();
+        assert_eq!(animator.writer().last, shown);
+
+        // swapping effects is passing another one
+        let mut fader = Fader;
+        assert_eq!(animator.poll(&mut fader, 25), Ok(true));
+        let mut expected = [BLACK; 8];
+        Fader.render(25, &mut expected);
+        assert_eq!(animator.writer().last, expected);
+    }
+}
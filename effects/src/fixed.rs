@@ -0,0 +1,322 @@
+//! Integer color math, for targets without an FPU.
+//!
+//! The ESP32-C3 does floats in software, and a `palette` conversion per LED
+//! per frame adds up. These stay within a few steps of the float versions
+//! (the tests say how close) using integer math and two small tables. With the
+//! `fixed-point` feature [`rainborrow_slice`](crate::rainborrow_slice),
+//! [`rainborrok_slice`](crate::rainborrok_slice) and
+//! [`chaser_slice`](crate::chaser_slice) use them.
+//!
+//! Hues are `0..1536` for HSV/HSL, six sectors of 256, and `u16` turns for
+//! sines and Oklch. Fractions are Q16, `65536` is `1.0`.
+
+use smart_leds::RGB8;
+
+/// `sin` over the first quarter turn in 64 steps, Q15
+const QUARTER_SINE: [i32; 65] = [
+    0, 804, 1608, 2410, 3212, 4011, 4808, 5602, 6393, 7179, 7962, 8739, 9512, 10278, 11039, 11793,
+    12539, 13279, 14010, 14732, 15446, 16151, 16846, 17530, 18204, 18868, 19519, 20159, 20787,
+    21403, 22005, 22594, 23170, 23731, 24279, 24811, 25329, 25832, 26319, 26790, 27245, 27683,
+    28105, 28510, 28898, 29268, 29621, 29956, 30273, 30571, 30852, 31113, 31356, 31580, 31785,
+    31971, 32137, 32285, 32412, 32521, 32609, 32678, 32728, 32757, 32767,
+];
+
+/// sRGB encoding of linear light in 256 steps, 256ths of the 8 bit value
+const SRGB_ENCODE: [u32; 257] = [
+    0, 3242, 5530, 7209, 8584, 9771, 10825, 11781, 12661, 13478, 14244, 14967, 15652, 16305, 16928,
+    17527, 18102, 18657, 19194, 19713, 20216, 20705, 21181, 21644, 22095, 22536, 22966, 23387,
+    23799, 24202, 24598, 24986, 25366, 25740, 26107, 26468, 26823, 27172, 27515, 27854, 28187,
+    28516, 28840, 29160, 29475, 29786, 30093, 30396, 30696, 30991, 31284, 31573, 31858, 32141,
+    32420, 32697, 32970, 33241, 33508, 33774, 34036, 34296, 34554, 34809, 35062, 35312, 35561,
+    35807, 36051, 36292, 36532, 36770, 37006, 37240, 37472, 37702, 37931, 38158, 38383, 38606,
+    38828, 39048, 39267, 39484, 39699, 39913, 40126, 40337, 40546, 40755, 40962, 41167, 41371,
+    41574, 41776, 41977, 42176, 42374, 42571, 42766, 42961, 43154, 43347, 43538, 43728, 43917,
+    44105, 44292, 44478, 44663, 44847, 45030, 45212, 45393, 45573, 45752, 45931, 46108, 46285,
+    46460, 46635, 46809, 46982, 47155, 47326, 47497, 47667, 47836, 48004, 48172, 48338, 48505,
+    48670, 48834, 48998, 49162, 49324, 49486, 49647, 49807, 49967, 50126, 50284, 50442, 50599,
+    50756, 50912, 51067, 51222, 51376, 51529, 51682, 51834, 51986, 52137, 52287, 52437, 52586,
+    52735, 52884, 53031, 53178, 53325, 53471, 53617, 53762, 53906, 54051, 54194, 54337, 54480,
+    54622, 54763, 54905, 55045, 55185, 55325, 55464, 55603, 55741, 55879, 56017, 56154, 56290,
+    56426, 56562, 56697, 56832, 56967, 57101, 57234, 57367, 57500, 57633, 57765, 57896, 58027,
+    58158, 58289, 58419, 58548, 58678, 58806, 58935, 59063, 59191, 59318, 59445, 59572, 59698,
+    59824, 59950, 60075, 60200, 60325, 60449, 60573, 60697, 60820, 60943, 61066, 61188, 61310,
+    61431, 61553, 61674, 61795, 61915, 62035, 62155, 62274, 62393, 62512, 62631, 62749, 62867,
+    62985, 63102, 63219, 63336, 63453, 63569, 63685, 63801, 63916, 64031, 64146, 64261, 64375,
+    64489, 64603, 64716, 64830, 64943, 65055, 65168, 65280,
+];
+
+/// `QUARTER_SINE` at `pos` of `0..=0x4000`, interpolated
+fn quarter_sine(pos: u32) -> i32 {
+    let idx = (pos >> 8) as usize;
+    if idx >= 64 {
+        return QUARTER_SINE[64];
+    }
+    let frac = (pos & 0xff) as i32;
+    let (a, b) = (QUARTER_SINE[idx], QUARTER_SINE[idx + 1]);
+    a + (((b - a) * frac) >> 8)
+}
+
+/// `angle` in 65536ths of a turn, Q15 out
+pub fn sin16(angle: u16) -> i16 {
+    let pos = (angle & 0x3fff) as u32;
+    let v = match angle >> 14 {
+        0 => quarter_sine(pos),
+        1 => quarter_sine(0x4000 - pos),
+        2 => -quarter_sine(pos),
+        _ => -quarter_sine(0x4000 - pos),
+    };
+    v as i16
+}
+
+pub fn cos16(angle: u16) -> i16 {
+    sin16(angle.wrapping_add(0x4000))
+}
+
+/// FastLED style: a turn is `0..=255`, `0` to `255` out with `128` in the
+/// middle
+pub fn sin8(theta: u8) -> u8 {
+    (128 + (sin16((theta as u16) << 8) as i32 >> 8)) as u8
+}
+
+/// `value * scale / 256`, except `255` scales by exactly `1`
+pub fn scale8(value: u8, scale: u8) -> u8 {
+    ((value as u16 * (scale as u16 + 1)) >> 8) as u8
+}
+
+/// `a` at `0`, `b` at `255`
+pub fn blend8(a: u8, b: u8, amount: u8) -> u8 {
+    let (a, b, t) = (a as u32, b as u32, amount as u32);
+    ((a * (255 - t) + b * t + 127) / 255) as u8
+}
+
+pub fn blend(a: RGB8, b: RGB8, amount: u8) -> RGB8 {
+    RGB8 {
+        r: blend8(a.r, b.r, amount),
+        g: blend8(a.g, b.g, amount),
+        b: blend8(a.b, b.b, amount),
+    }
+}
+
+/// Hue wheel with chroma `c` and `m` added to every channel
+fn hue_wheel(hue: u16, c: u32, m: u32) -> RGB8 {
+    let hue = hue % 1536;
+    let rising = (c * (hue & 0xff) as u32 + 127) / 255;
+    let falling = c - rising;
+    let (r, g, b) = match hue >> 8 {
+        0 => (c, rising, 0),
+        1 => (falling, c, 0),
+        2 => (0, c, rising),
+        3 => (0, falling, c),
+        4 => (rising, 0, c),
+        _ => (c, 0, falling),
+    };
+    let channel = |v: u32| (v + m).min(255) as u8;
+    RGB8 {
+        r: channel(r),
+        g: channel(g),
+        b: channel(b),
+    }
+}
+
+pub fn hsv(hue: u16, saturation: u8, value: u8) -> RGB8 {
+    let v = value as u32;
+    let c = (v * saturation as u32 + 127) / 255;
+    hue_wheel(hue, c, v - c)
+}
+
+pub fn hsl(hue: u16, saturation: u8, lightness: u8) -> RGB8 {
+    let l = lightness as u32;
+    let c = ((255 - (2 * l).abs_diff(255)) * saturation as u32 + 127) / 255;
+    hue_wheel(hue, c, l - c / 2)
+}
+
+/// Linear light, Q16, to 8 bit sRGB
+fn encode(linear: i64) -> u8 {
+    let v = linear.clamp(0, 1 << 16) as u32;
+    let idx = (v >> 8) as usize;
+    if idx >= 256 {
+        return 255;
+    }
+    let (a, b) = (SRGB_ENCODE[idx], SRGB_ENCODE[idx + 1]);
+    let q8 = a + (((b - a) * (v & 0xff)) >> 8);
+    ((q8 + 128) >> 8) as u8
+}
+
+/// `lightness` and `chroma` in Q16, `hue` in 65536ths of a turn from the
+/// same red Oklch starts at. Out of gamut colors clip per channel, like the
+/// float version.
+pub fn oklch(lightness: i32, chroma: i32, hue: u16) -> RGB8 {
+    let (c, l) = (chroma as i64, lightness as i64);
+    let a = (c * cos16(hue) as i64) >> 15;
+    let b = (c * sin16(hue) as i64) >> 15;
+
+    // Oklab to LMS, cubed, to linear sRGB
+    let l_ = l + ((25974 * a + 14143 * b) >> 16);
+    let m_ = l + ((-6918 * a - 4185 * b) >> 16);
+    let s_ = l + ((-5864 * a - 84639 * b) >> 16);
+    let cube = |x: i64| (((x * x) >> 16) * x) >> 16;
+    let (l, m, s) = (cube(l_), cube(m_), cube(s_));
+    RGB8 {
+        r: encode((267173 * l - 216774 * m + 15137 * s) >> 16),
+        g: encode((-83128 * l + 171033 * m - 22369 * s) >> 16),
+        b: encode((-275 * l - 46099 * m + 111910 * s) >> 16),
+    }
+}
+
+/// Once per frame, not per LED
+fn q16(x: f32) -> i32 {
+    (x * 65536.) as i32
+}
+
+/// `degrees` plus `i / len` of `spread` degrees, in 65536ths of a turn
+fn turn(degrees: u64, i: u64, spread: u64, len: u64) -> u16 {
+    ((degrees * len + i * spread) * 65536 / (360 * len)) as u16
+}
+
+/// [`rainborrow_slice`](crate::rainborrow_slice) without floats per LED
+pub fn rainborrow_slice(time: u8, brightness: f32, data: &mut [RGB8]) {
+    let lightness = (brightness.clamp(0., 1.) * 255. + 0.5) as u8;
+    for (i, led) in data.iter_mut().enumerate() {
+        let t = time.wrapping_add((i as u8).wrapping_mul(4));
+        *led = hsl((t as u32 * 1536 / 255) as u16, 255, lightness);
+    }
+}
+
+/// [`rainborrok_slice`](crate::rainborrok_slice) without floats per LED
+pub fn rainborrok_slice(time: u16, lightness: f32, chroma: f32, data: &mut [RGB8]) {
+    let (l, c) = (q16(lightness), q16(chroma));
+    let len = data.len() as u64;
+    for (i, led) in data.iter_mut().enumerate() {
+        *led = oklch(l, c, turn(time as u64, i as u64, 256, len));
+    }
+}
+
+/// [`chaser_slice`](crate::chaser_slice) without floats
+pub fn chaser_slice(time: u16, data: &mut [RGB8]) {
+    let num_leds = data.len();
+    if num_leds == 0 {
+        return;
+    }
+    let offset = time as usize % num_leds;
+    data[(offset + num_leds - 1) % num_leds] = RGB8 { g: 0, r: 0, b: 0 };
+    // 0.9 lightness, 0.15 chroma
+    let hue = turn(0, time as u64, 256, num_leds as u64);
+    data[offset] = oklch(58982, 9830, hue);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use palette::{FromColor, Hsl, Hsv, Oklch, Srgb};
+
+    fn rgb8(c: Srgb) -> RGB8 {
+        let c = c.into_format::<u8>();
+        RGB8::new(c.red, c.green, c.blue)
+    }
+
+    fn max_diff(a: RGB8, b: RGB8) -> u8 {
+        a.r.abs_diff(b.r)
+            .max(a.g.abs_diff(b.g))
+            .max(a.b.abs_diff(b.b))
+    }
+
+    #[test]
+    fn sines() {
+        for angle in (0..=u16::MAX).step_by(37) {
+            let exact = libm::sinf(angle as f32 / 65536. * core::f32::consts::TAU);
+            let fixed = sin16(angle) as f32 / 32767.;
+            assert!(
+                (exact - fixed).abs() < 0.0005,
+                "{} {} {}",
+                angle,
+                exact,
+                fixed
+            );
+        }
+        assert_eq!(cos16(0), 32767);
+        assert_eq!(
+            (sin8(0), sin8(64), sin8(128), sin8(192)),
+            (128, 255, 128, 0)
+        );
+    }
+
+    #[test]
+    fn scale_and_blend() {
+        assert_eq!(scale8(255, 255), 255);
+        assert_eq!(scale8(200, 127), 100);
+        assert_eq!(scale8(200, 0), 0);
+        for a in (0..=255).step_by(5) {
+            for t in (0..=255).step_by(5) {
+                let exact = a as f32 * (255 - t) as f32 / 255. + 17. * t as f32 / 255.;
+                let fixed = blend8(a as u8, 17, t as u8) as f32;
+                assert!((exact - fixed).abs() <= 0.5, "{} {}", a, t);
+            }
+        }
+        let mid = blend(RGB8::new(0, 100, 255), RGB8::new(255, 100, 0), 128);
+        assert_eq!(mid, RGB8::new(128, 100, 127));
+    }
+
+    #[test]
+    fn hsv_and_hsl_match_palette() {
+        for hue in (0..1536).step_by(7) {
+            let degrees = hue as f32 / 1536. * 360.;
+            for s in [0u8, 100, 255] {
+                for l in [0u8, 30, 128, 200, 255] {
+                    let (sf, lf) = (s as f32 / 255., l as f32 / 255.);
+                    let want = rgb8(Srgb::from_color(Hsl::new(degrees, sf, lf)));
+                    let got = hsl(hue, s, l);
+                    assert!(max_diff(want, got) <= 2, "hsl {} {} {}", hue, s, l);
+
+                    let want = rgb8(Srgb::from_color(Hsv::new(degrees, sf, lf)));
+                    let got = hsv(hue, s, l);
+                    assert!(max_diff(want, got) <= 2, "hsv {} {} {}", hue, s, l);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn oklch_matches_palette() {
+        let mut worst = 0;
+        for hue in (0..=u16::MAX).step_by(97) {
+            let degrees = hue as f32 / 65536. * 360.;
+            for (l, c) in [
+                (0.9, 0.15),
+                (0.7, 0.1),
+                (0.5, 0.2),
+                (0.2, 0.05),
+                (0.05, 0.02),
+            ] {
+                let want = rgb8(Srgb::from_color(Oklch::new(l, c, degrees)));
+                let got = oklch(q16(l), q16(c), hue);
+                worst = worst.max(max_diff(want, got));
+            }
+        }
+        assert!(worst <= 2, "{}", worst);
+    }
+
+    #[test]
+    fn effects_match_the_float_versions() {
+        let mut fixed = [RGB8::default(); 300];
+        for time in [0u8, 77, 255] {
+            rainborrow_slice(time, 0.4, &mut fixed);
+            for (i, led) in fixed.iter().enumerate() {
+                let t = time.wrapping_add((i as u8).wrapping_mul(4));
+                let hsl = Hsl::new(360. * t as f32 / 255., 1., 0.4);
+                assert!(max_diff(*led, rgb8(Srgb::from_color(hsl))) <= 2);
+            }
+        }
+        for time in [0u16, 123, 359] {
+            rainborrok_slice(time, 0.7, 0.12, &mut fixed);
+            for (i, led) in fixed.iter().enumerate() {
+                let hue = time as f32 + i as f32 * 256. / 300.;
+                let oklch = Oklch::new(0.7, 0.12, hue);
+                assert!(max_diff(*led, rgb8(Srgb::from_color(oklch))) <= 2);
+            }
+        }
+        let mut data = [RGB8::default(); 7];
+        chaser_slice(3, &mut data);
+        let oklch = Oklch::new(0.9, 0.15, 3. * 256. / 7.);
+        assert!(max_diff(data[3], rgb8(Srgb::from_color(oklch))) <= 2);
+    }
+}
@@ -0,0 +1,256 @@
+//! Where the LEDs of a matrix are.
+//!
+//! Strips get folded into matrices every which way: row by row, snaking back
+//! and forth, mounted sideways or upside down, or glued on by hand. A
+//! [`Layout`] turns an LED index into `x`/`y` on the matrix as it's looked at
+//! (and back), so 2D effects only ever deal with the picture.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Wiring<'a> {
+    /// Every row starts on the left
+    Progressive,
+    /// Every other row runs right to left
+    Serpentine,
+    /// `x`/`y` of each LED, by index, for anything else
+    Map(&'a [(u16, u16)]),
+}
+
+/// Clockwise, applied after flipping
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rotation {
+    R0,
+    R90,
+    R180,
+    R270,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Layout<'a> {
+    /// As wired, before rotating
+    pub width: u16,
+    pub height: u16,
+    pub wiring: Wiring<'a>,
+    pub rotation: Rotation,
+    pub flip_x: bool,
+    pub flip_y: bool,
+}
+
+impl<'a> Layout<'a> {
+    /// `width` × `height`, row by row from the top left
+    pub const fn new(width: u16, height: u16) -> Self {
+        Self {
+            width,
+            height,
+            wiring: Wiring::Progressive,
+            rotation: Rotation::R0,
+            flip_x: false,
+            flip_y: false,
+        }
+    }
+
+    /// How many LEDs are on the matrix
+    pub fn len(&self) -> usize {
+        match self.wiring {
+            Wiring::Map(coords) => coords.len(),
+            _ => self.width as usize * self.height as usize,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Width of the picture, after rotating
+    pub fn picture_width(&self) -> u16 {
+        match self.rotation {
+            Rotation::R0 | Rotation::R180 => self.width,
+            Rotation::R90 | Rotation::R270 => self.height,
+        }
+    }
+
+    /// Height of the picture, after rotating
+    pub fn picture_height(&self) -> u16 {
+        match self.rotation {
+            Rotation::R0 | Rotation::R180 => self.height,
+            Rotation::R90 | Rotation::R270 => self.width,
+        }
+    }
+
+    /// Where LED `index` is in the picture, `None` past the end
+    pub fn position(&self, index: usize) -> Option<(u16, u16)> {
+        let (w, h) = (self.width as usize, self.height as usize);
+        let (mut x, mut y) = match self.wiring {
+            Wiring::Map(coords) => {
+                let (x, y) = *coords.get(index)?;
+                (x as usize, y as usize)
+            }
+            _ if index >= w * h => return None,
+            Wiring::Progressive => (index % w, index / w),
+            Wiring::Serpentine if (index / w) % 2 == 1 => (w - 1 - index % w, index / w),
+            Wiring::Serpentine => (index % w, index / w),
+        };
+        if x >= w || y >= h {
+            return None;
+        }
+        if self.flip_x {
+            x = w - 1 - x;
+        }
+        if self.flip_y {
+            y = h - 1 - y;
+        }
+        let (x, y) = match self.rotation {
+            Rotation::R0 => (x, y),
+            Rotation::R90 => (h - 1 - y, x),
+            Rotation::R180 => (w - 1 - x, h - 1 - y),
+            Rotation::R270 => (y, w - 1 - x),
+        };
+        Some((x as u16, y as u16))
+    }
+
+    /// Which LED is at `x`/`y` in the picture, if any
+    pub fn index(&self, x: u16, y: u16) -> Option<usize> {
+        if x >= self.picture_width() || y >= self.picture_height() {
+            return None;
+        }
+        let (w, h) = (self.width as usize, self.height as usize);
+        let (x, y) = (x as usize, y as usize);
+        let (mut x, mut y) = match self.rotation {
+            Rotation::R0 => (x, y),
+            Rotation::R90 => (y, h - 1 - x),
+            Rotation::R180 => (w - 1 - x, h - 1 - y),
+            Rotation::R270 => (w - 1 - y, x),
+        };
+        if self.flip_x {
+            x = w - 1 - x;
+        }
+        if self.flip_y {
+            y = h - 1 - y;
+        }
+        match self.wiring {
+            Wiring::Progressive => Some(y * w + x),
+            Wiring::Serpentine if y % 2 == 1 => Some(y * w + (w - 1 - x)),
+            Wiring::Serpentine => Some(y * w + x),
+            Wiring::Map(coords) => coords.iter().position(|&c| c == (x as u16, y as u16)),
+        }
+    }
+
+    /// Middle of the picture, between LEDs for even sizes
+    pub fn center(&self) -> (f32, f32) {
+        let half = |n: u16| n.saturating_sub(1) as f32 / 2.;
+        (half(self.picture_width()), half(self.picture_height()))
+    }
+
+    /// From the center to LED `index`, in LEDs
+    pub fn distance(&self, index: usize) -> Option<f32> {
+        let (dx, dy) = self.offset(index)?;
+        Some(libm::sqrtf(dx * dx + dy * dy))
+    }
+
+    /// Of LED `index` around the center, in radians from the right, clockwise
+    /// since `y` points down. `-PI..=PI`.
+    pub fn angle(&self, index: usize) -> Option<f32> {
+        let (dx, dy) = self.offset(index)?;
+        Some(libm::atan2f(dy, dx))
+    }
+
+    /// How far from the center the picture goes along its longer axis
+    pub fn radius(&self) -> f32 {
+        let (cx, cy) = self.center();
+        cx.max(cy)
+    }
+
+    fn offset(&self, index: usize) -> Option<(f32, f32)> {
+        let (x, y) = self.position(index)?;
+        let (cx, cy) = self.center();
+        Some((x as f32 - cx, y as f32 - cy))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::f32::consts::PI;
+
+    fn for_all_layouts(w: u16, h: u16, map: &[(u16, u16)], mut f: impl FnMut(Layout)) {
+        let wirings = [Wiring::Progressive, Wiring::Serpentine, Wiring::Map(map)];
+        let rotations = [Rotation::R0, Rotation::R90, Rotation::R180, Rotation::R270];
+        let flips = [(false, false), (true, false), (false, true), (true, true)];
+        for &wiring in &wirings {
+            for &rotation in &rotations {
+                for &(flip_x, flip_y) in &flips {
+                    f(Layout {
+                        wiring,
+                        rotation,
+                        flip_x,
+                        flip_y,
+                        ..Layout::new(w, h)
+                    })
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn serpentine_snakes() {
+        let layout = Layout {
+            wiring: Wiring::Serpentine,
+            ..Layout::new(3, 2)
+        };
+        assert_eq!(layout.position(2), Some((2, 0)));
+        assert_eq!(layout.position(3), Some((2, 1)));
+        assert_eq!(layout.position(5), Some((0, 1)));
+        assert_eq!(layout.position(6), None);
+    }
+
+    #[test]
+    fn rotation_turns_the_picture() {
+        let layout = Layout {
+            rotation: Rotation::R90,
+            ..Layout::new(3, 2)
+        };
+        assert_eq!((layout.picture_width(), layout.picture_height()), (2, 3));
+        // the first LED ends up top right
+        assert_eq!(layout.position(0), Some((1, 0)));
+        assert_eq!(layout.position(2), Some((1, 2)));
+
+        let flipped = Layout {
+            flip_x: true,
+            ..Layout::new(3, 2)
+        };
+        assert_eq!(flipped.position(0), Some((2, 0)));
+    }
+
+    #[test]
+    fn index_undoes_position() {
+        // a 4×3 matrix wired in columns
+        let mut map = [(0, 0); 12];
+        for (i, c) in map.iter_mut().enumerate() {
+            *c = ((i / 3) as u16, (i % 3) as u16);
+        }
+        for_all_layouts(4, 3, &map, |layout| {
+            assert_eq!(layout.len(), 12);
+            for i in 0..12 {
+                let (x, y) = layout.position(i).unwrap();
+                assert_eq!(layout.index(x, y), Some(i), "{:?}", layout);
+            }
+            assert_eq!(layout.index(layout.picture_width(), 0), None);
+        });
+    }
+
+    #[test]
+    fn geometry() {
+        let layout = Layout::new(7, 7);
+        assert_eq!(layout.center(), (3., 3.));
+        assert_eq!(layout.radius(), 3.);
+        assert_eq!(layout.distance(24), Some(0.));
+        assert_eq!(layout.distance(0), Some(libm::sqrtf(18.)));
+        assert_eq!(layout.angle(27), Some(0.));
+        assert_eq!(layout.angle(3), Some(-PI / 2.));
+        assert_eq!(layout.distance(49), None);
+
+        let even = Layout::new(4, 2);
+        assert_eq!(even.center(), (1.5, 0.5));
+        assert_eq!(Layout::new(0, 0).position(0), None);
+        assert_eq!(Layout::new(1, 1).distance(0), Some(0.));
+    }
+}
@@ -1,134 +1,44 @@
 #![no_std]
 
-use palette::rgb::Rgb;
+pub mod effect;
+pub mod fixed;
+pub mod layout;
+pub mod matrix;
+pub mod rain;
+
 use palette::FromColor;
 use smart_leds::RGB;
 
-use palette::{encoding::Srgb, Hsl};
+use palette::Hsl;
 use smart_leds::{SmartLedsWrite, RGB8};
 
+use effect::Effect;
+use layout::Layout;
+pub use rain::Row;
+
 use embedded_hal::blocking::delay::DelayMs;
 
 // use micromath::F32Ext;
 
-use embedded_graphics::{pixelcolor::Rgb888, prelude::Point, Pixel};
-
-pub struct Row {
-    speed: u8,
-    x: u8,
-    y_start: u8,
-    y: u8,
-    y_sub: u8,
-    height: u8,
-    fade: u8,
-    age: u16,
-}
-
-impl Row {
-    pub fn new(speed: u8, x: u8, y: u8, height: u8, fade: u8) -> Self {
-        Self {
-            speed,
-            x,
-            y_start: y,
-            y,
-            height,
-            y_sub: 0,
-            fade,
-            age: 0,
-        }
-    }
-    pub fn tick(&mut self) -> bool {
-        let (new_y, wrapped) = self.y_sub.overflowing_add(self.speed);
-        self.y_sub = new_y;
-        if wrapped {
-            self.y = self.y.saturating_add(1);
-        }
-
-        self.age = self.age.saturating_add(1);
-
-        // TODO figure this out
-        self.y >= self.height
-            && self.age > 20000u16.saturating_div(self.fade as u16 * self.speed as u16)
-    }
-    pub fn iter(&self) -> impl Iterator<Item = Pixel<Rgb888>> + '_ {
-        RowIterator::new(self.x, self.y_start, self.y, self.fade)
-    }
-}
-
-struct RowIterator {
-    x: u8,
-    y: u8,
-    y_start: u8,
-    y_end: u8,
-    fade: u8,
-}
-
-impl RowIterator {
-    fn new(x: u8, y: u8, y_end: u8, fade: u8) -> Self {
-        Self {
-            x,
-            y,
-            y_start: y,
-            y_end,
-            fade,
-        }
-    }
-}
-
-impl Iterator for RowIterator {
-    type Item = Pixel<Rgb888>;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        let res = if self.y > self.y_end {
-            None
-        } else {
-            let color = if self.y == self.y_end {
-                Rgb888::new(90, 120, 110)
-            } else {
-                let delta_max = self.y_end - self.y_start;
-                let delta = self.y_end - self.y;
-
-                let green_max = 255u8.saturating_sub(delta_max.saturating_mul(14));
-                let g = green_max.saturating_sub(delta.saturating_mul(self.fade + 1));
-                Rgb888::new(0, g, 0)
-            };
-            Some(Pixel(Point::new(self.x as i32, self.y as i32), color))
-        };
-        self.y += 1;
-        res
-    }
-}
-
-#[test]
-fn wraps() {
-    let mut row = Row::new(126, 0, 0, 5, 1);
-    for y in [0, 0, 1, 1, 2] {
-        row.tick();
-        assert_eq!(y, row.y);
-    }
+pub fn rainborrow<const NUM_LEDS: usize>(time: u8, brightness: f32, data: &mut [RGB8; NUM_LEDS]) {
+    rainborrow_slice(time, brightness, data)
 }
 
-#[test]
-fn dies() {
-    let mut row = Row::new(128, 0, 0, 2, 1);
-    for shall_die in [false, false, false, true] {
-        let dead = row.tick();
-        assert_eq!(dead, shall_die);
+pub fn rainborrow_slice(time: u8, brightness: f32, data: &mut [RGB8]) {
+    if cfg!(feature = "fixed-point") {
+        return fixed::rainborrow_slice(time, brightness, data);
     }
-}
-
-pub fn rainborrow<const NUM_LEDS: usize>(time: u8, brightness: f32, data: &mut [RGB8; NUM_LEDS]) {
-    for i in 0..NUM_LEDS {
+    for (i, led) in data.iter_mut().enumerate() {
         let color: Hsl = Hsl::new(
             360. * time.wrapping_add((i as u8).wrapping_mul(4)) as f32 / 255.,
             1.0f32,
             brightness,
         );
 
+        // still sRGB, gamma and white balance happen at the output
         let rgb = palette::Srgb::from_color(color);
-        let rgb = rgb.into_linear().into_format::<u8>();
-        // let rgb: Rgb<Srgb, u8> = rgb.into_format::<u8>();
-        data[i] = RGB8 {
+        let rgb = rgb.into_format::<u8>();
+        *led = RGB8 {
             g: rgb.green,
             r: rgb.red,
             b: rgb.blue,
@@ -142,18 +52,27 @@ pub fn rainborrok<const NUM_LEDS: usize>(
     time: u16,
     lightness: f32,
     chroma: f32,
-    aaa_my_eyes: f32,
     data: &mut [RGB8; NUM_LEDS],
 ) {
-    // let aaa_my_eyes = 0.104;
-    for i in 0..NUM_LEDS {
-        let t_i = time as f32 + i as f32 * ((16 * 16) / NUM_LEDS) as f32;
+    rainborrok_slice(time, lightness, chroma, data)
+}
+
+/// sRGB out, dim it with `lightness` or at the output, not here
+pub fn rainborrok_slice(time: u16, lightness: f32, chroma: f32, data: &mut [RGB8]) {
+    if cfg!(feature = "fixed-point") {
+        return fixed::rainborrok_slice(time, lightness, chroma, data);
+    }
+    if data.is_empty() {
+        return;
+    }
+    let spread = (16 * 16) as f32 / data.len() as f32;
+    for (i, led) in data.iter_mut().enumerate() {
+        let t_i = time as f32 + i as f32 * spread;
         let color = palette::Oklch::new(lightness, chroma, t_i);
         //let gammad = lin.into_format();
         let rgb = palette::Srgb::from_color(color);
-        let rgb = rgb.into_linear() * aaa_my_eyes;
         let rgb = rgb.into_format::<u8>();
-        data[i] = RGB8 {
+        *led = RGB8 {
             g: rgb.green,
             r: rgb.red,
             b: rgb.blue,
@@ -162,10 +81,21 @@ pub fn rainborrok<const NUM_LEDS: usize>(
 }
 
 pub fn chaser<const NUM_LEDS: usize>(time: u16, data: &mut [RGB8; NUM_LEDS]) {
-    let offset = time as usize % NUM_LEDS;
-    data[(offset + NUM_LEDS - 1) % NUM_LEDS] = RGB8 { g: 0, r: 0, b: 0 };
+    chaser_slice(time, data)
+}
+
+pub fn chaser_slice(time: u16, data: &mut [RGB8]) {
+    if cfg!(feature = "fixed-point") {
+        return fixed::chaser_slice(time, data);
+    }
+    let num_leds = data.len();
+    if num_leds == 0 {
+        return;
+    }
+    let offset = time as usize % num_leds;
+    data[(offset + num_leds - 1) % num_leds] = RGB8 { g: 0, r: 0, b: 0 };
 
-    let t_i = time as f32 * ((16 * 16) / NUM_LEDS) as f32;
+    let t_i = time as f32 * (16 * 16) as f32 / num_leds as f32;
     let color = palette::Oklch::new(0.9, 0.15, t_i);
 
     let rgb = palette::Srgb::from_color(color);
@@ -178,13 +108,17 @@ pub fn chaser<const NUM_LEDS: usize>(time: u16, data: &mut [RGB8; NUM_LEDS]) {
 }
 
 pub fn progress<const NUM_LEDS: usize>(time: u16, data: &mut [RGB8; NUM_LEDS]) {
+    progress_slice(time, data)
+}
+
+pub fn progress_slice(time: u16, data: &mut [RGB8]) {
     // let aaa_my_eyes = 0.104;
 
-    let completeness = (time as u32 * NUM_LEDS as u32) / (u16::MAX as u32);
-    for i in 0..completeness as usize {
+    let completeness = (time as u32 * data.len() as u32) / (u16::MAX as u32);
+    for led in data.iter_mut().take(completeness as usize) {
         let rgb = palette::LinSrgb::new(64u8, 64, 64);
 
-        data[i] = RGB8 {
+        *led = RGB8 {
             g: rgb.green,
             r: rgb.red,
             b: rgb.blue,
@@ -194,114 +128,50 @@ pub fn progress<const NUM_LEDS: usize>(time: u16, data: &mut [RGB8; NUM_LEDS]) {
 
 // lol so generic .. not
 pub fn expanding_circle<const NUM_LEDS: usize>(time: u8, dampen: u8, data: &mut [RGB8; NUM_LEDS]) {
-    const MID: isize = 3;
-
-    const DIST: [[u8; 4]; 4] = [
-        [0, 60, 120, 180],
-        [60, 84, 134, 189],
-        [120, 134, 169, 216],
-        [180, 189, 216, 254],
-    ];
-
-    fn mk(time: u8) -> u8 {
-        if time == 0 {
-            return 1;
-        }
-        let mut res: u8 = 1;
-        for tt in 0..time {
-            res = res.wrapping_add((12 - tt).max(2));
-        }
-        res
-    }
-
-    let kk = 0;
-
-    let k = time.wrapping_mul(10);
-
-    let spd = 60;
-
-    // let mut k = ((spd - time) + (time) / 2).wrapping_mul(time);
-    // if time >= spd {
-    //     k = time.wrapping_mul(15);
-    // }
-
-    // OR SOMETHING
-
-    let spd = 80u8;
-
-    let mut k = spd
-        .saturating_sub(time / 2)
-        .max(1)
-        .min(20)
-        .wrapping_mul(time);
-    if time >= spd * 2 {
-        k = time.wrapping_mul(15);
-    }
-
-    // const K: u8 = 45;
-    // let k = (K.saturating_sub(time)).max(5).wrapping_mul(time);
-    // defmt::debug!("{}", k);
-    for i in 0..NUM_LEDS.min(49) {
-        let mut x = i as isize % 7;
-        let y = i as isize / 7;
-
-        let zigzag = false;
-
-        if zigzag {
-            if y % 2 == 1 {
-                x = 6 - x;
-            }
-        }
+    expanding_circle_slice(time, dampen, data)
+}
 
-        // defmt::debug!("i,x,y {},{},{}", i, x, y,);
-
-        let x = (x - MID).abs() as usize;
-        let y = (y - MID).abs() as usize;
-        // defmt::debug!("i,x',y' {},{},{}", i, x, y,);
-        let d = (255 - DIST[x][y]).wrapping_add(k).saturating_sub(time);
-        // defmt::info!("i,d {},{}", i, d);
-        data[i] = RGB8 {
-            g: (d / 2) / dampen,
-            r: 0,
-            b: (d).saturating_sub(time / 2) / dampen,
-        };
-    }
+/// [`expanding_circle_layout`] on a 7×7 matrix, green and blue
+pub fn expanding_circle_slice(time: u8, dampen: u8, data: &mut [RGB8]) {
+    expanding_circle_layout(time, dampen, 1, &Layout::new(7, 7), data, true)
 }
 
+/// [`expanding_circle_layout`] on a 7×7 matrix
 pub fn expanding_circle_2(time: u8, dampen: u8, amplify: u8, data: &mut [RGB8], grb: bool) {
-    const WH: isize = 7;
-    const MID: isize = WH / 2;
-
-    const DIST: [[u8; 4]; 4] = [
-        [0, 60, 120, 180],
-        [60, 84, 134, 189],
-        [120, 134, 169, 216],
-        [180, 189, 216, 254],
-    ];
+    expanding_circle_layout(time, dampen, amplify, &Layout::new(7, 7), data, grb)
+}
 
+/// A ring growing out of the middle of `layout`. Red and blue, or green and
+/// blue with `grb`. LEDs past the layout are left alone.
+pub fn expanding_circle_layout(
+    time: u8,
+    dampen: u8,
+    amplify: u8,
+    layout: &Layout,
+    data: &mut [RGB8],
+    grb: bool,
+) {
+    let dampen = dampen.max(1);
     let spd = 80u8;
 
-    let mut k = spd
-        .saturating_sub(time / 2)
-        .max(1)
-        .min(20)
-        .wrapping_mul(time);
+    let mut k = spd.saturating_sub(time / 2).clamp(1, 20).wrapping_mul(time);
     if time >= spd * 2 {
         k = time.wrapping_mul(15);
     }
 
-    for i in 0..49 {
-        let x = i as isize % WH;
-        let y = i as isize / WH;
-
-        let x = (x - MID).abs() as usize;
-        let y = (y - MID).abs() as usize;
-        let d = (255 - DIST[x][y]).wrapping_add(k).saturating_sub(time);
+    // the middle to the edge is 180, so even the corners of a square fit a u8
+    let scale = 180. / layout.radius().max(1.);
+    for (i, led) in data.iter_mut().enumerate().take(layout.len()) {
+        let dist = match layout.distance(i) {
+            Some(dist) => (dist * scale).min(255.) as u8,
+            None => continue,
+        };
+        let d = (255 - dist).wrapping_add(k).saturating_sub(time);
 
         let r = ((d / 2) / dampen).saturating_mul(amplify);
         let g = 0;
 
-        data[i] = RGB8 {
+        *led = RGB8 {
             r: if grb { g } else { r },
             g: if grb { r } else { g },
             b: ((d).saturating_sub(time / 2) / dampen).saturating_mul(amplify),
@@ -317,29 +187,159 @@ where
     <WS as SmartLedsWrite>::Error: core::fmt::Debug,
     D: DelayMs<u16>,
 {
-    let mut j = 0u16;
-    for i in 0..NUM_LEDS {
-        // j as f32 / (L - L / 2) as f32 * 180.
-        let h = (((j * 7) % 360) - 180) as f32;
-        let lin = Hsl::new(h, 0.96, 0.4);
-        let rgb = palette::rgb::Srgb::from_color(lin);
-        let rrr: Rgb<Srgb, u8> = rgb.into_format::<u8>();
-        data[i] = RGB8 {
-            r: rrr.green,
-            g: rrr.red,
-            b: rrr.blue,
+    effect::Fader.render(0, data);
+    // GRB strip
+    for led in data.iter_mut() {
+        *led = RGB8 {
+            r: led.g,
+            g: led.r,
+            b: led.b,
         };
-        j += 1;
     }
     ws.write(data.iter().cloned()).unwrap();
 
-    delay.delay_ms(60 as u16);
+    delay.delay_ms(60u16);
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    const BLACK: RGB8 = RGB8 { r: 0, g: 0, b: 0 };
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    fn every_effect(time: u8, data: &mut [RGB8]) {
+        rainborrow_slice(time, 0.5, data);
+        rainborrok_slice(time as u16, 0.7, 0.1, data);
+        chaser_slice(time as u16, data);
+        progress_slice(time as u16 * 256, data);
+        expanding_circle_slice(time, 1, data);
+        expanding_circle_2(time, 1, 1, data, false);
+    }
+
+    #[test]
+    fn slices_of_any_length() {
+        let mut data = [BLACK; 1000];
+        for len in [0, 1, 7, 49, 50, 1000] {
+            for time in [0, 1, 100, 255] {
+                every_effect(time, &mut data[..len]);
+            }
+        }
+    }
+
+    #[test]
+    fn arrays_are_the_same_as_slices() {
+        let mut array = [BLACK; 60];
+        let mut slice = [BLACK; 60];
+        rainborrow(30, 0.5, &mut array);
+        rainborrow_slice(30, 0.5, &mut slice);
+        assert_eq!(array, slice);
+        rainborrok(30, 0.7, 0.1, &mut array);
+        rainborrok_slice(30, 0.7, 0.1, &mut slice);
+        assert_eq!(array, slice);
+        chaser(30, &mut array);
+        chaser_slice(30, &mut slice);
+        assert_eq!(array, slice);
+        progress(30_000, &mut array);
+        progress_slice(30_000, &mut slice);
+        assert_eq!(array, slice);
+        expanding_circle(30, 2, &mut array);
+        expanding_circle_slice(30, 2, &mut slice);
+        assert_eq!(array, slice);
+    }
+
+    #[test]
+    fn long_strips_get_the_whole_rainbow() {
+        // more LEDs than the 256 the spread used to be counted in
+        let mut data = [BLACK; 1000];
+        rainborrok_slice(0, 0.7, 0.1, &mut data);
+        assert_ne!(data[0], data[500]);
+
+        chaser_slice(0, &mut data);
+        let first = data[0];
+        chaser_slice(500, &mut data);
+        assert_ne!(data[500], first);
+    }
+
+    #[test]
+    fn circle_on_7x7_is_unchanged() {
+        // what the circles used before they had a layout
+        const DIST: [[u8; 4]; 4] = [
+            [0, 60, 120, 180],
+            [60, 84, 134, 189],
+            [120, 134, 169, 216],
+            [180, 189, 216, 254],
+        ];
+        for time in [0, 1, 50, 100, 170, 255] {
+            let mut data = [BLACK; 49];
+            expanding_circle_2(time, 2, 3, &mut data, false);
+            let mut k = 80u8
+                .saturating_sub(time / 2)
+                .clamp(1, 20)
+                .wrapping_mul(time);
+            if time >= 160 {
+                k = time.wrapping_mul(15);
+            }
+            for (i, led) in data.iter().enumerate() {
+                let x = (i as isize % 7 - 3).unsigned_abs();
+                let y = (i as isize / 7 - 3).unsigned_abs();
+                let d = (255 - DIST[x][y]).wrapping_add(k).saturating_sub(time);
+                assert_eq!(led.r, ((d / 2) / 2).saturating_mul(3), "{time} {i}");
+                assert_eq!(led.b, (d.saturating_sub(time / 2) / 2).saturating_mul(3));
+            }
+        }
+    }
+
+    #[test]
+    fn circle_on_any_matrix() {
+        let layouts = [
+            Layout::new(16, 16),
+            Layout {
+                wiring: layout::Wiring::Serpentine,
+                rotation: layout::Rotation::R90,
+                ..Layout::new(20, 5)
+            },
+            Layout::new(1, 1),
+        ];
+        for layout in &layouts {
+            let mut data = [RGB8::new(1, 2, 3); 300];
+            expanding_circle_layout(0, 1, 1, layout, &mut data, false);
+            let center = layout.index(layout.picture_width() / 2, layout.picture_height() / 2);
+            let corner = data[layout.index(0, 0).unwrap()];
+            assert!(data[center.unwrap()].b >= corner.b, "{:?}", layout);
+            assert!(data[layout.len()..]
+                .iter()
+                .all(|c| *c == RGB8::new(1, 2, 3)));
+        }
+        // the picture is the same no matter how it's wired
+        let plain = Layout::new(6, 4);
+        let snake = Layout {
+            wiring: layout::Wiring::Serpentine,
+            ..plain
+        };
+        let (mut a, mut b) = ([BLACK; 24], [BLACK; 24]);
+        expanding_circle_layout(40, 1, 1, &plain, &mut a, false);
+        expanding_circle_layout(40, 1, 1, &snake, &mut b, false);
+        for (i, px) in a.iter().enumerate() {
+            let (x, y) = plain.position(i).unwrap();
+            assert_eq!(*px, b[snake.index(x, y).unwrap()]);
+        }
+    }
+
+    #[test]
+    fn one_led() {
+        let mut data = [BLACK; 1];
+        chaser_slice(5, &mut data);
+        assert_ne!(data[0], BLACK);
+        progress_slice(u16::MAX, &mut data);
+        assert_eq!(data[0], RGB8::new(64, 64, 64));
+        // the matrix center is off the end
+        let mut data = [RGB8::new(1, 2, 3); 1];
+        expanding_circle_slice(0, 1, &mut data);
+        assert_ne!(data[0], RGB8::new(1, 2, 3));
+    }
 }
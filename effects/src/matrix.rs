@@ -0,0 +1,183 @@
+//! embedded-graphics on LED matrices.
+//!
+//! [`LedMatrix`] is a [`DrawTarget`] over an LED buffer, with a [`Layout`]
+//! to find the LED for each pixel. Primitives, fonts and images draw straight
+//! onto the strip, and so do the digital rain [`Row`](crate::Row)s.
+
+use core::convert::Infallible;
+
+use embedded_graphics::{
+    geometry::Dimensions,
+    pixelcolor::{Rgb888, RgbColor},
+    prelude::{DrawTarget, OriginDimensions, Size},
+    primitives::{PointsIter, Rectangle},
+    Pixel,
+};
+use smart_leds::RGB8;
+
+use crate::layout::Layout;
+
+pub struct LedMatrix<'a, 'l> {
+    data: &'a mut [RGB8],
+    layout: Layout<'l>,
+}
+
+impl<'a, 'l> LedMatrix<'a, 'l> {
+    /// Pixels whose LED is past the end of `data` are dropped
+    pub fn new(data: &'a mut [RGB8], layout: Layout<'l>) -> Self {
+        Self { data, layout }
+    }
+
+    pub fn layout(&self) -> &Layout<'l> {
+        &self.layout
+    }
+
+    pub fn data(&mut self) -> &mut [RGB8] {
+        self.data
+    }
+
+    /// Color of the LED at `x`/`y` in the picture
+    pub fn get(&self, x: u16, y: u16) -> Option<RGB8> {
+        self.data.get(self.layout.index(x, y)?).copied()
+    }
+
+    fn led(&mut self, x: i32, y: i32) -> Option<&mut RGB8> {
+        if x < 0 || y < 0 || x > u16::MAX as i32 || y > u16::MAX as i32 {
+            return None;
+        }
+        let index = self.layout.index(x as u16, y as u16)?;
+        self.data.get_mut(index)
+    }
+}
+
+fn rgb8(color: Rgb888) -> RGB8 {
+    RGB8::new(color.r(), color.g(), color.b())
+}
+
+impl OriginDimensions for LedMatrix<'_, '_> {
+    fn size(&self) -> Size {
+        Size::new(
+            self.layout.picture_width() as u32,
+            self.layout.picture_height() as u32,
+        )
+    }
+}
+
+impl DrawTarget for LedMatrix<'_, '_> {
+    type Color = Rgb888;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            if let Some(led) = self.led(point.x, point.y) {
+                *led = rgb8(color);
+            }
+        }
+        Ok(())
+    }
+
+    fn fill_solid(&mut self, area: &Rectangle, color: Self::Color) -> Result<(), Self::Error> {
+        let area = area.intersection(&self.bounding_box());
+        for point in area.points() {
+            if let Some(led) = self.led(point.x, point.y) {
+                *led = rgb8(color);
+            }
+        }
+        Ok(())
+    }
+
+    /// Also clears LEDs that aren't in the picture, like the rest of a strip
+    fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
+        self.data.fill(rgb8(color));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{layout::Wiring, Row};
+    use embedded_graphics::{
+        image::{Image, ImageRaw},
+        mono_font::{ascii::FONT_4X6, MonoTextStyle},
+        pixelcolor::raw::BigEndian,
+        prelude::*,
+        primitives::{Line, PrimitiveStyle},
+        text::{Baseline, Text},
+    };
+
+    const BLACK: RGB8 = RGB8 { r: 0, g: 0, b: 0 };
+
+    fn lit(matrix: &LedMatrix) -> usize {
+        let size = matrix.size();
+        (0..size.height as u16)
+            .flat_map(|y| (0..size.width as u16).map(move |x| (x, y)))
+            .filter(|&(x, y)| matrix.get(x, y) != Some(BLACK))
+            .count()
+    }
+
+    #[test]
+    fn primitives_go_through_the_layout() {
+        let mut data = [BLACK; 16];
+        let layout = Layout {
+            wiring: Wiring::Serpentine,
+            ..Layout::new(4, 4)
+        };
+        let mut matrix = LedMatrix::new(&mut data, layout);
+        Line::new(Point::new(0, 1), Point::new(3, 1))
+            .into_styled(PrimitiveStyle::with_stroke(Rgb888::RED, 1))
+            .draw(&mut matrix)
+            .unwrap();
+        assert_eq!(lit(&matrix), 4);
+        // the second row runs backwards, but it's still one row of LEDs
+        assert!(data[4..8].iter().all(|c| *c == RGB8::new(255, 0, 0)));
+
+        // off the edge is fine
+        let mut matrix = LedMatrix::new(&mut data, layout);
+        matrix.clear(Rgb888::BLACK).unwrap();
+        Rectangle::new(Point::new(-2, -2), Size::new(4, 4))
+            .into_styled(PrimitiveStyle::with_fill(Rgb888::BLUE))
+            .draw(&mut matrix)
+            .unwrap();
+        assert_eq!(lit(&matrix), 4);
+    }
+
+    #[test]
+    fn text_and_images() {
+        let mut data = [BLACK; 8 * 6];
+        let mut matrix = LedMatrix::new(&mut data, Layout::new(8, 6));
+        let style = MonoTextStyle::new(&FONT_4X6, Rgb888::GREEN);
+        Text::with_baseline("Hi", Point::zero(), style, Baseline::Top)
+            .draw(&mut matrix)
+            .unwrap();
+        assert!(lit(&matrix) > 5);
+
+        // a 2×1 image, red then white
+        let raw: ImageRaw<Rgb888, BigEndian> = ImageRaw::new(&[255, 0, 0, 255, 255, 255], 2);
+        matrix.clear(Rgb888::BLACK).unwrap();
+        Image::new(&raw, Point::new(6, 5))
+            .draw(&mut matrix)
+            .unwrap();
+        assert_eq!(matrix.get(6, 5), Some(RGB8::new(255, 0, 0)));
+        assert_eq!(matrix.get(7, 5), Some(RGB8::new(255, 255, 255)));
+        assert_eq!(lit(&matrix), 2);
+    }
+
+    #[test]
+    fn rows_draw_directly() {
+        let mut data = [BLACK; 7 * 7];
+        let mut matrix = LedMatrix::new(&mut data, Layout::new(7, 7));
+        let mut row = Row::new(255, 2, 0, 7, 1);
+        while row.y() < 3 {
+            row.tick();
+        }
+        row.draw(&mut matrix).unwrap();
+        // the head is its own color, the trail above it green
+        assert_eq!(matrix.get(2, 3), Some(RGB8::new(90, 120, 110)));
+        assert_eq!(matrix.get(2, 0).map(|c| (c.r, c.b)), Some((0, 0)));
+        assert_eq!(lit(&matrix), 4);
+    }
+}
@@ -0,0 +1,425 @@
+//! Digital rain.
+//!
+//! Each [`Row`] is one falling trail: a head and a tail that dims the further
+//! up it goes and the longer it gets. [`Rain`] keeps a pool of them, spawns
+//! new ones from a seeded PRNG so the same seed rains the same way, and
+//! retires them once nothing of them is left on the matrix.
+
+use embedded_graphics::{
+    pixelcolor::{Rgb888, RgbColor},
+    prelude::{DrawTarget, Point},
+    Pixel,
+};
+use smart_leds::RGB8;
+
+use crate::{effect::Effect, layout::Layout, matrix::LedMatrix};
+
+/// Catching up on more ticks than this in one frame isn't worth it
+const MAX_CATCH_UP: u64 = 64;
+
+/// The classic
+pub const GREEN: Rgb888 = Rgb888::new(0, 255, 0);
+pub const PALE_HEAD: Rgb888 = Rgb888::new(90, 120, 110);
+
+/// How bright the tail of a `len` long row is, `delta` LEDs above the head.
+/// Longer rows are dimmer all over, so rows fade out as they fall.
+fn tail_level(len: u8, delta: u8, fade: u8) -> u8 {
+    let max = 255u8.saturating_sub(len.saturating_mul(14));
+    max.saturating_sub(delta.saturating_mul(fade.saturating_add(1)))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Row {
+    speed: u8,
+    x: u8,
+    y_start: u8,
+    y: u8,
+    y_sub: u8,
+    height: u8,
+    fade: u8,
+}
+
+impl Row {
+    /// `speed` is in 256ths of an LED per tick, `height` is the matrix's
+    pub fn new(speed: u8, x: u8, y: u8, height: u8, fade: u8) -> Self {
+        Self {
+            speed,
+            x,
+            y_start: y,
+            y,
+            height,
+            y_sub: 0,
+            fade,
+        }
+    }
+
+    /// Fall a bit, returns whether the row is gone now
+    pub fn tick(&mut self) -> bool {
+        let (new_y, wrapped) = self.y_sub.overflowing_add(self.speed);
+        self.y_sub = new_y;
+        if wrapped {
+            self.y = self.y.saturating_add(1);
+        }
+        self.is_dead()
+    }
+
+    /// The head fell off the bottom and what's left of the tail on the matrix
+    /// has faded to black
+    pub fn is_dead(&self) -> bool {
+        if self.y < self.height {
+            return false;
+        }
+        if self.y_start >= self.height {
+            return true;
+        }
+        // the lowest tail LED on the matrix is the brightest one left
+        let delta = self.y - (self.height - 1);
+        tail_level(self.y - self.y_start, delta, self.fade) == 0
+    }
+
+    /// Column
+    pub fn x(&self) -> u8 {
+        self.x
+    }
+
+    /// Where the head is
+    pub fn y(&self) -> u8 {
+        self.y
+    }
+
+    /// In the classic colors
+    pub fn iter(&self) -> impl Iterator<Item = Pixel<Rgb888>> + '_ {
+        self.iter_colored(PALE_HEAD, GREEN)
+    }
+
+    /// The head in `head`, the tail in `tail` dimmed
+    pub fn iter_colored(
+        &self,
+        head: Rgb888,
+        tail: Rgb888,
+    ) -> impl Iterator<Item = Pixel<Rgb888>> + '_ {
+        RowIterator {
+            x: self.x,
+            y: Some(self.y_start),
+            y_start: self.y_start,
+            y_end: self.y,
+            fade: self.fade,
+            head,
+            tail,
+        }
+    }
+
+    /// Onto an [`LedMatrix`] or any other target
+    pub fn draw<D: DrawTarget<Color = Rgb888>>(&self, target: &mut D) -> Result<(), D::Error> {
+        target.draw_iter(self.iter())
+    }
+}
+
+struct RowIterator {
+    x: u8,
+    /// `None` once past the head
+    y: Option<u8>,
+    y_start: u8,
+    y_end: u8,
+    fade: u8,
+    head: Rgb888,
+    tail: Rgb888,
+}
+
+impl Iterator for RowIterator {
+    type Item = Pixel<Rgb888>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let y = self.y.filter(|y| *y <= self.y_end)?;
+        let color = if y == self.y_end {
+            self.head
+        } else {
+            let level = tail_level(self.y_end - self.y_start, self.y_end - y, self.fade);
+            let scale = |c: u8| (c as u16 * level as u16 / 255) as u8;
+            Rgb888::new(
+                scale(self.tail.r()),
+                scale(self.tail.g()),
+                scale(self.tail.b()),
+            )
+        };
+        self.y = y.checked_add(1);
+        Some(Pixel(Point::new(self.x as i32, y as i32), color))
+    }
+}
+
+/// xorshift32, plenty for picking columns
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Rng(u32);
+
+impl Rng {
+    fn new(seed: u32) -> Self {
+        // all zeros would stay zero
+        Self(if seed == 0 { 0x9e37_79b9 } else { seed })
+    }
+
+    fn next(&mut self) -> u32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        x
+    }
+
+    /// `0..n`, `0` for `n == 0`
+    fn below(&mut self, n: u32) -> u32 {
+        if n == 0 {
+            return 0;
+        }
+        self.next() % n
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RainConfig {
+    /// Chance of a new row each tick, out of 256
+    pub density: u8,
+    /// Row speeds are picked from here, in 256ths of an LED per tick
+    pub min_speed: u8,
+    pub max_speed: u8,
+    /// How quickly tails get darker towards the top
+    pub fade: u8,
+    pub head: Rgb888,
+    pub tail: Rgb888,
+}
+
+impl Default for RainConfig {
+    fn default() -> Self {
+        Self {
+            density: 64,
+            min_speed: 40,
+            max_speed: 160,
+            fade: 8,
+            head: PALE_HEAD,
+            tail: GREEN,
+        }
+    }
+}
+
+/// Up to `MAX_ROWS` rows falling down `layout`, one tick per `step_ms` when
+/// used as an [`Effect`]
+#[derive(Clone, Debug)]
+pub struct Rain<'l, const MAX_ROWS: usize> {
+    pub config: RainConfig,
+    pub layout: Layout<'l>,
+    pub step_ms: u64,
+    rows: [Option<Row>; MAX_ROWS],
+    rng: Rng,
+    last_ms: Option<u64>,
+}
+
+impl<'l, const MAX_ROWS: usize> Rain<'l, MAX_ROWS> {
+    pub fn new(config: RainConfig, layout: Layout<'l>, seed: u32) -> Self {
+        Self {
+            config,
+            layout,
+            step_ms: 30,
+            rows: [None; MAX_ROWS],
+            rng: Rng::new(seed),
+            last_ms: None,
+        }
+    }
+
+    /// Maybe spawn a row, move all of them and retire the dead ones
+    pub fn tick(&mut self) {
+        let width = self.layout.picture_width().min(u8::MAX as u16 + 1) as u32;
+        let height = self.layout.picture_height().min(u8::MAX as u16) as u8;
+        if width > 0 && (self.rng.below(256) as u8) < self.config.density {
+            if let Some(free) = self.rows.iter_mut().find(|row| row.is_none()) {
+                let (min, max) = (self.config.min_speed, self.config.max_speed);
+                let (min, max) = (min.min(max), min.max(max));
+                let speed = min + self.rng.below((max - min) as u32 + 1) as u8;
+                let x = self.rng.below(width) as u8;
+                *free = Some(Row::new(speed.max(1), x, 0, height, self.config.fade));
+            }
+        }
+        for slot in self.rows.iter_mut() {
+            if slot.as_mut().is_some_and(Row::tick) {
+                *slot = None;
+            }
+        }
+    }
+
+    pub fn rows(&self) -> impl Iterator<Item = &Row> {
+        self.rows.iter().flatten()
+    }
+
+    pub fn draw<D: DrawTarget<Color = Rgb888>>(&self, target: &mut D) -> Result<(), D::Error> {
+        for row in self.rows() {
+            target.draw_iter(row.iter_colored(self.config.head, self.config.tail))?;
+        }
+        Ok(())
+    }
+}
+
+impl<const MAX_ROWS: usize> Effect for Rain<'_, MAX_ROWS> {
+    fn render(&mut self, time_ms: u64, data: &mut [RGB8]) {
+        let step_ms = self.step_ms.max(1);
+        let ticks = match self.last_ms {
+            Some(last) => time_ms.saturating_sub(last) / step_ms,
+            None => 1,
+        };
+        self.last_ms = Some(match self.last_ms {
+            Some(last) => last + ticks * step_ms,
+            None => time_ms,
+        });
+        for _ in 0..ticks.min(MAX_CATCH_UP) {
+            self.tick();
+        }
+
+        let mut matrix = LedMatrix::new(data, self.layout);
+        // drawing on an LedMatrix can't fail
+        matrix.clear(Rgb888::BLACK).ok();
+        self.draw(&mut matrix).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLACK: RGB8 = RGB8 { r: 0, g: 0, b: 0 };
+
+    fn ticks_to_die(mut row: Row) -> usize {
+        (1..10_000).find(|_| row.tick()).unwrap()
+    }
+
+    #[test]
+    fn wraps() {
+        let mut row = Row::new(126, 0, 0, 5, 1);
+        for y in [0, 0, 1, 1, 2] {
+            row.tick();
+            assert_eq!(y, row.y);
+        }
+    }
+
+    #[test]
+    fn dies() {
+        // the tail goes dark right away, so it's gone when the head leaves
+        let mut row = Row::new(255, 0, 0, 4, 255);
+        for shall_die in [false, false, false, false, true] {
+            assert_eq!(row.tick(), shall_die, "{}", row.y);
+        }
+        assert_eq!(row.y, 4);
+
+        // a slow fade keeps the bottom of the tail lit for a while after.
+        // at y the last LED is 255 - 14y - 2(y - 1), so that's y = 17.
+        let row = Row::new(128, 0, 0, 2, 1);
+        assert_eq!(ticks_to_die(row), 34);
+
+        // rows that start off the matrix are gone on the first tick
+        assert!(Row::new(1, 0, 9, 4, 1).tick());
+        assert!(Row::new(1, 0, 0, 0, 1).tick());
+    }
+
+    #[test]
+    fn dies_sooner_with_more_fade_and_speed() {
+        let life = |speed, fade| ticks_to_die(Row::new(speed, 0, 0, 10, fade));
+        assert!(life(128, 1) > life(128, 20));
+        assert!(life(64, 8) > life(128, 8));
+        assert_eq!(life(128, 8), 2 * (life(255, 8) - 1));
+    }
+
+    #[test]
+    fn tail_colors() {
+        let mut row = Row::new(255, 0, 0, 10, 10);
+        for _ in 0..4 {
+            row.tick();
+        }
+        let head = Rgb888::new(255, 255, 255);
+        let tail = Rgb888::new(255, 0, 128);
+        let pixels: [Pixel<Rgb888>; 4] = {
+            let mut it = row.iter_colored(head, tail);
+            [(); 4].map(|_| it.next().unwrap())
+        };
+        assert!(row.iter_colored(head, tail).nth(4).is_none());
+        assert_eq!(pixels[3], Pixel(Point::new(0, 3), head));
+        // a 3 long row is 213 at the top, 11 less per LED
+        assert_eq!(pixels[2].1, Rgb888::new(202, 0, 101));
+        assert_eq!(pixels[0].1, Rgb888::new(180, 0, 90));
+
+        // the classic colors are as before
+        assert_eq!(row.iter().nth(2).unwrap().1, Rgb888::new(0, 202, 0));
+    }
+
+    #[test]
+    fn same_seed_same_rain() {
+        let layout = Layout::new(8, 8);
+        let mut a: Rain<16> = Rain::new(RainConfig::default(), layout, 42);
+        let mut b: Rain<16> = Rain::new(RainConfig::default(), layout, 42);
+        let mut c: Rain<16> = Rain::new(RainConfig::default(), layout, 43);
+        let (mut da, mut db, mut dc) = ([BLACK; 64], [BLACK; 64], [BLACK; 64]);
+        let mut differs = false;
+        for t in 0..200 {
+            a.render(t * 30, &mut da);
+            b.render(t * 30, &mut db);
+            c.render(t * 30, &mut dc);
+            assert_eq!(da, db);
+            differs |= da != dc;
+        }
+        assert!(differs);
+        assert!(a.rows().count() > 0);
+        assert!(a.rows().all(|row| row.x() < 8));
+    }
+
+    #[test]
+    fn density_and_retirement() {
+        let layout = Layout::new(5, 6);
+        let config = RainConfig {
+            density: 0,
+            ..RainConfig::default()
+        };
+        let mut rain: Rain<4> = Rain::new(config, layout, 1);
+        for _ in 0..100 {
+            rain.tick();
+        }
+        assert_eq!(rain.rows().count(), 0);
+
+        // as many as there's room for
+        rain.config.density = 255;
+        rain.config.min_speed = 1;
+        rain.config.max_speed = 1;
+        for _ in 0..10 {
+            rain.tick();
+        }
+        assert_eq!(rain.rows().count(), 4);
+
+        // and once it stops, all of them go away: 6 rows down plus the tail
+        // fading, at 256 ticks per LED
+        rain.config.density = 0;
+        rain.config.max_speed = 255;
+        let mut ticks = 0;
+        while rain.rows().count() > 0 {
+            rain.tick();
+            ticks += 1;
+        }
+        assert!(ticks <= 20 * 256, "{}", ticks);
+    }
+
+    #[test]
+    fn renders_onto_the_layout() {
+        let layout = Layout {
+            rotation: crate::layout::Rotation::R90,
+            ..Layout::new(4, 6)
+        };
+        let config = RainConfig {
+            density: 255,
+            ..RainConfig::default()
+        };
+        let mut rain: Rain<8> = Rain::new(config, layout, 7);
+        let mut data = [RGB8::new(1, 2, 3); 30];
+        rain.render(0, &mut data);
+        // one row spawned, its head is on top
+        let row = *rain.rows().next().unwrap();
+        assert!(row.x() < 6);
+        let idx = layout.index(row.x() as u16, row.y() as u16).unwrap();
+        assert_eq!(data[idx], RGB8::new(90, 120, 110));
+        // everything else is cleared, including past the matrix
+        assert_eq!(data.iter().filter(|c| **c != BLACK).count(), 1);
+    }
+}
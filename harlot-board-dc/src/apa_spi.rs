@@ -35,18 +35,10 @@ impl Default for Pixel {
 }
 
 impl Pixel {
-    pub fn new(r: u8, g: u8, b: u8, brightness: u8) -> Self {
-        let brightness = if brightness >= 100 {
-            31
-        } else if brightness > 8 {
-            (brightness - 7) / 3
-        } else if brightness > 0 {
-            1
-        } else {
-            0
-        };
+    /// `global` is the 5 bit brightness, see `color_mixer::output`
+    pub fn new(r: u8, g: u8, b: u8, global: u8) -> Self {
         Self {
-            brightness: LED_STRIP_SPI_FRAME_SK9822_LED_MSB3 | (brightness & ((1 << 5) - 1)),
+            brightness: LED_STRIP_SPI_FRAME_SK9822_LED_MSB3 | (global & ((1 << 5) - 1)),
             r,
             g,
             b,
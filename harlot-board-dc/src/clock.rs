@@ -0,0 +1,42 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use color_mixer::clock::{Clock, WallClock};
+use esp_idf_svc::sntp::EspSntp;
+
+/// ESP-IDF high resolution timer: 64 bit microseconds since boot
+#[derive(Default)]
+pub struct EspClock;
+
+impl Clock for EspClock {
+    fn now_ms(&self) -> u64 {
+        let us = unsafe { esp_idf_sys::esp_timer_get_time() };
+        us as u64 / 1000
+    }
+}
+
+// 2020-09-13, the RTC counts from 1970 until the first sync
+const SYNCED_AFTER_MS: u64 = 1_600_000_000_000;
+
+/// Wall time from SNTP, for schedules. Syncs in the background once wifi is
+/// up, `None` until then.
+pub struct SntpClock {
+    _sntp: EspSntp,
+}
+
+impl SntpClock {
+    pub fn new() -> anyhow::Result<Self> {
+        Ok(Self {
+            _sntp: EspSntp::new_default()?,
+        })
+    }
+}
+
+impl WallClock for SntpClock {
+    fn unix_ms(&self) -> Option<u64> {
+        let ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()?
+            .as_millis() as u64;
+        (ms > SYNCED_AFTER_MS).then_some(ms)
+    }
+}
@@ -0,0 +1,388 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+
+use color_mixer::{
+    calibration::{Calibration, Curve, TestPatch},
+    compose::Flash,
+    patch::{Journal, Patch, PatchError},
+    power::{PowerEstimate, PowerModel},
+    preset::{PresetRequest, Presets},
+    schedule::Schedule,
+    schema,
+    strip::{Srgb8, State, ValidationError},
+    transition,
+};
+use embedded_svc::{
+    httpd::{registry::Registry, Response},
+    storage::RawStorage,
+};
+use esp_idf_svc::{
+    httpd::{Server, ServerRegistry},
+    nvs_storage::EspNvsStorage,
+};
+
+use crate::{CALIBRATION_FILE, POWER_FILE, PRESETS_FILE, SEGMENTS_FILE};
+
+pub type Segments = Arc<Mutex<Journal>>;
+
+/// Settings of this device's strip and supply, set over the API and not
+/// shared over the mesh, and what the render loop reports back
+#[derive(Default)]
+pub struct Output {
+    pub power: Mutex<PowerModel>,
+    /// What the last frame drew
+    pub estimate: Mutex<PowerEstimate>,
+    pub calibration: Mutex<Calibration>,
+    /// `calibration`, compiled
+    pub curve: Mutex<Curve>,
+    /// Shown instead of the state while calibrating
+    pub patch: Mutex<Option<Srgb8>>,
+    /// Waiting for the render loop to put it on top
+    pub flash: Mutex<Option<Flash>>,
+}
+
+impl Output {
+    pub fn set_calibration(&self, calibration: Calibration) {
+        *self.curve.lock().unwrap() = calibration.curve();
+        *self.calibration.lock().unwrap() = calibration;
+    }
+}
+
+fn store_json(storage: &Mutex<EspNvsStorage>, name: &str, value: &impl serde::Serialize) {
+    let res = serde_json::to_vec(value)
+        .map_err(anyhow::Error::from)
+        .and_then(|ser| Ok(storage.lock().unwrap().put_raw(name, &ser)?));
+    if let Err(e) = res {
+        log::error!("could not store {name}: {:?}", e);
+    }
+}
+
+#[derive(serde::Serialize)]
+struct PowerReport {
+    model: PowerModel,
+    estimate: PowerEstimate,
+}
+
+// the frontend is served from elsewhere
+fn cors(response: Response) -> Response {
+    response
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "GET, POST, OPTIONS")
+        .header("Access-Control-Allow-Headers", "content-type")
+        .header("Access-Control-Expose-Headers", "x-seq")
+}
+
+/// Write `state` to flash in the current schema version and raise `edited`
+pub fn store_state(storage: &Mutex<EspNvsStorage>, edited: &AtomicBool, state: &State) {
+    write_state(storage, state);
+    edited.store(true, Ordering::Relaxed);
+}
+
+/// [`store_state`] without raising `edited`, for states that came from the
+/// mesh and don't need to go back out
+pub fn write_state(storage: &Mutex<EspNvsStorage>, state: &State) {
+    if let Err(e) = storage
+        .lock()
+        .unwrap()
+        .put_raw(SEGMENTS_FILE, &schema::to_json(state))
+    {
+        log::error!("could not store data: {:?}", e);
+    }
+}
+
+/// Take over what belongs to the room rather than the look: the master
+/// brightness, the schedule and the transition settings
+fn keep_settings(new: &mut State, current: &State) {
+    new.set_master_brightness(current.master_brightness());
+    new.set_schedule(current.schedule().clone());
+    new.set_transition(current.transition());
+}
+
+/// Make a preset the current state, keeping the master brightness, the
+/// schedule and the transition settings
+pub fn recall(
+    journal: &mut Journal,
+    preset: &State,
+    max_leds: usize,
+) -> Result<(), ValidationError> {
+    let mut state = preset.clone();
+    keep_settings(&mut state, journal.state());
+    // the strip might have gotten shorter since it was saved
+    state.validate(max_leds)?;
+    journal.replace(state);
+    Ok(())
+}
+
+/// `GET /data` returns the segments with the current sequence number in
+/// `x-seq`, `POST /data` replaces them, `POST /patch` applies a [`Patch`] and
+/// answers with the new sequence number. Changes are stored and raise
+/// `edited`.
+///
+/// `GET /presets` lists preset names, `POST /presets` takes a
+/// [`PresetRequest`]. A recall leaves its fade duration in `fade`.
+///
+/// `GET /schedule` returns the [`Schedule`], `POST /schedule` replaces it.
+/// `GET /brightness` and `POST /brightness` do the same for the master
+/// brightness, a plain number, and `GET /transition` and `POST /transition`
+/// for the [`transition::Settings`].
+///
+/// `GET /power` returns the [`PowerModel`] and the latest [`PowerEstimate`],
+/// `POST /power` takes a new model. `GET /calibration` and
+/// `POST /calibration` do the same for the [`Calibration`],
+/// `POST /calibration/patch` shows a [`TestPatch`].
+pub fn server(
+    segments: Segments,
+    presets: Arc<Mutex<Presets>>,
+    storage: Arc<Mutex<EspNvsStorage>>,
+    edited: Arc<AtomicBool>,
+    fade: Arc<Mutex<Option<u32>>>,
+    output: Arc<Output>,
+    max_leds: usize,
+) -> anyhow::Result<Server> {
+    let preset_storage = storage.clone();
+    let power_storage = storage.clone();
+    let calibration_storage = storage.clone();
+    let get_power = output.clone();
+    let power = output.clone();
+    let get_calibration = output.clone();
+    let calibration = output.clone();
+    let flash_output = output.clone();
+    // stored in the current version, old payloads are migrated once
+    let store = move |state: &State| store_state(&storage, &edited, state);
+    let store_too = store.clone();
+    let store_three = store.clone();
+    let store_four = store.clone();
+    let store_five = store.clone();
+    let store_six = store.clone();
+
+    let get_segments = segments.clone();
+    let patch_segments = segments.clone();
+    let preset_segments = segments.clone();
+    let get_schedule = segments.clone();
+    let schedule_segments = segments.clone();
+    let get_brightness = segments.clone();
+    let brightness_segments = segments.clone();
+    let get_transition = segments.clone();
+    let transition_segments = segments.clone();
+    let list_presets = presets.clone();
+    let server = ServerRegistry::new()
+        .at("/data")
+        .get(move |_req| {
+            let journal = get_segments.lock().unwrap();
+            let ser = String::from_utf8(schema::to_json(journal.state()))?;
+            Ok(cors(Response::new(200))
+                .header("x-seq", journal.seq().to_string())
+                .body(ser.into()))
+        })?
+        .at("/data")
+        .post(move |mut req| {
+            let body = req.as_bytes()?;
+            let mut new = match schema::decode_state(&body, max_leds) {
+                Ok(new) => new,
+                Err(e) => {
+                    log::warn!("rejected /data: {:?}", e);
+                    return Ok(cors(Response::new(400)).body(format!("{e}").into()));
+                }
+            };
+
+            let mut journal = segments.lock().unwrap();
+            keep_settings(&mut new, journal.state());
+            journal.replace(new);
+            store(journal.state());
+            Ok(cors(Response::new(204)))
+        })?
+        .at("/patch")
+        .post(move |mut req| {
+            let patch: Patch = match serde_json::from_slice(&req.as_bytes()?) {
+                Ok(patch) => patch,
+                Err(e) => return Ok(cors(Response::new(400)).body(format!("{e}").into())),
+            };
+
+            let mut journal = patch_segments.lock().unwrap();
+            let before = journal.clone();
+            let res = journal.apply(&patch).and_then(|seq| {
+                // inserts and length changes can push it past the strip
+                journal.state().validate(max_leds)?;
+                Ok(seq)
+            });
+            match res {
+                Ok(seq) => {
+                    store_too(journal.state());
+                    Ok(cors(Response::new(200)).body(seq.to_string().into()))
+                }
+                Err(e) => {
+                    *journal = before;
+                    let status = match e {
+                        PatchError::Stale { .. } => 409,
+                        _ => 400,
+                    };
+                    Ok(cors(Response::new(status)).body(format!("{e}").into()))
+                }
+            }
+        })?
+        .at("/presets")
+        .get(move |_req| {
+            let presets = list_presets.lock().unwrap();
+            let names: Vec<&str> = presets.names().collect();
+            Ok(cors(Response::new(200)).body(serde_json::to_string(&names)?.into()))
+        })?
+        .at("/presets")
+        .post(move |mut req| {
+            let request: PresetRequest = match serde_json::from_slice(&req.as_bytes()?) {
+                Ok(request) => request,
+                Err(e) => return Ok(cors(Response::new(400)).body(format!("{e}").into())),
+            };
+
+            let mut presets = presets.lock().unwrap();
+            match request {
+                PresetRequest::Save { name } => {
+                    let state = preset_segments.lock().unwrap().state().clone();
+                    presets.save(name, state);
+                }
+                PresetRequest::Recall { name, fade_ms } => {
+                    let Some(state) = presets.get(&name) else {
+                        return Ok(cors(Response::new(404)));
+                    };
+                    let mut journal = preset_segments.lock().unwrap();
+                    if let Err(e) = recall(&mut journal, state, max_leds) {
+                        return Ok(cors(Response::new(400)).body(format!("{e}").into()));
+                    }
+                    store_three(journal.state());
+                    *fade.lock().unwrap() = Some(fade_ms);
+                    return Ok(cors(Response::new(204)));
+                }
+                PresetRequest::Delete { name } => {
+                    if !presets.delete(&name) {
+                        return Ok(cors(Response::new(404)));
+                    }
+                }
+            }
+
+            if let Err(e) = preset_storage
+                .lock()
+                .unwrap()
+                .put_raw(PRESETS_FILE, &presets.to_json())
+            {
+                log::error!("could not store presets: {:?}", e);
+            }
+            Ok(cors(Response::new(204)))
+        })?
+        .at("/schedule")
+        .get(move |_req| {
+            let journal = get_schedule.lock().unwrap();
+            let ser = serde_json::to_string(journal.state().schedule())?;
+            Ok(cors(Response::new(200)).body(ser.into()))
+        })?
+        .at("/schedule")
+        .post(move |mut req| {
+            let schedule: Schedule = match serde_json::from_slice(&req.as_bytes()?) {
+                Ok(schedule) => schedule,
+                Err(e) => return Ok(cors(Response::new(400)).body(format!("{e}").into())),
+            };
+            if let Err(e) = schedule.validate() {
+                return Ok(cors(Response::new(400)).body(format!("{e}").into()));
+            }
+
+            let mut journal = schedule_segments.lock().unwrap();
+            journal.set_schedule(schedule);
+            store_four(journal.state());
+            Ok(cors(Response::new(204)))
+        })?
+        .at("/brightness")
+        .get(move |_req| {
+            let journal = get_brightness.lock().unwrap();
+            let brightness = journal.state().master_brightness();
+            Ok(cors(Response::new(200)).body(brightness.to_string().into()))
+        })?
+        .at("/brightness")
+        .post(move |mut req| {
+            let brightness: u8 = match serde_json::from_slice(&req.as_bytes()?) {
+                Ok(brightness) => brightness,
+                Err(e) => return Ok(cors(Response::new(400)).body(format!("{e}").into())),
+            };
+
+            let mut journal = brightness_segments.lock().unwrap();
+            journal.set_master_brightness(brightness);
+            store_five(journal.state());
+            Ok(cors(Response::new(204)))
+        })?
+        .at("/transition")
+        .get(move |_req| {
+            let journal = get_transition.lock().unwrap();
+            let ser = serde_json::to_string(&journal.state().transition())?;
+            Ok(cors(Response::new(200)).body(ser.into()))
+        })?
+        .at("/transition")
+        .post(move |mut req| {
+            let settings: transition::Settings = match serde_json::from_slice(&req.as_bytes()?) {
+                Ok(settings) => settings,
+                Err(e) => return Ok(cors(Response::new(400)).body(format!("{e}").into())),
+            };
+
+            let mut journal = transition_segments.lock().unwrap();
+            journal.set_transition(settings);
+            store_six(journal.state());
+            Ok(cors(Response::new(204)))
+        })?
+        .at("/power")
+        .get(move |_req| {
+            let report = PowerReport {
+                model: *get_power.power.lock().unwrap(),
+                estimate: *get_power.estimate.lock().unwrap(),
+            };
+            Ok(cors(Response::new(200)).body(serde_json::to_string(&report)?.into()))
+        })?
+        .at("/power")
+        .post(move |mut req| {
+            let model: PowerModel = match serde_json::from_slice(&req.as_bytes()?) {
+                Ok(model) if model.is_valid() => model,
+                Ok(_) => return Ok(cors(Response::new(400)).body("negative current".into())),
+                Err(e) => return Ok(cors(Response::new(400)).body(format!("{e}").into())),
+            };
+
+            *power.power.lock().unwrap() = model;
+            store_json(&power_storage, POWER_FILE, &model);
+            Ok(cors(Response::new(204)))
+        })?
+        .at("/calibration")
+        .get(move |_req| {
+            let calibration = get_calibration.calibration.lock().unwrap();
+            Ok(cors(Response::new(200)).body(serde_json::to_string(&*calibration)?.into()))
+        })?
+        .at("/calibration")
+        .post(move |mut req| {
+            let new: Calibration = match serde_json::from_slice(&req.as_bytes()?) {
+                Ok(new) if new.is_valid() => new,
+                Ok(_) => return Ok(cors(Response::new(400)).body("invalid calibration".into())),
+                Err(e) => return Ok(cors(Response::new(400)).body(format!("{e}").into())),
+            };
+
+            store_json(&calibration_storage, CALIBRATION_FILE, &new);
+            calibration.set_calibration(new);
+            Ok(cors(Response::new(204)))
+        })?
+        .at("/calibration/patch")
+        .post(move |mut req| {
+            let patch: TestPatch = match serde_json::from_slice(&req.as_bytes()?) {
+                Ok(patch) => patch,
+                Err(e) => return Ok(cors(Response::new(400)).body(format!("{e}").into())),
+            };
+
+            *output.patch.lock().unwrap() = patch.color.map(|c| c.0);
+            Ok(cors(Response::new(204)))
+        })?
+        .at("/flash")
+        .post(move |mut req| {
+            let flash: Flash = match serde_json::from_slice(&req.as_bytes()?) {
+                Ok(flash) => flash,
+                Err(e) => return Ok(cors(Response::new(400)).body(format!("{e}").into())),
+            };
+
+            *flash_output.flash.lock().unwrap() = Some(flash);
+            Ok(cors(Response::new(204)))
+        })?;
+
+    server.start(&Default::default())
+}
@@ -13,6 +13,9 @@ use std::{
 };
 
 mod apa_spi;
+mod clock;
+mod http;
+mod mesh;
 mod wifi;
 
 use std::{
@@ -24,7 +27,21 @@ use std::{
 };
 
 use apa_spi::{Apa, Pixel};
-use color_mixer::strip::{Control, Segment, Srgb8, State};
+use clock::{EspClock, SntpClock};
+use color_mixer::{
+    calibration::Calibration,
+    clock::{Clock, FrameScheduler, WallClock},
+    compose::Compositor,
+    dither::Dither,
+    mesh::MeshConfig,
+    patch::Journal,
+    power::PowerModel,
+    preset::Presets,
+    schedule::Scheduler,
+    schema,
+    strip::{Control, Led, Segment, Srgb8, State, FULL_BRIGHTNESS},
+    transition::Transition,
+};
 use embedded_svc::{
     httpd::{Request, Response},
     io::{Io, Read, Write},
@@ -57,13 +74,18 @@ impl<R: Read> std::io::Read for StdReader<R> {
 }
 
 const SEGMENTS_FILE: &'static str = "segments.json";
+const PRESETS_FILE: &'static str = "presets.json";
+const POWER_FILE: &'static str = "power.json";
+const CALIBRATION_FILE: &'static str = "calibration.json";
+const MAX_LEDS: usize = 512;
+/// 100 fps, dithering needs a steady rate to average out
+const FRAME_MS: u64 = 10;
+/// Dither slow fades instead of stepping through 8 bit colors
+const DITHER: bool = true;
 fn main() -> anyhow::Result<()> {
     esp_idf_sys::link_patches();
     esp_idf_svc::log::EspLogger::initialize_default();
 
-    let mut now = 0;
-    let sys_start = Instant::now();
-
     println!("Hello, world!");
 
     log::debug!("Hello, log!");
@@ -73,26 +95,49 @@ fn main() -> anyhow::Result<()> {
     let nvs = Arc::new(esp_idf_svc::nvs::EspDefaultNvs::new()?);
     let storage = EspNvsStorage::new_default(nvs.clone(), FS_NAMESPACE, true)?;
 
-    let load_segments = || {
-        let len = storage.len(SEGMENTS_FILE)?.unwrap_or_default();
+    let load = |name: &str| -> anyhow::Result<Vec<u8>> {
+        let len = storage.len(name)?.unwrap_or_default();
         let mut buf = vec![];
         buf.resize(len, 0u8);
-        let (loaded_buf, _) = storage
-            .get_raw(SEGMENTS_FILE, &mut buf)?
+        let loaded = storage
+            .get_raw(name, &mut buf)?
+            .map(|(loaded_buf, _)| loaded_buf.len())
             .unwrap_or_default();
-        let de: IndexMap<String, Segment> = serde_json::from_slice(loaded_buf)?;
-        Ok(de)
+        buf.truncate(loaded);
+        Ok(buf)
     };
 
-    let res: Result<_, anyhow::Error> = load_segments();
+    // the whole state, master brightness, schedule and transition included
+    let res = load(SEGMENTS_FILE).and_then(|buf| Ok(schema::decode_state(&buf, MAX_LEDS)?));
 
     if let Err(e) = &res {
         log::error!("could not load data: {:?}", e);
     }
-    let mut segments = res.unwrap_or_default();
+    let mut state = res.unwrap_or_else(|_| State::new_empty());
+
+    let presets = load(PRESETS_FILE).and_then(|buf| Ok(Presets::from_json(&buf)?));
+    if let Err(e) = &presets {
+        log::warn!("no presets: {:?}", e);
+    }
+    let presets = Arc::new(Mutex::new(presets.unwrap_or_default()));
+
+    let power = load(POWER_FILE).and_then(|buf| Ok(serde_json::from_slice::<PowerModel>(&buf)?));
+    if let Err(e) = &power {
+        log::warn!("default power model: {:?}", e);
+    }
+    let calibration =
+        load(CALIBRATION_FILE).and_then(|buf| Ok(serde_json::from_slice::<Calibration>(&buf)?));
+    if let Err(e) = &calibration {
+        log::warn!("uncalibrated: {:?}", e);
+    }
+    let output = Arc::new(http::Output {
+        power: Mutex::new(power.unwrap_or_default()),
+        ..Default::default()
+    });
+    output.set_calibration(calibration.unwrap_or_default());
 
     let brightness = 10;
-    if segments.is_empty() {
+    if state.is_empty() {
         let chill_fac = 100;
         let some_segs = [
             Segment::new(
@@ -133,45 +178,190 @@ fn main() -> anyhow::Result<()> {
             ),
         ];
 
-        segments.extend(some_segs.into_iter().map(|s| (s.to_uuid_string(), s)));
+        for seg in some_segs {
+            state.insert(seg);
+        }
     }
 
-    let segments = Arc::new(Mutex::new(segments));
+    let segments = Arc::new(Mutex::new(Journal::new(state)));
+    let storage = Arc::new(Mutex::new(storage));
+    let edited = Arc::new(AtomicBool::new(false));
+    let fade = Arc::new(Mutex::new(None));
+
+    let _server = match http::server(
+        segments.clone(),
+        presets.clone(),
+        storage.clone(),
+        edited.clone(),
+        fade.clone(),
+        output.clone(),
+        MAX_LEDS,
+    ) {
+        Ok(server) => Some(server),
+        Err(e) => {
+            log::error!("could not start http server: {:?}", e);
+            None
+        }
+    };
 
     let mut apa_config = apa_spi::Config::default();
-    apa_config.length = 512;
+    apa_config.length = MAX_LEDS;
     const LEN: usize = 32;
     let mut apa: Apa = Apa::new(apa_config);
     let moar_chill = 1000;
-    let state = State::new(
-        segments
-            .lock()
-            .unwrap()
-            .iter()
-            .map(|(id, seg)| seg)
-            .cloned(),
-    );
+    // what the last frame showed, where a transition starts from
+    let mut shown = segments.lock().unwrap().state().clone();
+    // schedules wait for the first sync, until then the strip is just on
+    let wall_clock = match SntpClock::new() {
+        Ok(clock) => Some(clock),
+        Err(e) => {
+            log::warn!("running without sntp: {:?}", e);
+            None
+        }
+    };
+    let mut scheduler = Scheduler::new();
+    let mut transition: Option<Transition> = None;
+    let mut layers = Compositor::new();
 
-    loop {
-        let mut led_start = 0;
+    let mut frame: Vec<Led> = Vec::new();
+    let mut pixels = Vec::new();
+    let mut control = Control::with_clock(EspClock);
+    let mut frames = FrameScheduler::new(FRAME_MS, control.clock().now_ms());
+    let mut dither = Dither::new();
+
+    // boards on the same network follow the lowest id's clock and share edits
+    let mut mesh = match mesh::UdpMesh::new(mesh::node_id(), MeshConfig::default(), control.tick())
+    {
+        Ok(mesh) => Some(mesh),
+        Err(e) => {
+            log::warn!("running without mesh: {:?}", e);
+            None
+        }
+    };
 
+    loop {
+        let local = control.tick();
+        let now = match &mut mesh {
+            Some(mesh) => {
+                if edited.swap(false, Ordering::Relaxed) {
+                    let state = segments.lock().unwrap().state().clone();
+                    mesh.node().set_state(state);
+                }
+                if let Err(e) = mesh.poll(local) {
+                    log::warn!("mesh poll failed: {:?}", e);
+                }
+                if let Some(update) = mesh.node().take_state_update() {
+                    // held to the same checks as /data
+                    match update.validate(MAX_LEDS) {
+                        Ok(()) => {
+                            let mut journal = segments.lock().unwrap();
+                            journal.replace(update);
+                            http::write_state(&storage, journal.state());
+                        }
+                        Err(e) => log::warn!("dropped invalid state from the mesh: {e}"),
+                    }
+                }
+                mesh.node().now(local)
+            }
+            None => local,
+        };
         let log_f = |s: String| log::warn!("{s}");
         let log_f = |_s| {};
-        let segments = segments.lock().unwrap().clone();
+        let unix_ms = wall_clock.as_ref().and_then(|clock| clock.unix_ms());
+        if let Some(unix_ms) = unix_ms {
+            let schedule = segments.lock().unwrap().state().schedule().clone();
+            if let Some((name, fade_ms)) = scheduler.poll(&schedule, unix_ms) {
+                let presets = presets.lock().unwrap();
+                let mut journal = segments.lock().unwrap();
+                match presets
+                    .get(&name)
+                    .map(|preset| http::recall(&mut journal, preset, MAX_LEDS))
+                {
+                    Some(Ok(())) => {
+                        http::store_state(&storage, &edited, journal.state());
+                        *fade.lock().unwrap() = Some(fade_ms);
+                    }
+                    Some(Err(e)) => log::warn!("scheduled preset {name} is invalid: {e}"),
+                    None => log::warn!("scheduled preset {name} doesn't exist"),
+                }
+            }
+        }
+
+        let state = segments.lock().unwrap().state().clone();
+        let scheduled = unix_ms.map_or(1., |unix_ms| state.schedule().level_at(unix_ms));
+        let master = state.master_level() * scheduled;
+
+        // edits, recalls and mesh updates all come in the same way, recalls
+        // over their own fade duration
+        let fade_ms = fade.lock().unwrap().take();
+        if fade_ms.is_some() || state.segments() != shown.segments() {
+            let settings = state.transition();
+            let ms = fade_ms.unwrap_or(settings.ms) as u64;
+            let next = Transition::new(shown.clone(), now, ms, settings.style);
+            transition = Some(match transition.take() {
+                Some(running) => next.over(running, now),
+                None => next,
+            });
+        }
 
-        for (_id, seg) in segments {
-            let color = seg.color_at(now);
-            let segment_color = Pixel::new(color.red, color.green, color.blue, seg.brightness());
-            for i in led_start..led_start + seg.length() {
-                apa.set_pixel(i, segment_color, log_f);
+        // always the whole strip, so LEDs past the segments are written dark
+        // every frame instead of keeping whatever was shown there last
+        frame.clear();
+        frame.resize(MAX_LEDS, Led::default());
+        state.render(now, &mut frame);
+        if let Some(running) = &mut transition {
+            running.blend(now, &mut frame);
+            if running.is_done(now) {
+                transition = None;
             }
-            led_start += seg.length();
-            apa.flush();
         }
 
-        std::thread::sleep(std::time::Duration::from_millis(10));
+        if let Some(flash) = output.flash.lock().unwrap().take() {
+            layers.push(flash.layer(now));
+        }
+        layers.render(now, &mut frame);
+
+        // a test patch replaces the whole strip, at full segment brightness
+        if let Some(color) = *output.patch.lock().unwrap() {
+            frame.fill(Led::new(color, FULL_BRIGHTNESS));
+        }
+
+        let model = *output.power.lock().unwrap();
+        let estimate = {
+            let curve = output.curve.lock().unwrap();
+            // dithered in light after the curve, at the brightness the
+            // power limit settled on
+            if DITHER {
+                model.limit_with(
+                    &frame,
+                    MAX_LEDS,
+                    master,
+                    &curve,
+                    &mut pixels,
+                    |idx, target| dither.pixel(idx, target),
+                )
+            } else {
+                model.limit(&frame, MAX_LEDS, master, &curve, &mut pixels)
+            }
+        };
+        *output.estimate.lock().unwrap() = estimate;
+
+        for (i, px) in pixels.iter().enumerate() {
+            let pixel = Pixel::new(px.color.red, px.color.green, px.color.blue, px.global);
+            apa.set_pixel(i, pixel, log_f);
+        }
+        apa.flush();
+        shown = state;
 
-        let dt = Instant::now().duration_since(sys_start);
-        now = dt.as_millis() as u32;
+        let skipped = frames.skipped();
+        let wait = frames.frame_done(control.clock().now_ms());
+        // every hundredth, so a slow loop doesn't also drown in logging
+        if frames.skipped() / 100 > skipped / 100 {
+            log::warn!(
+                "{} frames dropped, rendering takes longer than {FRAME_MS}ms",
+                frames.skipped()
+            );
+        }
+        std::thread::sleep(std::time::Duration::from_millis(wait));
     }
 }
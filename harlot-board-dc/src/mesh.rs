@@ -0,0 +1,64 @@
+// UDP multicast transport for `color_mixer::mesh`
+
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+
+use color_mixer::mesh::{MeshConfig, Message, Node, NodeId};
+
+pub const GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 71, 17);
+pub const PORT: u16 = 7117;
+
+// a whole `State` has to fit, lwIP reassembles fragments up to this
+const MAX_DATAGRAM: usize = 8 * 1024;
+
+pub struct UdpMesh {
+    socket: UdpSocket,
+    node: Node,
+    buf: Vec<u8>,
+}
+
+impl UdpMesh {
+    pub fn new(id: NodeId, config: MeshConfig, local: u64) -> anyhow::Result<Self> {
+        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, PORT))?;
+        socket.join_multicast_v4(&GROUP, &Ipv4Addr::UNSPECIFIED)?;
+        socket.set_multicast_loop_v4(false)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            node: Node::new(id, config, local),
+            buf: vec![0; MAX_DATAGRAM],
+        })
+    }
+
+    /// Handle everything received since the last call and send what's due
+    pub fn poll(&mut self, local: u64) -> anyhow::Result<()> {
+        loop {
+            match self.socket.recv_from(&mut self.buf) {
+                Ok((len, _from)) => match serde_json::from_slice::<Message>(&self.buf[..len]) {
+                    Ok(msg) => self.node.handle(msg, local),
+                    Err(e) => log::warn!("bad mesh message: {e:?}"),
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        for msg in self.node.poll(local) {
+            let ser = serde_json::to_vec(&msg)?;
+            self.socket.send_to(&ser, SocketAddrV4::new(GROUP, PORT))?;
+        }
+        Ok(())
+    }
+
+    pub fn node(&mut self) -> &mut Node {
+        &mut self.node
+    }
+}
+
+/// Derived from the factory MAC, stable across reboots and unique per board
+pub fn node_id() -> NodeId {
+    let mut mac = [0u8; 8];
+    unsafe {
+        esp_idf_sys::esp_efuse_mac_get_default(mac.as_mut_ptr());
+    }
+    u64::from_le_bytes(mac)
+}